@@ -1,10 +1,16 @@
-use std::path::PathBuf;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::time::{Instant, Duration};
 
 use gbc::Gameboy;
 use gbc::cartridge::Cartridge;
-use gbc::joypad::{JoypadEvent, JoypadInput};
+use gbc::cdl::CodeDataLog;
+use gbc::joypad::{self, JoypadEvent, JoypadInput};
+use gbc::movie::Movie;
 use gbc::ppu::{FrameBuffer, GameboyRgba, LCD_WIDTH, LCD_HEIGHT};
+use gbc::rewind::RewindBuffer;
+use gbc::symbols::Symbols;
+use gbc::trace::{TraceConfig, TraceFormat, TraceTrigger, Tracer};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -14,6 +20,20 @@ use sdl2::video::Window;
 
 use structopt::StructOpt;
 
+mod rom;
+mod slots;
+
+use slots::SaveSlots;
+
+/// Number of frames between rewind snapshots
+const REWIND_INTERVAL: u32 = 2;
+
+/// Number of rewind snapshots to keep (~30 seconds of gameplay)
+const REWIND_CAPACITY: usize = 900;
+
+/// How long a status message stays in the window title
+const STATUS_DURATION: Duration = Duration::from_secs(3);
+
 struct FpsCounter {
     start_time: Instant,
     last_elapsed: Duration,
@@ -64,8 +84,58 @@ enum Args {
         #[structopt(long, help = "Boot into the DMG boot ROM")]
         boot_rom: bool,
 
-        #[structopt(long, help = "Trace all instructions to a file in the current directory")]
+        #[structopt(long, help = "Trace all instructions to a file (default: gbc.trace)")]
         trace: bool,
+
+        #[structopt(long, help = "Trace format: verbose, compact, doctor or binary (implies --trace)")]
+        trace_format: Option<TraceFormat>,
+
+        #[structopt(long, parse(from_os_str), help = "Write the trace to this file (implies --trace)")]
+        trace_file: Option<PathBuf>,
+
+        #[structopt(long, help = "Start tracing at an address or frame, e.g., pc:0x150 or frame:60 (implies --trace)")]
+        trace_start: Option<TraceTrigger>,
+
+        #[structopt(long, help = "Stop tracing at an address or frame, e.g., pc:0x150 or frame:60 (implies --trace)")]
+        trace_stop: Option<TraceTrigger>,
+
+        #[structopt(long, parse(from_os_str), help = "Directory for save state slots (default: next to the ROM)")]
+        state_dir: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), help = "Apply an IPS, BPS or UPS patch (default: <rom>.ips/.bps/.ups, if present)")]
+        patch: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), help = "Record a movie from power-on, written on exit")]
+        record: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), conflicts_with = "record", help = "Play back a movie")]
+        movie: Option<PathBuf>,
+
+        #[structopt(long, help = "Start paused, and wait for a GDB client on this address (e.g., 127.0.0.1:1234)")]
+        gdb: Option<String>,
+
+        #[structopt(long, parse(from_os_str), help = "Load symbols from an RGBDS .sym file (default: <rom>.sym, if present)")]
+        sym: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), help = "Log ROM code/data usage to a BizHawk .cdl file, merged with its contents, written on exit")]
+        cdl: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), help = "Profile CPU cycles, and write folded call stacks (for flame graphs) to this file on exit")]
+        profile: Option<PathBuf>,
+    },
+    #[structopt(about = "Replay a movie without a window")]
+    Replay {
+        #[structopt(parse(from_os_str), help = "Path to ROM file")]
+        rom_file: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "Path to movie file")]
+        movie: PathBuf,
+
+        #[structopt(long, parse(from_os_str), help = "Write the last frame to a PNG file")]
+        screenshot: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), help = "Apply an IPS, BPS or UPS patch (default: <rom>.ips/.bps/.ups, if present)")]
+        patch: Option<PathBuf>,
     },
     #[structopt(about = "Inspect a ROM")]
     Inspect {
@@ -89,6 +159,22 @@ fn keycode_to_joypad_input(keycode: Option<Keycode>) -> Option<JoypadInput> {
     }
 }
 
+fn keycode_to_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
 fn event_to_joypad(event: Event) -> Option<JoypadEvent> {
     match event {
         Event::KeyDown { keycode, .. } => {
@@ -109,6 +195,135 @@ fn event_to_joypad(event: Event) -> Option<JoypadEvent> {
     }
 }
 
+/// Movie being recorded or played back
+enum MovieState {
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, frame: usize },
+}
+
+/// Opens a (possibly compressed) ROM file.
+///
+/// Save files are stored next to the ROM file, as usual.
+fn open_cartridge(rom_file: &Path, boot_rom: bool) -> Result<Cartridge, Box<dyn Error>> {
+    let data = rom::read(rom_file)?;
+    let mut cartridge = Cartridge::from_bytes(data, boot_rom)?;
+    cartridge.rom_path = Some(rom_file.to_path_buf());
+    Ok(cartridge)
+}
+
+/// Loads the cartridge and applies a patch to it.
+///
+/// If no patch is given, a patch with the same name as the ROM is used, if present.
+fn load_cartridge(rom_file: &Path, patch: Option<PathBuf>, boot_rom: bool) -> Result<Cartridge, Box<dyn Error>> {
+    let mut cartridge = open_cartridge(rom_file, boot_rom)?;
+
+    let patch = patch.or_else(|| {
+        ["ips", "bps", "ups"]
+            .iter()
+            .map(|ext| rom_file.with_extension(ext))
+            .find(|path| path.is_file())
+    });
+
+    if let Some(patch) = patch {
+        cartridge.patch(&patch)?;
+        log::info!("Applied patch {}", patch.display());
+    }
+
+    Ok(cartridge)
+}
+
+/// Loads cheats for the ROM from `<rom>.cht`, if present.
+///
+/// Each line holds a Game Genie or GameShark code, optionally followed by a description.
+/// Empty lines and lines starting with `#` are ignored.
+fn load_cheats(gameboy: &mut Gameboy, rom_file: &Path) {
+    let path = rom_file.with_extension("cht");
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let code = line.split_whitespace().next().unwrap();
+        if let Err(e) = gameboy.add_cheat(code) {
+            log::warn!("{}: {}", path.display(), e);
+        }
+    }
+
+    log::info!("Loaded {} cheats from {}", gameboy.cheats().len(), path.display());
+}
+
+/// Loads symbols from `path`, or from `<rom>.sym` if present.
+fn load_symbols(gameboy: &mut Gameboy, rom_file: &Path, path: Option<PathBuf>) {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let path = rom_file.with_extension("sym");
+            if !path.is_file() {
+                return;
+            }
+            path
+        }
+    };
+
+    match Symbols::from_file(&path) {
+        Ok(symbols) => {
+            log::info!("Loaded {} symbols from {}", symbols.len(), path.display());
+            gameboy.set_symbols(Some(symbols));
+        }
+        Err(e) => log::warn!("{}: {}", path.display(), e),
+    }
+}
+
+/// Writes the profiled call stacks in the folded format, and prints where the cycles went.
+#[cfg(feature = "debug")]
+fn save_profile(gameboy: &mut Gameboy, path: &Path) {
+    let profiler = match gameboy.take_profiler() {
+        Some(profiler) => profiler,
+        None => return,
+    };
+    let symbols = gameboy.cpu().symbols();
+
+    let result = std::fs::File::create(path)
+        .and_then(|f| profiler.write_folded(std::io::BufWriter::new(f), symbols));
+    match result {
+        Ok(()) => println!("Saved folded call stacks to {}", path.display()),
+        Err(e) => eprintln!("Error saving profile: {}", e),
+    }
+
+    let total = profiler.total();
+    let running = total.cycles - total.halted;
+    println!("Cycles: {} ({:.1}% running, {:.1}% halted)", total.cycles, total.usage(), 100.0 - total.usage());
+
+    let frames = profiler.frames();
+    if !frames.is_empty() {
+        let busy = frames.iter().filter(|frame| frame.halted == 0).count();
+        let peak = frames.iter().map(|frame| frame.usage()).fold(0.0, f64::max);
+        println!("Frames: {}, average CPU usage: {:.1}%, peak: {:.1}%, never halted (likely lag frames): {}",
+                 frames.len(), total.usage(), peak, busy);
+    }
+
+    println!("Hottest addresses:");
+    for (bank, pc, cycles) in profiler.hot_addresses(10) {
+        let label = symbols.and_then(|s| s.format(bank, pc)).map(|l| format!(" <{}>", l)).unwrap_or_default();
+        println!("  {:02X}:{:04X}{}: {} cycles ({:.1}%)", bank, pc, label, cycles, cycles as f64 * 100.0 / running as f64);
+    }
+
+    if let Some(symbols) = symbols {
+        println!("Hottest functions:");
+        for (name, cycles) in profiler.hot_functions(symbols, 10) {
+            println!("  {}: {} cycles ({:.1}%)", name, cycles, cycles as f64 * 100.0 / running as f64);
+        }
+    }
+}
+
+#[cfg(not(feature = "debug"))]
+fn save_profile(_gameboy: &mut Gameboy, _path: &Path) {}
+
 /// Renders a single Gameboy frame to the SDL canvas using a texture as the render target.
 ///
 /// Once the texture is ready, it is copied back to the canvas and presented.
@@ -166,32 +381,61 @@ fn render_frame(frame_buffer: &FrameBuffer, canvas: &mut Canvas<Window>, texture
 /// to an SDL texture.
 ///
 /// At the end of the frame, any input joypad events are passed on to the Gameboy to be
-/// picked up in the next frame.
+/// picked up in the next frame. If a movie is being played back, its input is used
+/// instead; if one is being recorded, the input is appended to it.
+///
+/// Returns `true` if movie playback just finished.
 fn handle_frame(gameboy: &mut Gameboy, canvas: &mut Canvas<Window>, texture: &mut Texture,
-                joypad_events: &mut Vec<JoypadEvent>, outline: bool) {
+                joypad_events: &mut Vec<JoypadEvent>, movie: &mut Option<MovieState>,
+                outline: bool) -> bool {
     // Run the Gameboy until the next frame is ready (i.e., start of VBLANK).
     //
     // This means we run from VBLANK to VBLANK. From the rendering side, it doesn't
     // really matter: as long as the frame is ready, we can render. The emulator
     // will catch up & process the VBLANK in the next call to this function.
-    loop {
-        // Runs the CPU and all peripherals in lock step for a single "step". The size
-        // of this step is controlled by the CPU based on the last executed instruction.
-        let (frame_buffer, _) = gameboy.step();
-
-        if let Some(frame_buffer) = frame_buffer {
-            render_frame(frame_buffer, canvas, texture, outline);
-            break;
-        }
+    if let Some(frame_buffer) = gameboy.next_frame() {
+        render_frame(frame_buffer, canvas, texture, outline);
     }
 
-    gameboy.update_joypad(Some(joypad_events));
+    if let Some(e) = gameboy.take_error() {
+        log::error!("{}", e);
+    }
+
+    if let Some(event) = gameboy.take_event() {
+        log::error!("{}", event);
+    }
+
+    let mut state = joypad::apply_events(gameboy.joypad_state(), joypad_events);
+    let mut finished = false;
+
+    match movie {
+        Some(MovieState::Playing { movie: m, frame }) => match m.input(*frame) {
+            Some(input) => {
+                state = input;
+                *frame += 1;
+            }
+            None => finished = true,
+        },
+        Some(MovieState::Recording { movie: m, .. }) => m.push(state),
+        None => (),
+    }
+
+    if finished {
+        *movie = None;
+    }
+
+    gameboy.set_joypad_state(state);
 
     // Clear out all processed input events
     joypad_events.clear();
+
+    finished
 }
 
-fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
+/// Runs the emulator in a window until it is closed. Returns the emulator for any
+/// post-run output.
+fn gui(mut gameboy: Gameboy, rom_file: PathBuf, scale: u32, speed: u8,
+       state_dir: Option<PathBuf>, mut movie: Option<MovieState>) -> Gameboy {
     let rom_name = match rom_file.file_name() {
         None => None,
         Some(n) => Some(n.to_str().unwrap()),
@@ -234,10 +478,20 @@ fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
                                                      LCD_WIDTH as u32,
                                                      LCD_HEIGHT as u32).unwrap();
 
-    let mut gameboy = Gameboy::init(&rom_file, boot_rom, trace).unwrap();
 
     let mut paused = false;
     let mut outline = false;
+    let mut cheats = true;
+
+    // Save state slots for this ROM
+    let mut slots = SaveSlots::new(&rom_file, state_dir);
+
+    // Last status message shown in the window title, if any
+    let mut status: Option<(String, Instant)> = None;
+
+    // Snapshots used to rewind gameplay while the rewind key is held
+    let mut rewind = RewindBuffer::new(REWIND_INTERVAL, REWIND_CAPACITY);
+    let mut rewinding = false;
 
     // List of joypad events to push to the Gameboy
     let mut joypad_events = Vec::new();
@@ -261,9 +515,16 @@ fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
+                Event::KeyDown { keycode: Some(Keycode::Semicolon), .. } |
+                Event::KeyDown { keycode: Some(Keycode::L), .. } |
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } if movie.is_some() => {
+                    // Jumping around would break the movie
+                    status = Some(("Not available during a movie".to_string(), Instant::now()));
+                }
                 Event::KeyDown { keycode: Some(Keycode::Semicolon), .. } => {
                     // Reset the emulator
                     gameboy.reset();
+                    rewind.clear();
                 }
                 Event::KeyDown { keycode: Some(Keycode::P), .. } => {
                     paused = !paused;
@@ -271,13 +532,43 @@ fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
                 Event::KeyDown { keycode: Some(Keycode::O), .. } => {
                     outline = !outline;
                 }
+                Event::KeyDown { keycode: Some(Keycode::C), .. } if !gameboy.cheats().is_empty() => {
+                    cheats = !cheats;
+                    for i in 0..gameboy.cheats().len() {
+                        gameboy.set_cheat_enabled(i, cheats);
+                    }
+
+                    let msg = if cheats { "Cheats enabled" } else { "Cheats disabled" };
+                    status = Some((msg.to_string(), Instant::now()));
+                }
                 Event::KeyDown { keycode: Some(Keycode::K), .. } => {
-                    // Save this Gameboy to disk
-                    gameboy.dump("save.state").unwrap();
+                    // Save this Gameboy to the current slot
+                    let msg = match slots.save(&gameboy) {
+                        Ok(()) => format!("Saved state to slot {}", slots.slot()),
+                        Err(e) => format!("Failed to save slot {}: {}", slots.slot(), e),
+                    };
+                    status = Some((msg, Instant::now()));
                 }
                 Event::KeyDown { keycode: Some(Keycode::L), .. } => {
-                    // Load a Gameboy from disk
-                    gameboy = Gameboy::load(&rom_file, "save.state").unwrap();
+                    // Load a Gameboy from the current slot
+                    let msg = match slots.load(&mut gameboy) {
+                        Ok(()) => {
+                            rewind.clear();
+                            format!("Loaded state from slot {}", slots.slot())
+                        }
+                        Err(e) => format!("Failed to load slot {}: {}", slots.slot(), e),
+                    };
+                    status = Some((msg, Instant::now()));
+                }
+                Event::KeyDown { keycode: Some(keycode), .. } if keycode_to_slot(keycode).is_some() => {
+                    slots.select(keycode_to_slot(keycode).unwrap());
+                    status = Some((format!("Selected slot {}", slots.slot()), Instant::now()));
+                }
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    rewinding = true;
+                }
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => {
+                    rewinding = false;
                 }
                 Event::KeyDown { .. } | Event::KeyUp { .. } => {
                     if let Some(e) = event_to_joypad(event) {
@@ -288,9 +579,29 @@ fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
             }
         }
 
-        if !paused {
+        if !paused && rewinding {
+            // Step back to the previous snapshot and redraw its frame
+            match rewind.rewind(&mut gameboy) {
+                Ok(true) => render_frame(gameboy.frame_buffer(), &mut canvas, &mut texture, outline),
+                Ok(false) => (),
+                Err(e) => log::error!("Failed to rewind: {}", e),
+            }
+        } else if !paused {
             // Render a single frame
-            handle_frame(&mut gameboy, &mut canvas, &mut texture, &mut joypad_events, outline);
+            if handle_frame(&mut gameboy, &mut canvas, &mut texture, &mut joypad_events,
+                            &mut movie, outline) {
+                status = Some(("Movie finished".to_string(), Instant::now()));
+            }
+            rewind.frame(&gameboy);
+        }
+
+        // The debugger frontend ended the session (e.g., GDB killed the program)
+        #[cfg(feature = "debug")]
+        if let Some(reason) = gameboy.debugger().take_detach() {
+            if let gbc::debug::Detach::Error(_) = reason {
+                log::error!("{}", reason);
+            }
+            break 'running;
         }
 
         let elapsed = frame_start.elapsed();
@@ -302,11 +613,68 @@ fn gui(rom_file: PathBuf, scale: u32, speed: u8, boot_rom: bool, trace: bool) {
             sleeper.sleep(frame_duration - elapsed);
         }
 
-        // Update FPS counter in window title
+        // Update FPS counter and status message in window title
         let fps = fps_counter.frame();
-        let title = format!("{} - {:.2} fps", rom_name, fps);
+        let title = match &status {
+            Some((msg, start)) if start.elapsed() < STATUS_DURATION => {
+                format!("{} - {:.2} fps - {}", rom_name, fps, msg)
+            }
+            _ => format!("{} - {:.2} fps", rom_name, fps),
+        };
         canvas.window_mut().set_title(&title).unwrap();
     }
+
+    if let Some(MovieState::Recording { movie, path }) = movie {
+        match movie.save(&path) {
+            Ok(()) => println!("Recorded {} frames to {}", movie.len(), path.display()),
+            Err(e) => eprintln!("Error saving movie: {}", e),
+        }
+    }
+
+    gameboy
+}
+
+/// Writes a code/data log and prints the ROM coverage of each bank.
+fn save_cdl(log: &CodeDataLog, path: &Path) {
+    if let Err(e) = log.save(path) {
+        eprintln!("Error saving code/data log: {}", e);
+        return;
+    }
+
+    println!("Saved code/data log to {}", path.display());
+
+    let coverage = log.coverage();
+    for bank in &coverage {
+        println!("{}", bank);
+    }
+
+    let size: usize = coverage.iter().map(|bank| bank.size).sum();
+    let unused: usize = coverage.iter().map(|bank| bank.unused).sum();
+    println!("Total: {:.1}% used", (size - unused) as f64 * 100.0 / size as f64);
+}
+
+/// Replays a movie without a window, as fast as possible.
+fn replay(rom_file: PathBuf, movie: PathBuf, screenshot: Option<PathBuf>,
+          patch: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let cartridge = load_cartridge(&rom_file, patch, false)?;
+    let mut gameboy = Gameboy::from_cartridge(cartridge, false)?;
+    let movie = Movie::open(&movie)?;
+
+    movie.replay(&mut gameboy)?;
+
+    println!("Replayed {} frames", movie.len());
+
+    let serial = gameboy.serial_output();
+    if !serial.is_empty() {
+        println!("Serial output:\n{}", serial);
+    }
+
+    if let Some(path) = screenshot {
+        let png = slots::encode_png(gameboy.frame_buffer())?;
+        std::fs::write(path, png)?;
+    }
+
+    Ok(())
 }
 
 fn main() {
@@ -315,17 +683,134 @@ fn main() {
     let cli = Args::from_args();
 
     match cli {
-        Args::Run { rom_file, scale, speed, boot_rom, trace } => {
+        Args::Run {
+            rom_file,
+            scale,
+            speed,
+            boot_rom,
+            trace,
+            trace_format,
+            trace_file,
+            trace_start,
+            trace_stop,
+            state_dir,
+            patch,
+            record,
+            movie,
+            gdb,
+            sym,
+            cdl,
+            profile,
+        } => {
             if speed == 0 || speed > 5 {
                 eprintln!("Error: Maximum supported emulator speed is 5x!");
                 return;
             }
 
-            gui(rom_file, scale, speed, boot_rom, trace);
+            let cartridge = match load_cartridge(&rom_file, patch, boot_rom) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Error loading ROM: {}", e);
+                    return;
+                }
+            };
+
+            let mut gameboy = Gameboy::from_cartridge(cartridge, false).unwrap();
+            load_cheats(&mut gameboy, &rom_file);
+            load_symbols(&mut gameboy, &rom_file, sym);
+
+            if let Some(path) = cdl.as_ref() {
+                if let Err(e) = gameboy.enable_cdl().merge_file(path) {
+                    eprintln!("Error loading code/data log {}: {}", path.display(), e);
+                    return;
+                }
+            }
+
+            if trace || trace_format.is_some() || trace_file.is_some() || trace_start.is_some() || trace_stop.is_some() {
+                let config = TraceConfig {
+                    format: trace_format.unwrap_or(TraceFormat::Verbose),
+                    start: trace_start,
+                    stop: trace_stop,
+                };
+                let path = trace_file.unwrap_or_else(|| PathBuf::from("gbc.trace"));
+
+                match Tracer::create(&path, config) {
+                    Ok(tracer) => gameboy.set_tracer(Some(tracer)),
+                    Err(e) => {
+                        eprintln!("Error creating trace file {}: {}", path.display(), e);
+                        return;
+                    }
+                }
+            }
+
+            #[cfg(feature = "debug")]
+            match gdb {
+                Some(addr) => match gbc::debug::gdb::GdbStub::listen(&addr) {
+                    Ok(stub) => {
+                        println!("Waiting for a GDB client on {}", addr);
+                        gameboy.set_debug_frontend(Some(Box::new(stub)));
+                    }
+                    Err(e) => {
+                        eprintln!("Error starting GDB stub: {}", e);
+                        return;
+                    }
+                },
+                None => gameboy.set_debug_frontend(Some(Box::new(gbc::debug::repl::Repl::new()))),
+            }
+
+            #[cfg(not(feature = "debug"))]
+            if gdb.is_some() {
+                eprintln!("Error: --gdb requires the emulator to be built with the \"debug\" feature");
+                return;
+            }
+
+            #[cfg(feature = "debug")]
+            if profile.is_some() {
+                gameboy.set_profiler(Some(gbc::debug::profile::Profiler::new()));
+            }
+
+            #[cfg(not(feature = "debug"))]
+            if profile.is_some() {
+                eprintln!("Error: --profile requires the emulator to be built with the \"debug\" feature");
+                return;
+            }
+
+            // Movie being recorded or played back, if any
+            let movie = if let Some(path) = record {
+                let movie = Movie::record(&mut gameboy, true);
+                Some(MovieState::Recording { movie, path })
+            } else if let Some(path) = movie {
+                let movie = match Movie::open(&path).and_then(|m| m.start(&mut gameboy).map(|_| m)) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        eprintln!("Error loading movie: {}", e);
+                        return;
+                    }
+                };
+                Some(MovieState::Playing { movie, frame: 0 })
+            } else {
+                None
+            };
+
+            let mut gameboy = gui(gameboy, rom_file, scale, speed, state_dir, movie);
+
+            if let (Some(log), Some(path)) = (gameboy.cdl(), cdl) {
+                save_cdl(log, &path);
+            }
+
+            if let Some(path) = profile {
+                save_profile(&mut gameboy, &path);
+            }
+        }
+        Args::Replay { rom_file, movie, screenshot, patch } => {
+            if let Err(e) = replay(rom_file, movie, screenshot, patch) {
+                eprintln!("Error replaying movie: {}", e);
+                std::process::exit(1);
+            }
         }
         Args::Inspect { rom_file } => {
             for f in &rom_file {
-                let cartridge = match Cartridge::from_file(f, false) {
+                let cartridge = match open_cartridge(f, false) {
                     Err(e) => {
                         eprintln!("Error reading cartridge: {}", e);
                         return;
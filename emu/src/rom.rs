@@ -0,0 +1,84 @@
+//! Reading ROMs from disk, including compressed ones
+//!
+//! Besides raw ROM files, `.gz` files and `.zip` archives are supported. For archives,
+//! the first file with a Gameboy ROM extension (`.gb`, `.gbc` or `.cgb`) is used.
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const ROM_EXTENSIONS: &[&str] = &["gb", "gbc", "cgb"];
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| extensions.iter().any(|ext| e.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// Read the contents of a ROM, decompressing it if needed.
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    if has_extension(path, &["zip"]) {
+        read_zip(path)
+    } else if has_extension(path, &["gz"]) {
+        let mut data = Vec::new();
+        GzDecoder::new(File::open(path)?).read_to_end(&mut data)?;
+        Ok(data)
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
+
+fn read_zip(path: &Path) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if !file.is_file() || !has_extension(Path::new(file.name()), ROM_EXTENSIONS) {
+            continue;
+        }
+
+        log::info!("Loading {} from {}", file.name(), path.display());
+
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+        return Ok(data);
+    }
+
+    Err(format!("No ROM found in {}", path.display()).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn compressed_roms() {
+        let rom: Vec<u8> = (0..=255u8).cycle().take(32 * 1024).collect();
+        let dir = std::env::temp_dir().join(format!("gbcemu-rom-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let gz_path = dir.join("test.gb.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&gz_path).unwrap(), Default::default());
+        encoder.write_all(&rom).unwrap();
+        encoder.finish().unwrap();
+
+        let zip_path = dir.join("test.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        zip.start_file("readme.txt", Default::default()).unwrap();
+        zip.write_all(b"Not a ROM").unwrap();
+        zip.start_file("roms/test.gb", Default::default()).unwrap();
+        zip.write_all(&rom).unwrap();
+        zip.finish().unwrap();
+
+        assert_eq!(read(&gz_path).unwrap(), rom);
+        assert_eq!(read(&zip_path).unwrap(), rom);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
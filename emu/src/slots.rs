@@ -0,0 +1,135 @@
+//! Numbered save state slots
+//!
+//! Each slot is stored as `<rom>.ss<N>` in the state directory. A slot file holds
+//! a PNG thumbnail of the screen at the time of the save, followed by the raw
+//! emulator state:
+//!
+//! * Magic (`GBCS`) and format version (1 byte)
+//! * Thumbnail length (4 bytes, LE)
+//! * Thumbnail (PNG)
+//! * Emulator state
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gbc::Gameboy;
+use gbc::ppu::{FrameBuffer, LCD_HEIGHT, LCD_WIDTH};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub struct SaveSlots {
+    /// Directory that holds the slot files
+    dir: PathBuf,
+
+    /// ROM file name without its extension
+    rom_name: String,
+
+    /// Currently selected slot
+    slot: u8,
+}
+
+impl SaveSlots {
+    pub const NUM_SLOTS: u8 = 10;
+
+    const MAGIC: &'static [u8; 4] = b"GBCS";
+    const VERSION: u8 = 1;
+    const HEADER_SIZE: usize = 9;
+
+    /// Slots for the given ROM. If no directory is provided, slots are stored
+    /// next to the ROM.
+    pub fn new(rom_file: &Path, dir: Option<PathBuf>) -> Self {
+        let dir = dir.unwrap_or_else(|| {
+            rom_file.parent().map(Path::to_path_buf).unwrap_or_default()
+        });
+
+        let rom_name = rom_file
+            .file_stem()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "gbc".to_string());
+
+        Self {
+            dir,
+            rom_name,
+            slot: 0,
+        }
+    }
+
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn select(&mut self, slot: u8) {
+        self.slot = slot % Self::NUM_SLOTS;
+    }
+
+    /// Path to the file backing the given slot
+    pub fn path(&self, slot: u8) -> PathBuf {
+        self.dir.join(format!("{}.ss{}", self.rom_name, slot))
+    }
+
+    /// Save the Gameboy into the current slot.
+    pub fn save(&self, gameboy: &Gameboy) -> Result<()> {
+        let thumbnail = encode_png(gameboy.frame_buffer())?;
+        let state = gameboy.save_state();
+
+        let mut data = Vec::with_capacity(Self::HEADER_SIZE + thumbnail.len() + state.len());
+        data.extend_from_slice(Self::MAGIC);
+        data.push(Self::VERSION);
+        data.extend_from_slice(&(thumbnail.len() as u32).to_le_bytes());
+        data.extend_from_slice(&thumbnail);
+        data.extend_from_slice(&state);
+
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(self.slot), data)?;
+
+        Ok(())
+    }
+
+    /// Load the current slot into the Gameboy.
+    pub fn load(&self, gameboy: &mut Gameboy) -> Result<()> {
+        let (_, state) = self.read(self.slot)?;
+        gameboy.load_state(&state)?;
+        Ok(())
+    }
+
+    /// Read the (thumbnail, state) pair stored in a slot.
+    pub fn read(&self, slot: u8) -> Result<(Vec<u8>, Vec<u8>)> {
+        let path = self.path(slot);
+        let mut data = fs::read(&path)?;
+
+        if data.len() < Self::HEADER_SIZE || &data[..4] != Self::MAGIC {
+            return Err(format!("{} is not a save state", path.display()).into());
+        }
+
+        if data[4] != Self::VERSION {
+            return Err(format!("Unsupported save state version: {}", data[4]).into());
+        }
+
+        let thumbnail_len = u32::from_le_bytes(data[5..9].try_into().unwrap()) as usize;
+        let thumbnail_end = Self::HEADER_SIZE + thumbnail_len;
+        if thumbnail_end > data.len() {
+            return Err(format!("{} is truncated", path.display()).into());
+        }
+
+        let state = data.split_off(thumbnail_end);
+        let thumbnail = data.split_off(Self::HEADER_SIZE);
+
+        Ok((thumbnail, state))
+    }
+}
+
+/// Encode a frame as a PNG image
+pub fn encode_png(frame_buffer: &FrameBuffer) -> Result<Vec<u8>> {
+    let mut png = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut png, LCD_WIDTH as u32, LCD_HEIGHT as u32);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&frame_buffer.to_rgba())?;
+    drop(writer);
+
+    Ok(png)
+}
@@ -1,8 +1,10 @@
 //! Common utilities for running test ROMs
+// Each test binary only uses some of these helpers
+#![allow(dead_code)]
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
-use gbc::Gameboy;
+use gbc::{Gameboy, Reg16};
 
 /// Run a single ROM and check each line in stdout using the provided `line_check_fn`
 ///
@@ -44,3 +46,31 @@ pub fn run_single_test_rom(
 
     passed
 }
+
+/// Run a single Mooneye test ROM until it reports a result
+///
+/// Mooneye tests signal completion by loading the Fibonacci sequence (3, 5, 8, 13, 21, 34)
+/// into B, C, D, E, H and L on success, or 0x42 into all of them on failure.
+pub fn run_mooneye_test_rom(rom_path: &PathBuf, timeout: Option<u64>) -> bool {
+    let mut gameboy = Gameboy::init(rom_path, false, false).unwrap();
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(timeout.unwrap_or(10)); // Default timeout is 10 seconds
+
+    loop {
+        if start.elapsed() > timeout {
+            eprintln!("Test timed out after {:?}", timeout);
+            return false;
+        }
+
+        gameboy.frame(None);
+
+        let regs = [Reg16::BC, Reg16::DE, Reg16::HL].map(|reg| gameboy.register(reg));
+
+        match regs {
+            [0x0305, 0x080D, 0x1522] => return true,
+            [0x4242, 0x4242, 0x4242] => return false,
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Run through the Mooneye GB acceptance tests
+use std::path::{Path, PathBuf};
+
+mod common;
+
+fn rom_path(suite: &str, name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("..")
+        .join("samples")
+        .join("mooneye")
+        .join(suite)
+        .join(name)
+}
+
+fn run_suite(suite: &str, roms: &[&str]) {
+    let failed: Vec<&str> = roms
+        .iter()
+        .copied()
+        .filter(|name| !common::run_mooneye_test_rom(&rom_path(suite, name), None))
+        .collect();
+
+    assert!(failed.is_empty(), "Failed tests: {:?}", failed);
+}
+
+/// Run through Mooneye's OAM DMA tests
+#[test]
+fn test_oam_dma() {
+    run_suite("oam_dma", &["basic.gb", "reg_read.gb"]);
+}
@@ -0,0 +1,105 @@
+//! Movie recording and playback tests
+use std::path::Path;
+
+use gbc::Gameboy;
+use gbc::cartridge::Cartridge;
+use gbc::joypad::JoypadInput;
+use gbc::movie::Movie;
+
+/// Replaying a recorded movie should end up in the exact same state
+#[test]
+fn test_movie_replay() {
+    let rom_path =
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("tetris_world.gb");
+
+    let mut gameboy = Gameboy::init(&rom_path, false, false).unwrap();
+    let mut movie = Movie::record(&mut gameboy, true);
+
+    // Mash Start and A through the menus, then move around
+    for frame in 0..300 {
+        let state = match frame % 40 {
+            0..=4 => JoypadInput::Start.mask(),
+            10..=14 => JoypadInput::A.mask(),
+            20..=29 => JoypadInput::Left.mask() | JoypadInput::B.mask(),
+            _ => 0,
+        };
+
+        gameboy.next_frame();
+        gameboy.set_joypad_state(state);
+        movie.push(state);
+    }
+
+    let expected = gameboy.save_state();
+
+    // Serialize the movie and replay it on a fresh Gameboy
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    assert_eq!(movie.len(), 300);
+
+    let mut gameboy = Gameboy::init(&rom_path, false, false).unwrap();
+    movie.replay(&mut gameboy).unwrap();
+
+    assert_eq!(gameboy.save_state(), expected);
+}
+
+/// Movies starting from a save state should not depend on the state of the Gameboy
+/// they are replayed on
+#[test]
+fn test_movie_from_state() {
+    let rom_path =
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("tetris_world.gb");
+
+    let mut gameboy = Gameboy::init(&rom_path, false, false).unwrap();
+    for _ in 0..120 {
+        gameboy.next_frame();
+    }
+
+    let mut movie = Movie::record(&mut gameboy, false);
+    assert!(!movie.power_on());
+
+    for frame in 0..120 {
+        let state = if frame % 2 == 0 { JoypadInput::Start.mask() } else { 0 };
+        gameboy.next_frame();
+        gameboy.set_joypad_state(state);
+        movie.push(state);
+    }
+
+    let expected = gameboy.save_state();
+
+    // Replay on the same Gameboy, which is now in a different state
+    movie.replay(&mut gameboy).unwrap();
+    assert_eq!(gameboy.save_state(), expected);
+}
+
+/// Movies starting at power-on should start from the battery-backed RAM they were
+/// recorded with, not the one of the Gameboy they are replayed on
+#[test]
+fn test_movie_battery_ram() {
+    let rom_path =
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("pokemon_gold.gbc");
+
+    // Without a ROM path, no save files are written next to the sample
+    let init = || {
+        let cartridge = Cartridge::from_bytes(std::fs::read(&rom_path).unwrap(), false).unwrap();
+        Gameboy::from_cartridge(cartridge, false).unwrap()
+    };
+
+    let mut gameboy = init();
+    gameboy.battery_ram().unwrap().fill(0x42);
+
+    let mut movie = Movie::record(&mut gameboy, true);
+    assert!(movie.power_on());
+    movie.push(0);
+
+    let mut gameboy = init();
+    movie.start(&mut gameboy).unwrap();
+    assert!(gameboy.battery_ram().unwrap().iter().all(|&value| value == 0x42));
+}
@@ -0,0 +1,38 @@
+//! Save state tests
+use std::path::Path;
+
+use gbc::Gameboy;
+
+/// Restoring an in-memory save state should replay the exact same frames
+#[test]
+fn test_save_state_roundtrip() {
+    let rom_path =
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("tetris_world.gb");
+
+    let mut gameboy = Gameboy::init(&rom_path, false, false).unwrap();
+
+    for _ in 0..60 {
+        gameboy.frame(None);
+    }
+
+    let state = gameboy.save_state();
+
+    for _ in 0..30 {
+        gameboy.frame(None);
+    }
+
+    let expected = gameboy.save_state();
+
+    // Rewind to the saved state and run the same number of frames
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.save_state(), state);
+
+    for _ in 0..30 {
+        gameboy.frame(None);
+    }
+
+    assert_eq!(gameboy.save_state(), expected);
+}
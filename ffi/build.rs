@@ -0,0 +1,28 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Generate the C header into `OUT_DIR`. If `GBC_FFI_UPDATE_HEADER` is set, the checked-in
+/// `include/gbc.h` is updated as well.
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=GBC_FFI_UPDATE_HEADER");
+
+    // A broken header should not break the build of the library itself
+    let bindings = match cbindgen::generate(&crate_dir) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("cargo:warning=Failed to generate C bindings: {}", e);
+            return;
+        }
+    };
+
+    bindings.write_to_file(out_dir.join("gbc.h"));
+
+    if env::var_os("GBC_FFI_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(crate_dir.join("include").join("gbc.h"));
+    }
+}
@@ -0,0 +1,225 @@
+#ifndef GBC_H
+#define GBC_H
+
+/* Generated by cbindgen from `ffi/src/lib.rs`: do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Width of the screen, in pixels
+ */
+#define GBC_LCD_WIDTH 160
+
+/**
+ * Height of the screen, in pixels
+ */
+#define GBC_LCD_HEIGHT 144
+
+/**
+ * Size of a frame returned by `gbc_framebuffer`, in bytes
+ */
+#define GBC_FRAME_SIZE ((GBC_LCD_WIDTH * GBC_LCD_HEIGHT) * 4)
+
+typedef enum GbcError {
+  GBC_ERROR_OK = 0,
+  /**
+   * A required pointer argument was null
+   */
+  GBC_ERROR_NULL_POINTER = -1,
+  /**
+   * A buffer was too small; see the function docs for the required size
+   */
+  GBC_ERROR_BUFFER_TOO_SMALL = -2,
+  /**
+   * Maps to `gbc::Error::IoError`
+   */
+  GBC_ERROR_IO = -3,
+  /**
+   * Maps to `gbc::Error::Utf8Error`
+   */
+  GBC_ERROR_UTF8 = -4,
+  /**
+   * Maps to `gbc::Error::InvalidValue`
+   */
+  GBC_ERROR_INVALID_VALUE = -5,
+  /**
+   * Maps to `gbc::Error::BincodeError`
+   */
+  GBC_ERROR_BINCODE = -6,
+  /**
+   * The emulator panicked. The handle must not be used again, other than to free it.
+   */
+  GBC_ERROR_PANIC = -7,
+  /**
+   * The CPU executed an illegal opcode and locked up. Only `gbc_load_state` or freeing
+   * the handle are useful from here on.
+   */
+  GBC_ERROR_LOCKED = -8,
+} GbcError;
+
+/**
+ * Opaque handle to an emulator instance
+ */
+typedef struct GbcGameboy GbcGameboy;
+
+/**
+ * CPU registers
+ */
+typedef struct GbcRegisters {
+  uint16_t af;
+  uint16_t bc;
+  uint16_t de;
+  uint16_t hl;
+  uint16_t sp;
+  uint16_t pc;
+} GbcRegisters;
+
+/**
+ * Returns a description of the last error on this thread, or null if there was none.
+ *
+ * The string is valid until the next failing call on this thread.
+ */
+const char *gbc_last_error(void);
+
+/**
+ * Create an emulator running the ROM at the given path.
+ *
+ * Battery-backed RAM is saved next to the ROM. Free the handle with `gbc_free`.
+ *
+ * # Safety
+ *
+ * `path` must be a NUL-terminated string and `out` must be valid for writes.
+ */
+enum GbcError gbc_init_from_path(const char *path, bool boot_rom, struct GbcGameboy **out);
+
+/**
+ * Create an emulator running a ROM that is already in memory.
+ *
+ * The ROM is copied, and battery-backed RAM is not persisted. Free the handle
+ * with `gbc_free`.
+ *
+ * # Safety
+ *
+ * `data` must be valid for reads of `len` bytes and `out` must be valid for writes.
+ */
+enum GbcError gbc_init_from_bytes(const uint8_t *data, size_t len, struct GbcGameboy **out);
+
+/**
+ * Free an emulator created by one of the `gbc_init_*` functions.
+ *
+ * # Safety
+ *
+ * `handle` must be null or a handle that has not been freed yet.
+ */
+void gbc_free(struct GbcGameboy *handle);
+
+/**
+ * Execute a single instruction.
+ *
+ * The number of cycles taken is written to `cycles` and whether a frame was completed
+ * to `frame_ready`. Either can be null.
+ *
+ * If writing the save file failed, the instruction is still executed and the
+ * error is returned.
+ *
+ * If the instruction was illegal, the CPU locks up and `GBC_ERROR_LOCKED` is returned, now
+ * and on every later step until a state is loaded.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle; `cycles` and `frame_ready` must be null or valid for writes.
+ */
+enum GbcError gbc_step(struct GbcGameboy *handle, uint32_t *cycles, bool *frame_ready);
+
+/**
+ * Run the emulator until the next frame is complete.
+ *
+ * If the LCD is off, this gives up after two frames' worth of cycles; `frame_ready`
+ * is then set to false. It can be null.
+ *
+ * As with `gbc_step`, I/O errors and CPU lock-ups during the frame are returned.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle and `frame_ready` must be null or valid for writes.
+ */
+enum GbcError gbc_frame(struct GbcGameboy *handle, bool *frame_ready);
+
+/**
+ * Copy the last frame into `buf` as RGBA pixels, row by row.
+ *
+ * `buf` must hold at least `GBC_FRAME_SIZE` bytes.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle and `buf` must be valid for writes of `len` bytes.
+ */
+enum GbcError gbc_framebuffer(struct GbcGameboy *handle, uint8_t *buf, size_t len);
+
+/**
+ * Set the pressed inputs.
+ *
+ * Each set bit is a pressed input: Right, Left, Up, Down, A, B, Select and Start,
+ * from the lowest bit to the highest.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle.
+ */
+enum GbcError gbc_set_joypad(struct GbcGameboy *handle, uint8_t state);
+
+/**
+ * Read a byte from the memory bus, as the CPU would.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle and `value` must be valid for writes.
+ */
+enum GbcError gbc_read_memory(struct GbcGameboy *handle, uint16_t addr, uint8_t *value);
+
+/**
+ * Write a byte to the memory bus, as the CPU would.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle.
+ */
+enum GbcError gbc_write_memory(struct GbcGameboy *handle, uint16_t addr, uint8_t value);
+
+/**
+ * Read the CPU registers.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle and `registers` must be valid for writes.
+ */
+enum GbcError gbc_registers(struct GbcGameboy *handle, struct GbcRegisters *registers);
+
+/**
+ * Serialize the emulator state into `buf`.
+ *
+ * The size of the state is always written to `size`. If `buf` is null or smaller
+ * than that, `GBC_ERROR_BUFFER_TOO_SMALL` is returned: call again with a buffer that
+ * is large enough.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle, `buf` must be null or valid for writes of `len`
+ * bytes, and `size` must be valid for writes.
+ */
+enum GbcError gbc_save_state(struct GbcGameboy *handle, uint8_t *buf, size_t len, size_t *size);
+
+/**
+ * Restore a state produced by `gbc_save_state`.
+ *
+ * # Safety
+ *
+ * `handle` must be a valid handle and `buf` must be valid for reads of `len` bytes.
+ */
+enum GbcError gbc_load_state(struct GbcGameboy *handle, const uint8_t *buf, size_t len);
+
+#endif /* GBC_H */
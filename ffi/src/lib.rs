@@ -0,0 +1,532 @@
+//! C API for the `gbc` library.
+//!
+//! The emulator is exposed as an opaque `GbcGameboy` handle. Every function that can
+//! fail returns a `GbcError`; on failure, `gbc_last_error` returns a description of
+//! what went wrong. The C header is checked in as `include/gbc.h`: builds generate it
+//! into `OUT_DIR`, and only update the checked-in copy if `GBC_FFI_UPDATE_HEADER` is set.
+//!
+//! None of these functions are thread-safe: a handle must only be used by one thread
+//! at a time.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use gbc::cartridge::Cartridge;
+use gbc::ppu::{LCD_HEIGHT, LCD_WIDTH};
+use gbc::{Event, Gameboy, Reg16};
+
+/// Width of the screen, in pixels
+pub const GBC_LCD_WIDTH: usize = 160;
+
+/// Height of the screen, in pixels
+pub const GBC_LCD_HEIGHT: usize = 144;
+
+/// Size of a frame returned by `gbc_framebuffer`, in bytes
+pub const GBC_FRAME_SIZE: usize = GBC_LCD_WIDTH * GBC_LCD_HEIGHT * 4;
+
+/// Opaque handle to an emulator instance
+pub struct GbcGameboy {
+    gameboy: Gameboy,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GbcError {
+    Ok = 0,
+    /// A required pointer argument was null
+    NullPointer = -1,
+    /// A buffer was too small; see the function docs for the required size
+    BufferTooSmall = -2,
+    /// Maps to `gbc::Error::IoError`
+    Io = -3,
+    /// Maps to `gbc::Error::Utf8Error`
+    Utf8 = -4,
+    /// Maps to `gbc::Error::InvalidValue`
+    InvalidValue = -5,
+    /// Maps to `gbc::Error::BincodeError`
+    Bincode = -6,
+    /// The emulator panicked. The handle must not be used again, other than to free it.
+    Panic = -7,
+    /// The CPU executed an illegal opcode and locked up. Only `gbc_load_state` or freeing
+    /// the handle are useful from here on.
+    Locked = -8,
+}
+
+impl From<&gbc::Error> for GbcError {
+    fn from(err: &gbc::Error) -> Self {
+        match err {
+            gbc::Error::IoError(_) => Self::Io,
+            gbc::Error::Utf8Error(_) => Self::Utf8,
+            gbc::Error::InvalidValue(_) => Self::InvalidValue,
+            gbc::Error::BincodeError(_) => Self::Bincode,
+        }
+    }
+}
+
+/// CPU registers
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GbcRegisters {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    // Interior NULs would truncate the message anyway
+    let msg = CString::new(msg.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Record a `gbc::Error` as the last error and map it to an error code
+fn fail(err: gbc::Error) -> GbcError {
+    let code = GbcError::from(&err);
+    set_last_error(err.to_string());
+    code
+}
+
+/// Report any I/O error or CPU lock-up that occurred while the emulator was running
+fn check(gameboy: &mut Gameboy) -> GbcError {
+    if let Some(err) = gameboy.take_error() {
+        return fail(err);
+    }
+
+    // The lock-up event is only reported once, but the CPU stays locked up
+    let event = gameboy.take_event();
+    if !gameboy.cpu().is_locked() {
+        return GbcError::Ok;
+    }
+
+    match event {
+        Some(event @ Event::Locked { .. }) => set_last_error(event.to_string()),
+        None => set_last_error("CPU is locked up".to_string()),
+    }
+
+    GbcError::Locked
+}
+
+/// Run `f`, turning any panic into `GbcError::Panic`
+fn guard<F: FnOnce() -> GbcError>(f: F) -> GbcError {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(code) => code,
+        Err(err) => {
+            let msg = err
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| err.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "Unknown panic".to_string());
+            set_last_error(format!("Emulator panicked: {}", msg));
+            GbcError::Panic
+        }
+    }
+}
+
+/// Borrow the Gameboy behind a handle
+unsafe fn gameboy<'a>(handle: *mut GbcGameboy) -> Option<&'a mut Gameboy> {
+    handle.as_mut().map(|h| &mut h.gameboy)
+}
+
+/// Return the handle for a cartridge through `out`
+unsafe fn boot(cartridge: gbc::Result<Cartridge>, out: *mut *mut GbcGameboy) -> GbcError {
+    let gameboy = match cartridge.and_then(|c| Gameboy::from_cartridge(c, false)) {
+        Ok(gameboy) => gameboy,
+        Err(err) => return fail(err),
+    };
+
+    *out = Box::into_raw(Box::new(GbcGameboy { gameboy }));
+
+    GbcError::Ok
+}
+
+/// Returns a description of the last error on this thread, or null if there was none.
+///
+/// The string is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn gbc_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Create an emulator running the ROM at the given path.
+///
+/// Battery-backed RAM is saved next to the ROM. Free the handle with `gbc_free`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_init_from_path(
+    path: *const c_char,
+    boot_rom: bool,
+    out: *mut *mut GbcGameboy,
+) -> GbcError {
+    if path.is_null() || out.is_null() {
+        return GbcError::NullPointer;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(err) => return fail(err.into()),
+    };
+
+    guard(|| boot(Cartridge::from_file(path, boot_rom), out))
+}
+
+/// Create an emulator running a ROM that is already in memory.
+///
+/// The ROM is copied, and battery-backed RAM is not persisted. Free the handle
+/// with `gbc_free`.
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_init_from_bytes(
+    data: *const u8,
+    len: usize,
+    out: *mut *mut GbcGameboy,
+) -> GbcError {
+    if data.is_null() || out.is_null() {
+        return GbcError::NullPointer;
+    }
+
+    let data = std::slice::from_raw_parts(data, len).to_vec();
+
+    guard(|| boot(Cartridge::from_bytes(data, false), out))
+}
+
+/// Free an emulator created by one of the `gbc_init_*` functions.
+///
+/// # Safety
+///
+/// `handle` must be null or a handle that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_free(handle: *mut GbcGameboy) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Execute a single instruction.
+///
+/// The number of cycles taken is written to `cycles` and whether a frame was completed
+/// to `frame_ready`. Either can be null.
+///
+/// If writing the save file failed, the instruction is still executed and the
+/// error is returned.
+///
+/// If the instruction was illegal, the CPU locks up and `GBC_ERROR_LOCKED` is returned, now
+/// and on every later step until a state is loaded.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle; `cycles` and `frame_ready` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_step(
+    handle: *mut GbcGameboy,
+    cycles: *mut u32,
+    frame_ready: *mut bool,
+) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) => gameboy,
+        None => return GbcError::NullPointer,
+    };
+
+    guard(|| {
+        let (frame, taken) = gameboy.step();
+        let ready = frame.is_some();
+
+        if let Some(cycles) = cycles.as_mut() {
+            *cycles = taken;
+        }
+
+        if let Some(frame_ready) = frame_ready.as_mut() {
+            *frame_ready = ready;
+        }
+
+        check(gameboy)
+    })
+}
+
+/// Run the emulator until the next frame is complete.
+///
+/// If the LCD is off, this gives up after two frames' worth of cycles; `frame_ready`
+/// is then set to false. It can be null.
+///
+/// As with `gbc_step`, I/O errors and CPU lock-ups during the frame are returned.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle and `frame_ready` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_frame(handle: *mut GbcGameboy, frame_ready: *mut bool) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) => gameboy,
+        None => return GbcError::NullPointer,
+    };
+
+    guard(|| {
+        let ready = gameboy.next_frame().is_some();
+
+        if let Some(frame_ready) = frame_ready.as_mut() {
+            *frame_ready = ready;
+        }
+
+        check(gameboy)
+    })
+}
+
+/// Copy the last frame into `buf` as RGBA pixels, row by row.
+///
+/// `buf` must hold at least `GBC_FRAME_SIZE` bytes.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle and `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_framebuffer(handle: *mut GbcGameboy, buf: *mut u8, len: usize) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) if !buf.is_null() => gameboy,
+        _ => return GbcError::NullPointer,
+    };
+
+    let rgba = gameboy.frame_buffer().to_rgba();
+    debug_assert_eq!(rgba.len(), LCD_WIDTH * LCD_HEIGHT * 4);
+
+    if len < rgba.len() {
+        return GbcError::BufferTooSmall;
+    }
+
+    ptr::copy_nonoverlapping(rgba.as_ptr(), buf, rgba.len());
+
+    GbcError::Ok
+}
+
+/// Set the pressed inputs.
+///
+/// Each set bit is a pressed input: Right, Left, Up, Down, A, B, Select and Start,
+/// from the lowest bit to the highest.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_set_joypad(handle: *mut GbcGameboy, state: u8) -> GbcError {
+    match gameboy(handle) {
+        Some(gameboy) => {
+            gameboy.set_joypad_state(state);
+            GbcError::Ok
+        }
+        None => GbcError::NullPointer,
+    }
+}
+
+/// Read a byte from the memory bus, as the CPU would.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle and `value` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_read_memory(handle: *mut GbcGameboy, addr: u16, value: *mut u8) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) if !value.is_null() => gameboy,
+        _ => return GbcError::NullPointer,
+    };
+
+    guard(|| {
+        *value = gameboy.read_memory(addr);
+        GbcError::Ok
+    })
+}
+
+/// Write a byte to the memory bus, as the CPU would.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_write_memory(handle: *mut GbcGameboy, addr: u16, value: u8) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) => gameboy,
+        None => return GbcError::NullPointer,
+    };
+
+    guard(|| {
+        gameboy.write_memory(addr, value);
+        GbcError::Ok
+    })
+}
+
+/// Read the CPU registers.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle and `registers` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_registers(handle: *mut GbcGameboy, registers: *mut GbcRegisters) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) if !registers.is_null() => gameboy,
+        _ => return GbcError::NullPointer,
+    };
+
+    *registers = GbcRegisters {
+        af: gameboy.register(Reg16::AF),
+        bc: gameboy.register(Reg16::BC),
+        de: gameboy.register(Reg16::DE),
+        hl: gameboy.register(Reg16::HL),
+        sp: gameboy.register(Reg16::SP),
+        pc: gameboy.register(Reg16::PC),
+    };
+
+    GbcError::Ok
+}
+
+/// Serialize the emulator state into `buf`.
+///
+/// The size of the state is always written to `size`. If `buf` is null or smaller
+/// than that, `GBC_ERROR_BUFFER_TOO_SMALL` is returned: call again with a buffer that
+/// is large enough.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle, `buf` must be null or valid for writes of `len`
+/// bytes, and `size` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_save_state(
+    handle: *mut GbcGameboy,
+    buf: *mut u8,
+    len: usize,
+    size: *mut usize,
+) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) if !size.is_null() => gameboy,
+        _ => return GbcError::NullPointer,
+    };
+
+    let state = gameboy.save_state();
+    *size = state.len();
+
+    if buf.is_null() || len < state.len() {
+        return GbcError::BufferTooSmall;
+    }
+
+    ptr::copy_nonoverlapping(state.as_ptr(), buf, state.len());
+
+    GbcError::Ok
+}
+
+/// Restore a state produced by `gbc_save_state`.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle and `buf` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gbc_load_state(handle: *mut GbcGameboy, buf: *const u8, len: usize) -> GbcError {
+    let gameboy = match gameboy(handle) {
+        Some(gameboy) if !buf.is_null() => gameboy,
+        _ => return GbcError::NullPointer,
+    };
+
+    let state = std::slice::from_raw_parts(buf, len);
+
+    guard(|| match gameboy.load_state(state) {
+        Ok(()) => GbcError::Ok,
+        Err(err) => fail(err),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("tetris_world.gb");
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn c_api() {
+        let rom = rom();
+        let mut handle = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(gbc_init_from_bytes(rom.as_ptr(), rom.len(), &mut handle), GbcError::Ok);
+
+            let mut ready = false;
+            for _ in 0..60 {
+                assert_eq!(gbc_frame(handle, &mut ready), GbcError::Ok);
+            }
+            assert!(ready);
+
+            let mut frame = vec![0u8; GBC_FRAME_SIZE];
+            assert_eq!(gbc_framebuffer(handle, frame.as_mut_ptr(), 16), GbcError::BufferTooSmall);
+            assert_eq!(gbc_framebuffer(handle, frame.as_mut_ptr(), frame.len()), GbcError::Ok);
+
+            // Memory and registers
+            let mut value = 0;
+            assert_eq!(gbc_write_memory(handle, 0xC000, 0x42), GbcError::Ok);
+            assert_eq!(gbc_read_memory(handle, 0xC000, &mut value), GbcError::Ok);
+            assert_eq!(value, 0x42);
+
+            let mut registers = GbcRegisters::default();
+            assert_eq!(gbc_registers(handle, &mut registers), GbcError::Ok);
+            assert_ne!(registers.pc, 0x0100);
+
+            // Save states: query the size, then save and restore
+            let mut size = 0;
+            assert_eq!(gbc_save_state(handle, ptr::null_mut(), 0, &mut size), GbcError::BufferTooSmall);
+            let mut state = vec![0u8; size];
+            assert_eq!(gbc_save_state(handle, state.as_mut_ptr(), state.len(), &mut size), GbcError::Ok);
+
+            assert_eq!(gbc_write_memory(handle, 0xC000, 0x00), GbcError::Ok);
+            assert_eq!(gbc_load_state(handle, state.as_ptr(), state.len()), GbcError::Ok);
+            assert_eq!(gbc_read_memory(handle, 0xC000, &mut value), GbcError::Ok);
+            assert_eq!(value, 0x42);
+
+            // Errors are mapped from `gbc::Error` and described by `gbc_last_error`
+            assert_eq!(gbc_load_state(handle, state.as_ptr(), 4), GbcError::Bincode);
+            assert!(!gbc_last_error().is_null());
+
+            gbc_free(handle);
+        }
+
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(gbc_init_from_bytes(rom.as_ptr(), 16, &mut handle), GbcError::InvalidValue);
+            assert!(handle.is_null());
+            assert_eq!(gbc_step(handle, ptr::null_mut(), ptr::null_mut()), GbcError::NullPointer);
+        }
+    }
+
+    #[test]
+    fn locked() {
+        // Illegal opcode at the entry point
+        let mut rom = rom();
+        rom[0x100] = 0xED;
+        let mut handle = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(gbc_init_from_bytes(rom.as_ptr(), rom.len(), &mut handle), GbcError::Ok);
+
+            // The CPU stays locked up, so every step reports it
+            for _ in 0..3 {
+                assert_eq!(gbc_step(handle, ptr::null_mut(), ptr::null_mut()), GbcError::Locked);
+                assert!(!gbc_last_error().is_null());
+            }
+            assert_eq!(gbc_frame(handle, ptr::null_mut()), GbcError::Locked);
+
+            gbc_free(handle);
+        }
+    }
+}
@@ -1,11 +1,16 @@
 use std::convert::{TryFrom, TryInto};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::path::PathBuf;
 
+use crate::cdl::CodeDataLog;
+use crate::cheats::GameGenie;
 use crate::error::{Error, Result};
 use crate::memory::{MemoryRead, MemoryWrite};
 use crate::rtc::Rtc;
+#[cfg(feature = "fs")]
+use crate::storage::FileStorage;
+use crate::storage::Storage;
 
 // Cartridge RAM size
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -59,12 +64,16 @@ pub struct Ram {
     ram_size: RamSize,
 
     #[cfg_attr(feature = "save", serde(skip))]
-    file: Option<File>,
+    storage: Option<Box<dyn Storage>>,
+
+    /// First failed write to the storage, if any (see `Gameboy::take_error`)
+    #[cfg_attr(feature = "save", serde(skip))]
+    error: Option<Error>,
 }
 
 /// 8 KB switchable/banked external RAM
 impl Ram {
-    const BANK_SIZE: usize = 8 * 1024; // 8K
+    pub(crate) const BANK_SIZE: usize = 8 * 1024; // 8K
     pub const BASE_ADDR: u16 = 0xA000;
     pub const LAST_ADDR: u16 = 0xBFFF;
 
@@ -90,37 +99,62 @@ impl Ram {
                     active_bank: 0,
                     num_banks,
                     ram_size,
-                    file: None,
+                    storage: None,
+                    error: None,
                 })
             }
         }
     }
 
-    /// Create a new battery-backed RAM (i.e., save file support).
+    /// Make this RAM battery-backed (i.e., save file support).
     ///
-    /// If `overwrite` is `true`, overwrite any existing file with the current state. This
-    /// is used when loading from a save state.
-    pub fn enable_battery<P: AsRef<Path>>(&mut self, rom_path: P, overwrite: bool) -> Result<()> {
-        let save_path = rom_path.as_ref().with_extension("sav");
-        let mut save_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(save_path)?;
+    /// If `overwrite` is `true`, overwrite any existing data in the storage with the
+    /// current state. This is used when loading from a save state.
+    pub fn enable_battery(&mut self, mut storage: Box<dyn Storage>, overwrite: bool) -> Result<()> {
+        match storage.load()? {
+            Some(data) if !overwrite && data.len() == self.data.len() => {
+                // Load all data from the storage into RAM
+                self.data.copy_from_slice(&data);
+            }
+            _ => storage.save(&self.data)?,
+        }
+
+        self.storage = Some(storage);
+
+        Ok(())
+    }
 
-        if overwrite {
-            // Overwrite the contents of the backing file
-            save_file.write_all(&self.data)?;
-        } else if !overwrite && save_file.metadata()?.len() as usize == self.data.len() {
-            // Load all data from the file into RAM
-            save_file.read_exact(&mut self.data)?;
+    /// Overwrite the storage, if any, with `data`
+    #[cfg(feature = "save")]
+    fn persist(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.storage {
+            Some(storage) => storage.save(data),
+            None => Ok(()),
         }
+    }
 
-        save_file.set_len(self.data.len() as u64)?;
+    /// Take over the storage of `prev`, if any, along with its buffer: the contents of
+    /// this RAM are copied into it, so that `data_mut` keeps pointing to the same memory.
+    #[cfg(feature = "save")]
+    fn restore_from(&mut self, prev: &mut Ram) {
+        self.storage = prev.storage.take();
 
-        self.file = Some(save_file);
+        if prev.data.len() == self.data.len() {
+            prev.data.copy_from_slice(&self.data);
+            std::mem::swap(&mut self.data, &mut prev.data);
+        }
+    }
 
-        Ok(())
+    /// Raw contents of all RAM banks
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Raw contents of all RAM banks.
+    ///
+    /// Writes through this slice bypass the storage.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
     }
 
     /// Handle a bank change request
@@ -131,15 +165,28 @@ impl Ram {
 
         self.active_bank = bank & (self.num_banks - 1);
     }
+
+    /// Returns the first storage error since the last call, if any
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Index into `data` for an address in the current active bank
+    ///
+    /// RAM smaller than a bank (2K) is mirrored across the bank.
+    #[inline]
+    fn index(&self, addr: u16) -> usize {
+        let addr = (addr - Self::BASE_ADDR) as usize;
+        let bank_offset = self.active_bank as usize * Self::BANK_SIZE;
+        (bank_offset + addr) % self.data.len()
+    }
 }
 
 impl MemoryRead<u16, u8> for Ram {
     /// Read a byte of data from the current active bank
     #[inline]
     fn read(&self, addr: u16) -> u8 {
-        let addr = (addr - Self::BASE_ADDR) as usize;
-        let bank_offset = self.active_bank as usize * Self::BANK_SIZE;
-        self.data[bank_offset + addr]
+        self.data[self.index(addr)]
     }
 }
 
@@ -147,16 +194,15 @@ impl MemoryWrite<u16, u8> for Ram {
     /// Write a byte of data to the current active bank
     #[inline]
     fn write(&mut self, addr: u16, value: u8) {
-        let addr = (addr - Self::BASE_ADDR) as usize;
-        let bank_offset = self.active_bank as usize * Self::BANK_SIZE;
-        let index = bank_offset + addr;
+        let index = self.index(addr);
 
         self.data[index] = value;
 
-        if let Some(file) = &mut self.file {
+        if let Some(storage) = &mut self.storage {
             // Write through to the save file
-            file.seek(SeekFrom::Start(index as u64)).unwrap();
-            file.write(&[value]).unwrap();
+            if let Err(err) = storage.write(index, &[value]) {
+                self.error.get_or_insert(err);
+            }
         }
     }
 }
@@ -200,6 +246,29 @@ impl From<RomSize> for usize {
     }
 }
 
+impl RomSize {
+    /// Smallest standard ROM size that fits the given number of bytes
+    pub fn from_len(len: usize) -> Result<Self> {
+        let sizes = [
+            RomSize::_32K,
+            RomSize::_64K,
+            RomSize::_128K,
+            RomSize::_256K,
+            RomSize::_512K,
+            RomSize::_1M,
+            RomSize::_2M,
+            RomSize::_4M,
+            RomSize::_8M,
+        ];
+
+        sizes
+            .iter()
+            .copied()
+            .find(|s| usize::from(*s) >= len)
+            .ok_or_else(|| Error::InvalidValue(format!("ROM is too large: {} bytes", len)))
+    }
+}
+
 impl TryFrom<u8> for RomSize {
     type Error = Error;
 
@@ -263,54 +332,72 @@ impl Rom {
         }
     }
 
-    pub fn load(&mut self, rom_file: &mut File) -> Result<()> {
-        let size = usize::from(self.rom_size);
+    /// CRC32 of the full ROM data
+    pub fn checksum(&self) -> u32 {
+        crc32fast::hash(&self.data)
+    }
 
-        // Seek to beginning of the ROM file and read all banks
-        rom_file.seek(SeekFrom::Start(0))?;
+    /// Create a ROM from data that is already in memory.
+    ///
+    /// The data is padded with 0xFF (or truncated) to the given size.
+    pub fn from_bytes(rom_size: RomSize, mut data: Vec<u8>) -> Self {
+        let size = usize::from(rom_size);
+        data.resize(size, 0xFF);
 
-        if self.data.len() == 0 {
-            // No data in ROM; read everything from the file
-            rom_file.read_to_end(&mut self.data)?;
-        } else {
-            rom_file.read_exact(&mut self.data)?;
+        Self {
+            data,
+            active_bank_0: 0,
+            active_bank_1: 1,
+            num_banks: (size / Self::BANK_SIZE) as u16,
+            rom_size,
+        }
+    }
+
+    /// Offset in the ROM data of the byte mapped at `addr`
+    #[inline]
+    pub(crate) fn offset(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+
+        match addr {
+            // Bank 0
+            0x0000..=0x3FFF => self.active_bank_0 as usize * Self::BANK_SIZE + addr,
+            // Bank 1 (dynamic)
+            0x4000..=0x7FFF => self.active_bank_1 as usize * Self::BANK_SIZE + addr - 0x4000,
+            _ => unreachable!("Unexpected ROM address: {}", addr),
         }
+    }
+
+    /// ROM size, in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
 
-        assert!(self.data.len() == size, "Expected {} bytes in ROM, found {}", size, self.data.len());
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 
-        Ok(())
+    /// Wrap a bank number to the banks that actually exist.
+    ///
+    /// The MBC drives more bank lines than a small ROM chip has address lines for, so
+    /// on hardware the upper bits are simply ignored.
+    #[inline]
+    fn mask_bank(&self, bank: u16) -> u16 {
+        bank % self.num_banks
     }
 
     pub fn update_bank_0(&mut self, bank: u16) {
-        assert!(bank < self.num_banks);
-        self.active_bank_0 = bank;
+        self.active_bank_0 = self.mask_bank(bank);
     }
 
     pub fn update_bank(&mut self, bank: u16) {
-        assert!(bank < self.num_banks);
-        self.active_bank_1 = bank;
+        self.active_bank_1 = self.mask_bank(bank);
     }
 }
 
 impl MemoryRead<u16, u8> for Rom {
     #[inline]
     fn read(&self, addr: u16) -> u8 {
-        let addr = addr as usize;
-
-        match addr {
-            0x0000..=0x3FFF => {
-                // Bank 0
-                let bank_offset = self.active_bank_0 as usize * Self::BANK_SIZE;
-                self.data[bank_offset + addr]
-            }
-            0x4000..=0x7FFF => {
-                // Bank 1 (dynamic)
-                let addr = addr - 0x4000;
-                let bank_offset = self.active_bank_1 as usize * Self::BANK_SIZE;
-                self.data[bank_offset + addr]
-            }
-            _ => unreachable!("Unexpected read from: {}", addr),
-        }
+        self.data[self.offset(addr)]
     }
 }
 
@@ -318,22 +405,8 @@ impl MemoryRead<u16, u8> for Rom {
 impl MemoryWrite<u16, u8> for Rom {
     #[inline]
     fn write(&mut self, addr: u16, value: u8) {
-        let addr = addr as usize;
-
-        match addr {
-            0x0000..=0x3FFF => {
-                // Bank 0
-                let bank_offset = self.active_bank_0 as usize * Self::BANK_SIZE;
-                self.data[bank_offset + addr] = value;
-            }
-            0x4000..=0x7FFF => {
-                // Bank 1 (dynamic)
-                let addr = addr - 0x4000;
-                let bank_offset = self.active_bank_1 as usize * Self::BANK_SIZE;
-                self.data[bank_offset + addr] = value;
-            }
-            _ => unreachable!("Unexpected read from: {}", addr),
-        }
+        let offset = self.offset(addr);
+        self.data[offset] = value;
     }
 }
 
@@ -398,6 +471,14 @@ pub struct Controller {
 
     /// RAM/ROM bank select register
     ram_rom_bank: u8,
+
+    /// Active Game Genie codes, applied to ROM reads
+    #[cfg_attr(feature = "save", serde(skip))]
+    pub(crate) game_genie: Vec<GameGenie>,
+
+    /// Code/data log of ROM usage, if enabled
+    #[cfg_attr(feature = "save", serde(skip))]
+    pub(crate) cdl: Option<CodeDataLog>,
 }
 
 impl Controller {
@@ -418,6 +499,8 @@ impl Controller {
             banking_mode: false,
             ram_enable: false,
             ram_rom_bank: 0,
+            game_genie: Vec::new(),
+            cdl: None,
         }
     }
 
@@ -435,13 +518,17 @@ impl Controller {
         };
 
         let mut ram = Ram::new(ram_size);
-        if ram.is_some() && cartridge_type.is_battery_backed() {
-            ram.as_mut().unwrap().enable_battery(&cartridge.rom_path, false)?;
+        if let Some(ram) = ram.as_mut().filter(|_| cartridge_type.is_battery_backed()) {
+            if let Some(storage) = cartridge.ram_storage()? {
+                ram.enable_battery(storage, false)?;
+            }
         }
 
         let rtc = if cartridge_type.is_rtc() {
             let mut rtc = Rtc::new();
-            rtc.with_file(&cartridge.rom_path, false)?;
+            if let Some(storage) = cartridge.rtc_storage()? {
+                rtc.with_storage(storage, false)?;
+            }
             rtc.into()
         } else {
             None
@@ -459,34 +546,109 @@ impl Controller {
             banking_mode: false,
             ram_enable: false,
             ram_rom_bank: 0,
+            game_genie: Vec::new(),
+            cdl: None,
         })
     }
 
     #[cfg(feature = "save")]
-    /// Load data into controller from a ROM file
-    pub fn load<P: AsRef<Path>>(&mut self, rom_path: P) -> Result<()> {
-        // Load the ROM contents from disk
-        let mut rom_file = File::open(&rom_path)?;
-        self.rom.load(&mut rom_file)?;
+    /// Load the ROM and storage of a cartridge into this controller
+    pub fn load(&mut self, cartridge: &mut Cartridge) -> Result<()> {
+        self.rom.data = cartridge.rom()?.data;
 
-        // Check if we need to create a file for battery-backed cartridge RAM
+        // Check if we need to create a storage for battery-backed cartridge RAM
         if self.cartridge_type.is_battery_backed() {
-            let ram = match self.ram.as_mut() {
-                None => panic!("Cartridge is battery-backed, yet save file contains no RAM!"),
-                Some(ram) => ram,
-            };
+            let ram = self.ram.as_mut().ok_or_else(|| {
+                Error::InvalidValue("Cartridge is battery-backed, yet save file contains no RAM".to_string())
+            })?;
 
-            ram.enable_battery(&rom_path, true)?;
+            if let Some(storage) = cartridge.ram_storage()? {
+                ram.enable_battery(storage, true)?;
+            }
         }
 
-        // Check if we need to create a file for the RTC
+        // Check if we need to create a storage for the RTC
         if self.cartridge_type.is_rtc() {
-            let rtc = match self.rtc.as_mut() {
-                None => panic!("Cartridge has RTC enabled, yet save file contains no RTC!"),
-                Some(rtc) => rtc,
-            };
+            let rtc = self.rtc.as_mut().ok_or_else(|| {
+                Error::InvalidValue("Cartridge has RTC enabled, yet save file contains no RTC".to_string())
+            })?;
+
+            if let Some(storage) = cartridge.rtc_storage()? {
+                rtc.with_storage(storage, true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Carry over the ROM, boot ROM and backing files from a running controller.
+    ///
+    /// This is used when a save state is loaded in place: all of these are skipped
+    /// during serialization, but the cartridge itself has not changed.
+    #[cfg(feature = "save")]
+    pub fn restore_from(&mut self, prev: &mut Controller, persist: bool) -> Result<()> {
+        // Storage writes can fail: do them while the storage still belongs to `prev`,
+        // which is still the running controller if this returns an error
+        if persist {
+            if let (Some(ram), Some(prev_ram)) = (self.ram.as_ref(), prev.ram.as_mut()) {
+                prev_ram.persist(&ram.data)?;
+            }
+
+            if let (Some(rtc), Some(prev_rtc)) = (self.rtc.as_ref(), prev.rtc.as_mut()) {
+                prev_rtc.persist(rtc)?;
+            }
+        }
+
+        if let (Some(ram), Some(prev_ram)) = (self.ram.as_mut(), prev.ram.as_mut()) {
+            ram.restore_from(prev_ram);
+        }
+
+        if let (Some(rtc), Some(prev_rtc)) = (self.rtc.as_mut(), prev.rtc.as_mut()) {
+            rtc.restore_from(prev_rtc);
+        }
+
+        std::mem::swap(&mut self.rom.data, &mut prev.rom.data);
+        self.boot_rom = prev.boot_rom.take();
+        self.cdl = prev.cdl.take();
+
+        Ok(())
+    }
+
+    /// Serialize the battery-backed RAM and the RTC, i.e., what survives a power cycle
+    #[cfg(feature = "save")]
+    pub(crate) fn save_battery(&mut self) -> Vec<u8> {
+        let ram = self.battery_ram().map(|ram| ram.data.clone());
+        crate::serialize(&(ram, &self.rtc))
+    }
+
+    /// Restore the battery-backed RAM and RTC produced by `Self::save_battery`, and
+    /// write them to their storage
+    #[cfg(feature = "save")]
+    pub(crate) fn load_battery(&mut self, data: &[u8]) -> Result<()> {
+        let (ram, rtc): (Option<Vec<u8>>, Option<Rtc>) = bincode::deserialize(data)?;
+
+        if let (Some(data), Some(current)) = (ram.as_ref(), self.battery_ram()) {
+            if data.len() != current.data.len() {
+                return Err(Error::InvalidValue(format!(
+                    "Battery RAM size ({} bytes) does not match the cartridge ({} bytes)",
+                    data.len(),
+                    current.data.len(),
+                )));
+            }
+            current.persist(data)?;
+        }
+
+        if let (Some(rtc), Some(current)) = (rtc.as_ref(), self.rtc.as_mut()) {
+            current.persist(rtc)?;
+        }
+
+        if let (Some(data), Some(current)) = (ram, self.battery_ram()) {
+            current.data.copy_from_slice(&data);
+        }
 
-            rtc.with_file(&rom_path, true)?;
+        if let (Some(mut rtc), Some(current)) = (rtc, self.rtc.as_mut()) {
+            rtc.restore_from(current);
+            *current = rtc;
         }
 
         Ok(())
@@ -496,7 +658,33 @@ impl Controller {
     ///
     /// ROM remains unchanged, RAM is reset
     pub fn reset(&mut self) {
-        self.ram = Ram::new(self.ram_size);
+        // Battery-backed RAM survives a reset, just like on the real thing
+        if !self.cartridge_type.is_battery_backed() {
+            self.ram = Ram::new(self.ram_size);
+        }
+    }
+
+    /// Switch the cartridge RAM bank, if the cartridge has RAM
+    fn set_ram_bank(&mut self, bank: u8) {
+        if let Some(ram) = self.ram.as_mut() {
+            ram.set_bank(bank);
+        }
+    }
+
+    /// Returns the first storage error (RAM or RTC) since the last call, if any
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        let ram_error = self.ram.as_mut().and_then(|ram| ram.take_error());
+        let rtc_error = self.rtc.as_mut().and_then(|rtc| rtc.take_error());
+        ram_error.or(rtc_error)
+    }
+
+    /// Battery-backed cartridge RAM, if any
+    pub fn battery_ram(&mut self) -> Option<&mut Ram> {
+        if self.cartridge_type.is_battery_backed() {
+            self.ram.as_mut()
+        } else {
+            None
+        }
     }
 }
 
@@ -504,12 +692,23 @@ impl MemoryRead<u16, u8> for Controller {
     #[inline]
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            Rom::BASE_ADDR..=Rom::LAST_ADDR => self.rom.read(addr),
+            Rom::BASE_ADDR..=Rom::LAST_ADDR => {
+                if let Some(cdl) = self.cdl.as_ref() {
+                    cdl.log_read(self.rom.offset(addr));
+                }
+
+                let value = self.rom.read(addr);
+                self.game_genie
+                    .iter()
+                    .find_map(|code| code.apply(addr, value))
+                    .unwrap_or(value)
+            }
             Ram::BASE_ADDR..=Ram::LAST_ADDR => {
+                // Reads from missing RAM or RTC return open bus
                 if !self.rtc_active {
-                    self.ram.as_ref().unwrap().read(addr)
+                    self.ram.as_ref().map_or(0xFF, |ram| ram.read(addr))
                 } else {
-                    self.rtc.as_ref().unwrap().read()
+                    self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read())
                 }
             }
             _ => unreachable!("Invalid read from 0x{:X}", addr),
@@ -544,7 +743,7 @@ impl MemoryWrite<u16, u8> for Controller {
 
                 if usize::from(self.ram_size) == RamSize::_32K.into() {
                     // Switch RAM bank, but only in advanced banking mode
-                    self.ram.as_mut().unwrap().set_bank(value);
+                    self.set_ram_bank(value);
                 } else if usize::from(self.rom_size) >= RomSize::_1M.into() {
                     // For large ROM carts, there are two options:
                     if !self.banking_mode {
@@ -580,7 +779,7 @@ impl MemoryWrite<u16, u8> for Controller {
 
                 if self.ram_enable && large_ram && banking_mode {
                     // Large RAM, switch to previously selected bank immediately
-                    self.ram.as_mut().unwrap().set_bank(self.ram_rom_bank);
+                    self.set_ram_bank(self.ram_rom_bank);
                 }
 
                 self.banking_mode = banking_mode;
@@ -618,19 +817,25 @@ impl MemoryWrite<u16, u8> for Controller {
                 let value = value & 0x0F;
 
                 match value {
-                    0x0..=0x3 => {
-                        self.ram.as_mut().unwrap().set_bank(value);
+                    0x0..=0x7 => {
+                        // Banks 4-7 only exist on MBC30: on smaller carts, the bank
+                        // is masked to the RAM size
+                        self.set_ram_bank(value);
                         self.rtc_active = false;
                     }
                     0x8..=0xC => {
-                        self.rtc.as_mut().unwrap().select(value);
+                        if let Some(rtc) = self.rtc.as_mut() {
+                            rtc.select(value);
+                        }
                         self.rtc_active = true;
                     }
-                    _ => unreachable!(),
+                    _ => log::debug!("Ignored MBC3 RAM bank select: 0x{:X}", value),
                 }
             }
             0x6000..=0x7FFF if self.cartridge_type.is_mbc3() => {
-                self.rtc.as_mut().unwrap().latch(value);
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.latch(value);
+                }
             }
             0x0000..=0x1FFF if self.cartridge_type.is_mbc5() => {
                 // Cartridge RAM enable/disable
@@ -648,19 +853,19 @@ impl MemoryWrite<u16, u8> for Controller {
             }
             0x4000..=0x5FFF if self.cartridge_type.is_mbc5() => {
                 // MBC5 RAM bank select (4 bits)
-                self.ram.as_mut().unwrap().set_bank(value & 0xF);
+                self.set_ram_bank(value & 0xF);
             }
 
             Ram::BASE_ADDR..=Ram::LAST_ADDR if !self.rtc_active => {
                 // Forward RAM writes as-is
-                if self.ram_enable {
-                    self.ram.as_mut().unwrap().write(addr, value)
+                if let (Some(ram), true) = (self.ram.as_mut(), self.ram_enable) {
+                    ram.write(addr, value);
                 }
             }
             Ram::BASE_ADDR..=Ram::LAST_ADDR if self.rtc_active => {
                 // If RTC is active, writes go to the RTC registers
-                if self.ram_enable {
-                    self.rtc.as_mut().unwrap().write(value);
+                if let (Some(rtc), true) = (self.rtc.as_mut(), self.ram_enable) {
+                    rtc.write(value);
                 }
             }
 
@@ -821,9 +1026,14 @@ impl TryFrom<u8> for CartridgeType {
 }
 
 pub struct Cartridge {
-    /// ROM file
-    pub rom_file: File,
-    pub rom_path: PathBuf,
+    /// ROM contents
+    data: Vec<u8>,
+
+    /// Path to the ROM, if it was loaded from a file.
+    ///
+    /// Unless a storage is set explicitly, battery-backed RAM and the RTC are stored
+    /// in files next to this path (`.sav` and `.rtcs`, respectively).
+    pub rom_path: Option<PathBuf>,
 
     /// If `true`, boot ROM is executed on boot/reset,
     /// prior to loading the game
@@ -833,31 +1043,145 @@ pub struct Cartridge {
     ///
     /// See: https://gbdev.gg8.se/wiki/articles/The_Cartridge_Header
     pub header: [u8; Self::HEADER_SIZE],
+
+    /// `true` if a patch was applied to the ROM
+    patched: bool,
+
+    /// Storage for battery-backed RAM
+    ram_storage: Option<Box<dyn Storage>>,
+
+    /// Storage for the RTC state
+    rtc_storage: Option<Box<dyn Storage>>,
 }
 
 impl Cartridge {
     const HEADER_SIZE: usize = 0x50; // bytes
-    const HEADER_OFFSET: u64 = 0x100;
+    const HEADER_OFFSET: usize = 0x100;
 
+    #[cfg(feature = "fs")]
     pub fn from_file<P: AsRef<Path>>(path: P, boot_rom: bool) -> Result<Self> {
-        let mut rom_file = File::open(&path)?;
-        let rom_path = PathBuf::from(path.as_ref());
-        let mut header = [0u8; Self::HEADER_SIZE];
-
-        // Read the header in
-        rom_file.seek(SeekFrom::Start(Self::HEADER_OFFSET))?;
-        rom_file.read(&mut header)?;
+        let data = std::fs::read(&path)?;
+        let mut cartridge = Self::from_bytes(data, boot_rom)?;
+        cartridge.rom_path = Some(PathBuf::from(path.as_ref()));
+        Ok(cartridge)
+    }
 
-        let cartridge = Self {
-            rom_file,
-            rom_path,
+    /// Create a cartridge from a ROM that is already in memory.
+    ///
+    /// Battery-backed RAM and the RTC are not persisted unless a storage is set
+    /// with `Self::set_ram_storage` and `Self::set_rtc_storage`.
+    pub fn from_bytes(data: Vec<u8>, boot_rom: bool) -> Result<Self> {
+        let mut cartridge = Self {
+            data,
+            rom_path: None,
             boot_rom,
-            header,
+            header: [0u8; Self::HEADER_SIZE],
+            patched: false,
+            ram_storage: None,
+            rtc_storage: None,
         };
 
+        cartridge.read_header()?;
+
         Ok(cartridge)
     }
 
+    /// Read the header in from the ROM data
+    fn read_header(&mut self) -> Result<()> {
+        let header_end = Self::HEADER_OFFSET + Self::HEADER_SIZE;
+        if self.data.len() < header_end {
+            return Err(Error::InvalidValue(format!(
+                "ROM is too small: {} bytes", self.data.len()
+            )));
+        }
+
+        self.header.copy_from_slice(&self.data[Self::HEADER_OFFSET..header_end]);
+
+        Ok(())
+    }
+
+    /// Use the given storage for battery-backed RAM
+    pub fn set_ram_storage(&mut self, storage: Box<dyn Storage>) {
+        self.ram_storage = Some(storage);
+    }
+
+    /// Use the given storage for the RTC state
+    pub fn set_rtc_storage(&mut self, storage: Box<dyn Storage>) {
+        self.rtc_storage = Some(storage);
+    }
+
+    /// Storage for battery-backed RAM: either the one set explicitly, or a file next to the ROM
+    pub(crate) fn ram_storage(&mut self) -> Result<Option<Box<dyn Storage>>> {
+        self.storage("sav", |c| c.ram_storage.take())
+    }
+
+    /// Storage for the RTC: either the one set explicitly, or a file next to the ROM
+    pub(crate) fn rtc_storage(&mut self) -> Result<Option<Box<dyn Storage>>> {
+        self.storage("rtcs", |c| c.rtc_storage.take())
+    }
+
+    fn storage<F>(&mut self, extension: &str, take: F) -> Result<Option<Box<dyn Storage>>>
+    where
+        F: FnOnce(&mut Self) -> Option<Box<dyn Storage>>,
+    {
+        if let Some(storage) = take(self) {
+            return Ok(Some(storage));
+        }
+
+        #[cfg(feature = "fs")]
+        if let Some(path) = &self.rom_path {
+            return Ok(Some(Box::new(FileStorage::open(path.with_extension(extension))?)));
+        }
+
+        #[cfg(not(feature = "fs"))]
+        let _ = extension;
+
+        Ok(None)
+    }
+
+    /// Apply an IPS, BPS or UPS patch file to this cartridge.
+    ///
+    /// The patch is applied in memory: the ROM file is left untouched. Patches can
+    /// be stacked by calling this multiple times.
+    #[cfg(feature = "fs")]
+    pub fn patch<P: AsRef<Path>>(&mut self, patch_path: P) -> Result<()> {
+        let patch = std::fs::read(patch_path)?;
+        self.apply_patch(&patch)
+    }
+
+    /// Apply an IPS, BPS or UPS patch that is already in memory.
+    pub fn apply_patch(&mut self, patch: &[u8]) -> Result<()> {
+        let data = crate::patch::apply(&self.data, patch)?;
+        let header = self.header;
+        let prev = std::mem::replace(&mut self.data, data);
+
+        if let Err(e) = self.read_header() {
+            self.data = prev;
+            self.header = header;
+            return Err(e);
+        }
+
+        // Re-derive the ROM size in case the patch grew the ROM without
+        // updating the header accordingly
+        match self.rom_size() {
+            Ok(size) if usize::from(size) >= self.data.len() => (),
+            _ => {
+                let size = RomSize::from_len(self.data.len())? as u8;
+                self.data[Self::HEADER_OFFSET + 0x48] = size;
+                self.header[0x48] = size;
+            }
+        }
+
+        self.patched = true;
+
+        Ok(())
+    }
+
+    /// Returns `true` if a patch was applied to this cartridge
+    pub fn is_patched(&self) -> bool {
+        self.patched
+    }
+
     /// Entry point
     pub fn entry_point(&self) -> [u8; 4] {
         let raw = &self.header[0..=3];
@@ -905,13 +1229,10 @@ impl Cartridge {
     }
 
     /// SGB flag
+    ///
+    /// Only 0x03 enables SGB functions: any other value is treated as no SGB support.
     pub fn sgb(&self) -> bool {
-        let sgb = self.header[0x46];
-        match sgb {
-            0x0 => false,
-            0x3 => true,
-            _ => panic!("Unknown SGB value: {}", sgb),
-        }
+        self.header[0x46] == 0x03
     }
 
     /// Cartridge type
@@ -933,12 +1254,7 @@ impl Cartridge {
     ///
     /// `true` if Japanese, `false` otherwise
     pub fn destination_code(&self) -> bool {
-        let code = self.header[0x4A];
-        match code {
-            0x0 => true,
-            0x1 => false,
-            _ => panic!("Unknown destination code: {}", code),
-        }
+        self.header[0x4A] == 0x00
     }
 
     pub fn header_checksum(&self) -> u8 {
@@ -962,11 +1278,8 @@ impl Cartridge {
     }
 
     /// Get a Rom from this Cartridge.
-    pub fn rom(&mut self) -> Result<Rom> {
-        let rom_size = self.rom_size()?;
-        let mut rom = Rom::new(rom_size);
-        rom.load(&mut self.rom_file)?;
-        Ok(rom)
+    pub fn rom(&self) -> Result<Rom> {
+        Ok(Rom::from_bytes(self.rom_size()?, self.data.clone()))
     }
 }
 
@@ -975,6 +1288,7 @@ mod test {
     use super::*;
 
     #[test]
+    #[cfg(feature = "fs")]
     fn parse_cartridge_header() {
         let sample_rom_path = Path::new(env!("CARGO_MANIFEST_DIR"))
             .join("..")
@@ -996,4 +1310,193 @@ mod test {
         assert_eq!(cartridge.licensee_code().unwrap(), "Nintendo R&D 1");
         assert!(cartridge.verify_header_checksum());
     }
+
+    #[test]
+    fn cartridge_from_bytes() {
+        // MBC1 + RAM + battery, 32K ROM, 8K RAM
+        let mut data = vec![0u8; 32 * 1024];
+        data[0x147] = 0x03;
+        data[0x148] = 0x00;
+        data[0x149] = 0x02;
+
+        let mut cartridge = Cartridge::from_bytes(data, false).unwrap();
+        assert!(cartridge.rom_path.is_none());
+
+        let storage = crate::storage::MemoryStorage::with_data(vec![0x42; 8 * 1024]);
+        cartridge.set_ram_storage(Box::new(storage.clone()));
+
+        let mut controller = Controller::from_cartridge(cartridge).unwrap();
+
+        // Saved RAM is loaded from the storage, and writes go through to it
+        controller.write(0x0000u16, 0x0Au8);
+        assert_eq!(controller.read(0xA000u16), 0x42);
+        controller.write(0xA001u16, 0x99u8);
+        assert_eq!(storage.data().unwrap()[..3], [0x42, 0x99, 0x42]);
+
+        // Battery-backed RAM survives a reset
+        controller.reset();
+        assert_eq!(controller.battery_ram().unwrap().data_mut()[..3], [0x42, 0x99, 0x42]);
+
+        // ROMs too small to hold a header are rejected
+        assert!(Cartridge::from_bytes(vec![0u8; 0x100], false).is_err());
+    }
+
+    /// Storage that fails every write
+    struct BrokenStorage;
+
+    impl Storage for BrokenStorage {
+        fn load(&mut self) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn save(&mut self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, _offset: usize, _data: &[u8]) -> Result<()> {
+            Err(Error::IoError("Disk full".to_string()))
+        }
+    }
+
+    /// A 32K ROM with the given cartridge type and RAM size
+    fn test_cartridge(cartridge_type: u8, ram_size: u8) -> Cartridge {
+        let mut data = vec![0u8; 32 * 1024];
+        data[0x147] = cartridge_type;
+        data[0x148] = 0x00;
+        data[0x149] = ram_size;
+        data[0x4000] = 0x42;
+        Cartridge::from_bytes(data, false).unwrap()
+    }
+
+    #[test]
+    fn out_of_range_accesses() {
+        // MBC3 without RAM or RTC
+        let mut controller = Controller::from_cartridge(test_cartridge(0x11, 0x00)).unwrap();
+        controller.write(0x0000u16, 0x0Au8);
+
+        // ROM bank selects past the end of the ROM wrap around
+        controller.write(0x2000u16, 0x7Fu8);
+        assert_eq!(controller.rom.active_bank_1, 1);
+        assert_eq!(controller.read(0x4000u16), 0x42);
+
+        // Missing RAM and RTC read as open bus, and writes are ignored
+        for select in 0x00..=0x0Fu8 {
+            controller.write(0x4000u16, select);
+            controller.write(0xA000u16, 0x12u8);
+            assert_eq!(controller.read(0xA000u16), 0xFF);
+        }
+        controller.write(0x6000u16, 0x01u8);
+
+        // 2K RAM is mirrored across the bank
+        let mut controller = Controller::from_cartridge(test_cartridge(0x03, 0x01)).unwrap();
+        controller.write(0x0000u16, 0x0Au8);
+        controller.write(0xA001u16, 0x34u8);
+        assert_eq!(controller.read(0xA801u16), 0x34);
+    }
+
+    #[test]
+    fn storage_errors() {
+        let mut cartridge = test_cartridge(0x03, 0x02);
+        cartridge.set_ram_storage(Box::new(BrokenStorage));
+        let mut controller = Controller::from_cartridge(cartridge).unwrap();
+
+        // The write still goes through to RAM, and the error is kept around
+        controller.write(0x0000u16, 0x0Au8);
+        controller.write(0xA000u16, 0x99u8);
+        controller.write(0xA001u16, 0x99u8);
+        assert_eq!(controller.read(0xA000u16), 0x99);
+
+        assert!(matches!(controller.take_error(), Some(Error::IoError(_))));
+        assert!(controller.take_error().is_none());
+    }
+
+    /// Storage that fails every save
+    struct ReadOnlyStorage;
+
+    impl Storage for ReadOnlyStorage {
+        fn load(&mut self) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn save(&mut self, _data: &[u8]) -> Result<()> {
+            Err(Error::IoError("Read-only".to_string()))
+        }
+
+        fn write(&mut self, _offset: usize, _data: &[u8]) -> Result<()> {
+            Err(Error::IoError("Read-only".to_string()))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "save")]
+    fn restore_storage() {
+        let storage = crate::storage::MemoryStorage::new();
+        let mut cartridge = test_cartridge(0x03, 0x02);
+        cartridge.set_ram_storage(Box::new(storage.clone()));
+        let mut prev = Controller::from_cartridge(cartridge).unwrap();
+
+        // Without persisting (e.g., when rewinding), the storage is taken over as-is
+        let mut restored = Controller::from_cartridge(test_cartridge(0x03, 0x02)).unwrap();
+        restored.ram.as_mut().unwrap().data[0] = 0x12;
+        let ptr = prev.ram.as_ref().unwrap().data.as_ptr();
+        restored.restore_from(&mut prev, false).unwrap();
+        assert!(restored.ram.as_ref().unwrap().storage.is_some());
+        assert_eq!(storage.data().unwrap()[0], 0);
+
+        // The RAM stays at the same address, with the restored contents
+        assert_eq!(restored.ram.as_ref().unwrap().data.as_ptr(), ptr);
+        assert_eq!(restored.ram.as_ref().unwrap().data[0], 0x12);
+
+        let mut next = Controller::from_cartridge(test_cartridge(0x03, 0x02)).unwrap();
+        next.ram.as_mut().unwrap().data[0] = 0x34;
+        next.restore_from(&mut restored, true).unwrap();
+        assert_eq!(storage.data().unwrap()[0], 0x34);
+
+        // If the storage cannot be written, the running controller keeps everything
+        let mut prev = Controller::from_cartridge(test_cartridge(0x03, 0x02)).unwrap();
+        prev.ram.as_mut().unwrap().storage = Some(Box::new(ReadOnlyStorage));
+        let mut restored = Controller::from_cartridge(test_cartridge(0x03, 0x02)).unwrap();
+        assert!(restored.restore_from(&mut prev, true).is_err());
+        assert!(prev.ram.as_ref().unwrap().storage.is_some());
+        assert!(restored.ram.as_ref().unwrap().storage.is_none());
+        assert_eq!(prev.rom.data.len(), 32 * 1024);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn patch_cartridge() {
+        let sample_rom_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join("tetris_world.gb");
+        let original = std::fs::read(&sample_rom_path).unwrap();
+
+        // Rename the game, and write a byte past the end of the 32K ROM
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x04]);
+        ips.extend_from_slice(b"TEST");
+        ips.extend_from_slice(&[0x00, 0x80, 0x00, 0x00, 0x01, 0x42]);
+        ips.extend_from_slice(b"EOF");
+
+        let patch_path = std::env::temp_dir().join(format!("gbc-test-{}.ips", std::process::id()));
+        std::fs::write(&patch_path, &ips).unwrap();
+
+        let mut cartridge = Cartridge::from_file(&sample_rom_path, false).unwrap();
+        assert_eq!(cartridge.rom_size().unwrap(), RomSize::_32K);
+
+        cartridge.patch(&patch_path).unwrap();
+        std::fs::remove_file(&patch_path).unwrap();
+
+        assert!(cartridge.is_patched());
+        assert!(cartridge.title().unwrap().starts_with("TEST"));
+        assert_eq!(cartridge.rom_size().unwrap(), RomSize::_64K);
+
+        let mut rom = cartridge.rom().unwrap();
+        rom.update_bank(2);
+        assert_eq!(rom.read(0x4000u16), 0x42);
+        assert_eq!(rom.read(0x4001u16), 0xFF);
+
+        // The ROM file is never modified
+        assert_eq!(std::fs::read(&sample_rom_path).unwrap(), original);
+    }
 }
@@ -0,0 +1,362 @@
+//! Code/data logger
+//!
+//! Records how every byte of the cartridge ROM was used: executed as code, read as
+//! data, or copied to VRAM or OAM by DMA/HDMA (i.e., graphics). This tells ROM hackers
+//! which parts of a ROM are code, data or unused.
+//!
+//! Logs are saved in BizHawk's `.cdl` format for the Game Boy (`BIZHAWK-CDL-2`, subtype
+//! `GB`): named `ROM`, `HRAM`, `WRAM` and `CartRAM` blocks of one flag byte per byte of
+//! memory, in memory order. Only the ROM is logged; the other blocks are kept as merged
+//! in, so that logs made with BizHawk survive a session. Flags are ORed together, which
+//! means that logs from several sessions can be merged.
+//!
+//! See: https://github.com/TASEmulators/BizHawk/blob/master/src/BizHawk.Emulation.Common/CodeDataLog.cs
+use std::cell::Cell;
+use std::convert::TryInto;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use crate::cartridge::Rom;
+use crate::error::{Error, Result};
+
+/// Byte was executed as the first byte of an instruction
+pub const EXEC_FIRST: u8 = 1 << 0;
+
+/// Byte was executed as an operand
+pub const EXEC_OPERAND: u8 = 1 << 1;
+
+/// Byte was read by an instruction, or by DMA
+pub const DATA: u8 = 1 << 2;
+
+/// Byte was copied by OAM DMA or HDMA. This is not one of BizHawk's flags, which
+/// only see these bytes as `DATA`.
+pub const GRAPHICS: u8 = 1 << 3;
+
+/// Byte was executed, as an opcode or an operand
+pub const CODE: u8 = EXEC_FIRST | EXEC_OPERAND;
+
+const HEADER: &str = "BIZHAWK-CDL-2";
+const SUBTYPE: &str = "GB";
+
+/// Sizes of the blocks that are not logged, as laid out by BizHawk
+const HRAM_SIZE: usize = 0x80;
+const WRAM_SIZE: usize = 0x8000;
+
+pub struct CodeDataLog {
+    flags: Vec<Cell<u8>>,
+
+    /// Blocks other than the ROM, by name
+    blocks: Vec<(&'static str, Vec<u8>)>,
+
+    /// Flag applied to ROM reads, if they are currently logged
+    access: Cell<u8>,
+}
+
+impl CodeDataLog {
+    /// Create an empty log for a ROM and cartridge RAM of the given sizes
+    pub fn new(rom_size: usize, cartridge_ram_size: usize) -> Self {
+        let mut blocks = vec![("HRAM", vec![0; HRAM_SIZE]), ("WRAM", vec![0; WRAM_SIZE])];
+        if cartridge_ram_size > 0 {
+            blocks.push(("CartRAM", vec![0; cartridge_ram_size]));
+        }
+
+        Self {
+            flags: vec![Cell::new(0); rom_size],
+            blocks,
+            access: Cell::new(0),
+        }
+    }
+
+    /// Merge a previously saved log into this one. The log must have been made on the
+    /// same ROM, i.e., all of its blocks must match the ones of this log.
+    pub fn merge(&mut self, data: &[u8]) -> Result<()> {
+        let mut reader = Reader { data };
+
+        if reader.string()? != HEADER {
+            return Err(Error::InvalidValue("Not a BizHawk CDL file".to_string()));
+        }
+
+        let subtype = reader.string()?;
+        if subtype.trim_end() != SUBTYPE {
+            return Err(Error::InvalidValue(format!("Unsupported CDL subtype: {}", subtype.trim_end())));
+        }
+
+        // Check every block before merging anything
+        let mut merged = Vec::new();
+        for _ in 0..reader.i32()? {
+            let name = reader.string()?;
+            let len = reader.i32()?;
+            let data = reader.bytes(len.try_into().map_err(|_| reader.invalid())?)?;
+
+            let size = match name.as_str() {
+                "ROM" => self.flags.len(),
+                _ => match self.blocks.iter().find(|(block, _)| *block == name) {
+                    Some((_, flags)) => flags.len(),
+                    None => return Err(Error::InvalidValue(format!("Unexpected CDL block: {}", name))),
+                },
+            };
+
+            if data.len() != size {
+                return Err(Error::InvalidValue(format!(
+                    "CDL {} size ({} bytes) does not match this cartridge ({} bytes)",
+                    name,
+                    data.len(),
+                    size,
+                )));
+            }
+
+            merged.push((name, data));
+        }
+
+        for (name, data) in merged {
+            if name == "ROM" {
+                for (flags, value) in self.flags.iter().zip(data) {
+                    flags.set(flags.get() | value);
+                }
+            } else if let Some((_, flags)) = self.blocks.iter_mut().find(|(block, _)| *block == name) {
+                for (flags, value) in flags.iter_mut().zip(data) {
+                    *flags |= value;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge the log in `path` into this one, if the file exists
+    #[cfg(feature = "fs")]
+    pub fn merge_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        match std::fs::read(path) {
+            Ok(data) => self.merge(&data),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, HEADER);
+        write_string(&mut data, &format!("{:15}", SUBTYPE));
+        data.extend_from_slice(&(self.blocks.len() as i32 + 1).to_le_bytes());
+
+        write_string(&mut data, "ROM");
+        data.extend_from_slice(&(self.flags.len() as i32).to_le_bytes());
+        data.extend(self.flags.iter().map(Cell::get));
+
+        for (name, flags) in &self.blocks {
+            write_string(&mut data, name);
+            data.extend_from_slice(&(flags.len() as i32).to_le_bytes());
+            data.extend_from_slice(flags);
+        }
+
+        data
+    }
+
+    /// Write the log to `path`. Existing logs should be merged in first.
+    #[cfg(feature = "fs")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Flags of the byte at the given ROM offset
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    /// Coverage of each ROM bank
+    pub fn coverage(&self) -> Vec<BankCoverage> {
+        self.flags
+            .chunks(Rom::BANK_SIZE)
+            .enumerate()
+            .map(|(bank, flags)| {
+                let count = |flag| flags.iter().filter(|f| f.get() & flag != 0).count();
+
+                BankCoverage {
+                    bank: bank as u16,
+                    size: flags.len(),
+                    code: count(CODE),
+                    data: count(DATA),
+                    graphics: count(GRAPHICS),
+                    unused: flags.iter().filter(|f| f.get() == 0).count(),
+                }
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub(crate) fn log(&self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.get(offset) {
+            flags.set(flags.get() | flag);
+        }
+    }
+
+    /// Start logging ROM reads with the given flag, or stop if 0
+    #[inline]
+    pub(crate) fn set_access(&self, flag: u8) {
+        self.access.set(flag);
+    }
+
+    /// Log a ROM read, if reads are currently logged
+    #[inline]
+    pub(crate) fn log_read(&self, offset: usize) {
+        let flag = self.access.get();
+        if flag != 0 {
+            self.log(offset, flag);
+        }
+    }
+}
+
+/// Write a string the way .NET's `BinaryWriter` does: prefixed by its length, as a
+/// 7-bit encoded integer
+fn write_string(data: &mut Vec<u8>, s: &str) {
+    let mut len = s.len();
+    while len >= 0x80 {
+        data.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    data.push(len as u8);
+    data.extend_from_slice(s.as_bytes());
+}
+
+/// Reads the values written by .NET's `BinaryWriter`
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn invalid(&self) -> Error {
+        Error::InvalidValue("Truncated or invalid CDL file".to_string())
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(self.invalid());
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let mut len = 0usize;
+        for shift in (0..35).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            len |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                let bytes = self.bytes(len)?;
+                return Ok(String::from_utf8_lossy(bytes).into_owned());
+            }
+        }
+
+        Err(self.invalid())
+    }
+}
+
+/// How the bytes of a ROM bank were used. A byte can count towards several uses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BankCoverage {
+    pub bank: u16,
+    pub size: usize,
+    pub code: usize,
+    pub data: usize,
+    pub graphics: usize,
+    pub unused: usize,
+}
+
+impl BankCoverage {
+    /// Percentage of the bank that was used
+    pub fn used(&self) -> f64 {
+        (self.size - self.unused) as f64 * 100.0 / self.size as f64
+    }
+}
+
+impl std::fmt::Display for BankCoverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Bank {:03}: {:5.1}% used, code: {:5}, data: {:5}, graphics: {:5}, unused: {:5}",
+            self.bank,
+            self.used(),
+            self.code,
+            self.data,
+            self.graphics,
+            self.unused,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let mut cdl = CodeDataLog::new(Rom::BANK_SIZE * 2, 0x2000);
+        cdl.log(0x100, EXEC_FIRST);
+        cdl.log(0x4000, DATA);
+
+        // Logs from another session are ORed in
+        let mut saved = CodeDataLog::new(Rom::BANK_SIZE * 2, 0x2000);
+        saved.log(0x100, DATA);
+        saved.log(0x4001, DATA | GRAPHICS);
+        saved.blocks[1].1[0x10] = EXEC_FIRST;
+        cdl.merge(&saved.to_bytes()).unwrap();
+
+        assert_eq!(cdl.flags(0x100), EXEC_FIRST | DATA);
+        assert_eq!(cdl.flags(0x4001), DATA | GRAPHICS);
+        assert_eq!(cdl.blocks[1], ("WRAM", saved.blocks[1].1.clone()));
+
+        // Logs of a different cartridge are rejected
+        let other = CodeDataLog::new(Rom::BANK_SIZE, 0x2000);
+        assert!(cdl.merge(&other.to_bytes()).is_err());
+        let other = CodeDataLog::new(Rom::BANK_SIZE * 2, 0);
+        assert!(cdl.merge(&other.to_bytes()).is_ok());
+        let other = CodeDataLog::new(Rom::BANK_SIZE * 2, 0x8000);
+        assert!(cdl.merge(&other.to_bytes()).is_err());
+        assert!(cdl.merge(&cdl.to_bytes()[..100]).is_err());
+        assert!(cdl.merge(b"\x0dBIZHAWK-CDL-1").is_err());
+
+        let coverage = cdl.coverage();
+        assert_eq!(coverage.len(), 2);
+        assert_eq!((coverage[0].code, coverage[0].data, coverage[0].unused), (1, 1, Rom::BANK_SIZE - 1));
+        assert_eq!((coverage[1].data, coverage[1].graphics, coverage[1].unused), (2, 1, Rom::BANK_SIZE - 2));
+    }
+
+    #[test]
+    fn bizhawk_layout() {
+        let cdl = CodeDataLog::new(4, 0x2000);
+        cdl.log(1, EXEC_FIRST);
+        cdl.log(2, EXEC_OPERAND);
+
+        let data = cdl.to_bytes();
+        assert_eq!(&data[..14], b"\x0dBIZHAWK-CDL-2");
+        assert_eq!(&data[14..30], b"\x0fGB             ");
+        assert_eq!(&data[30..34], &4i32.to_le_bytes());
+        assert_eq!(&data[34..38], b"\x03ROM");
+        assert_eq!(&data[38..42], &4i32.to_le_bytes());
+        assert_eq!(&data[42..46], &[0, EXEC_FIRST, EXEC_OPERAND, 0]);
+        assert_eq!(&data[46..51], b"\x04HRAM");
+        assert_eq!(data.len(), 46 + (5 + 4 + 0x80) + (5 + 4 + 0x8000) + (8 + 4 + 0x2000));
+    }
+
+    #[test]
+    fn reads() {
+        let cdl = CodeDataLog::new(Rom::BANK_SIZE, 0);
+
+        // Reads are only logged while armed
+        cdl.log_read(0x10);
+        cdl.set_access(DATA);
+        cdl.log_read(0x20);
+        cdl.set_access(0);
+        cdl.log_read(0x30);
+
+        assert_eq!((cdl.flags(0x10), cdl.flags(0x20), cdl.flags(0x30)), (0, DATA, 0));
+    }
+}
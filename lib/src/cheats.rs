@@ -0,0 +1,199 @@
+//! Game Genie and GameShark cheat codes
+//!
+//! Game Genie codes patch the cartridge ROM as it is read: a read from the code's
+//! address returns the code's value instead, optionally only if the original byte
+//! matches a compare value.
+//!
+//! GameShark codes write a value to RAM. They are applied at the start of every VBLANK.
+use crate::error::{Error, Result};
+
+/// Game Genie code: `ABC-DEF` or `ABC-DEF-GHI`
+///
+/// * `AB`: new value
+/// * `FCDE`: address, with `F` inverted
+/// * `GI`: compare value, XOR'd with 0xBA then rotated left by 2 (`H` is unused)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameGenie {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGenie {
+    fn parse(code: &str, digits: &[u8]) -> Result<Self> {
+        let value = digits[0] << 4 | digits[1];
+        let addr = ((digits[5] ^ 0xF) as u16) << 12
+            | (digits[2] as u16) << 8
+            | (digits[3] as u16) << 4
+            | digits[4] as u16;
+
+        if addr >= 0x8000 {
+            return Err(Error::InvalidValue(format!(
+                "Game Genie code {} does not target ROM (0x{:04X})", code, addr
+            )));
+        }
+
+        let compare = if digits.len() == 9 {
+            let compare = digits[6] << 4 | digits[8];
+            Some(compare.rotate_right(2) ^ 0xBA)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            addr,
+            value,
+            compare,
+        })
+    }
+
+    /// Apply this code to a byte read from the given ROM address.
+    #[inline]
+    pub fn apply(&self, addr: u16, value: u8) -> Option<u8> {
+        if addr != self.addr {
+            return None;
+        }
+
+        match self.compare {
+            Some(compare) if compare != value => None,
+            _ => Some(self.value),
+        }
+    }
+}
+
+/// GameShark code: `ABCDEFGH`
+///
+/// * `AB`: code type; `01` writes to the active RAM bank, `9X` writes to WRAM bank `X`
+/// * `CD`: value
+/// * `GHEF`: address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameShark {
+    /// WRAM bank to write to. If `None`, the active bank is used.
+    pub bank: Option<u8>,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl GameShark {
+    fn parse(code: &str, digits: &[u8]) -> Result<Self> {
+        let kind = digits[0] << 4 | digits[1];
+        let value = digits[2] << 4 | digits[3];
+        let addr = (digits[6] as u16) << 12
+            | (digits[7] as u16) << 8
+            | (digits[4] as u16) << 4
+            | digits[5] as u16;
+
+        let bank = match kind {
+            0x00 | 0x01 => None,
+            0x90..=0x97 if (0xD000..=0xDFFF).contains(&addr) => Some(kind & 0x7),
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Unsupported GameShark code type {:02X} in {}", kind, code
+                )))
+            }
+        };
+
+        if addr < 0x8000 || (0xE000..0xFF80).contains(&addr) || addr == 0xFFFF {
+            return Err(Error::InvalidValue(format!(
+                "GameShark code {} does not target RAM (0x{:04X})", code, addr
+            )));
+        }
+
+        Ok(Self { bank, addr, value })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatCode {
+    GameGenie(GameGenie),
+    GameShark(GameShark),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    /// Code as entered by the user
+    pub code: String,
+
+    pub kind: CheatCode,
+
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Parse a Game Genie (`ABC-DEF` or `ABC-DEF-GHI`) or GameShark (`ABCDEFGH`) code.
+    ///
+    /// The cheat starts out enabled.
+    pub fn parse(code: &str) -> Result<Self> {
+        let code = code.trim();
+        let invalid = || Error::InvalidValue(format!("Invalid cheat code: {}", code));
+
+        let digits = code
+            .chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+
+        let is_game_genie = code.contains('-') || digits.len() != 8;
+
+        let kind = match digits.len() {
+            6 | 9 if is_game_genie => CheatCode::GameGenie(GameGenie::parse(code, &digits)?),
+            8 => CheatCode::GameShark(GameShark::parse(code, &digits)?),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            code: code.to_string(),
+            kind,
+            enabled: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn game_genie() {
+        let cheat = Cheat::parse("00A-17B-C49").unwrap();
+        let code = match cheat.kind {
+            CheatCode::GameGenie(code) => code,
+            _ => panic!("Expected a Game Genie code"),
+        };
+
+        assert_eq!(code, GameGenie { addr: 0x4A17, value: 0x00, compare: Some(0xC8) });
+        assert_eq!(code.apply(0x4A17, 0xC8), Some(0x00));
+        assert_eq!(code.apply(0x4A17, 0xC9), None);
+        assert_eq!(code.apply(0x4A18, 0xC8), None);
+
+        // Without a compare value, every read is replaced
+        let cheat = Cheat::parse("3E1-23F").unwrap();
+        assert_eq!(cheat.kind, CheatCode::GameGenie(GameGenie { addr: 0x0123, value: 0x3E, compare: None }));
+
+        // Only ROM can be patched
+        assert!(Cheat::parse("3E1-237").is_err());
+    }
+
+    #[test]
+    fn game_shark() {
+        let cheat = Cheat::parse("0163A4C0").unwrap();
+        assert_eq!(cheat.kind, CheatCode::GameShark(GameShark { bank: None, addr: 0xC0A4, value: 0x63 }));
+
+        let cheat = Cheat::parse("9205D0D8").unwrap();
+        assert_eq!(cheat.kind, CheatCode::GameShark(GameShark { bank: Some(2), addr: 0xD8D0, value: 0x05 }));
+
+        // WRAM banks only apply to the switchable bank
+        assert!(Cheat::parse("9205D0C8").is_err());
+
+        // ROM is not writable
+        assert!(Cheat::parse("01050040").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Cheat::parse("").is_err());
+        assert!(Cheat::parse("XYZ-123").is_err());
+        assert!(Cheat::parse("1234567").is_err());
+    }
+}
@@ -1,12 +1,32 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-
 use crate::cartridge::Cartridge;
+use crate::cdl;
 use crate::dma::DmaController;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::instructions::{Arg, Cond, Cycles, Instruction};
 use crate::memory::{MemoryBus, MemoryRead, MemoryWrite};
 use crate::registers::{Flag, Reg16, Reg8, RegisterFile, RegisterOps};
+use crate::symbols::Symbols;
+use crate::trace::Tracer;
+#[cfg(feature = "debug")]
+use crate::debug::stack::{CallStack, Frame, FrameKind};
+
+/// Notable events that happened while the emulator was running, for the frontend
+/// and debugger to surface
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The CPU executed an illegal opcode at `addr`, and is locked up until reset
+    Locked { opcode: u8, addr: u16 },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Locked { opcode, addr } => {
+                write!(f, "CPU locked up by illegal opcode {:#04X} at {:#06X}", opcode, addr)
+            }
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -67,21 +87,57 @@ pub struct Cpu {
     pub cgb: bool,
 
     pub halted: bool,
+
+    /// STOP mode: the CPU and system clock are stopped until a joypad input is pressed
     pub stopped: bool,
     pub speed: bool,
 
+    /// Set for the step in which a speed switch happened. The CPU and system clock are
+    /// paused for 2050 M-cycles while the switch takes place.
+    speed_switching: bool,
+
     /// Global interrupt enable flag (Interrupt Master Enable)
     ime: bool,
 
-    /// Trace all instructions executed to a file
+    /// Set by EI: IME is only enabled after the following instruction
+    ime_pending: bool,
+
+    /// Set when HALT is executed with IME disabled and an interrupt pending. The CPU
+    /// does not halt, but fails to increment PC after reading the next opcode.
+    halt_bug: bool,
+
+    /// Set once an illegal opcode is executed. Only a reset gets the CPU out of this.
+    locked: bool,
+
+    /// Last event that has not been picked up by the frontend yet
+    #[cfg_attr(feature = "save", serde(skip))]
+    event: Option<Event>,
+
+    /// Trace all instructions executed
+    #[cfg_attr(feature = "save", serde(skip))]
+    tracer: Option<Tracer>,
+
+    /// Set if writing to the trace failed, which stops tracing
     #[cfg_attr(feature = "save", serde(skip))]
-    trace: Option<BufWriter<File>>,
+    trace_error: Option<Error>,
+
+    /// Labels used to show addresses in traces and in the debugger
+    #[cfg_attr(feature = "save", serde(skip))]
+    symbols: Option<Symbols>,
+
+    /// Shadow call stack, for the debugger
+    #[cfg(feature = "debug")]
+    #[cfg_attr(feature = "save", serde(skip))]
+    call_stack: CallStack,
 }
 
 impl Cpu {
     /// Base CPU frequency, in Hz
     pub const BASE_FREQ: u32 = 4_194_304;
 
+    /// Length of the pause after a speed switch: 2050 M-cycles
+    pub const SPEED_SWITCH_CYCLES: u16 = 2050 * 4;
+
     /// CPU cycle time, in ns
     pub const CYCLE_TIME: u32 = ((1.0 / Self::BASE_FREQ as f64) * 1e9) as u32;
 
@@ -98,10 +154,19 @@ impl Cpu {
             dma: DmaController::new(cgb),
             cgb,
             ime: false,
+            ime_pending: false,
+            halt_bug: false,
+            locked: false,
+            event: None,
             halted: false,
             stopped: false,
             speed: false,
-            trace: None,
+            speed_switching: false,
+            tracer: None,
+            trace_error: None,
+            symbols: None,
+            #[cfg(feature = "debug")]
+            call_stack: CallStack::default(),
         }
     }
 
@@ -122,9 +187,8 @@ impl Cpu {
         let dma = DmaController::new(cgb);
 
         // If tracing is enabled, create a trace file in the current directory
-        let trace = if trace {
-            let f = File::create("gbc.trace")?;
-            Some(BufWriter::new(f))
+        let tracer = if trace {
+            Some(Self::trace_file()?)
         } else {
             None
         };
@@ -135,13 +199,32 @@ impl Cpu {
             dma,
             cgb,
             ime: false,
+            ime_pending: false,
+            halt_bug: false,
+            locked: false,
+            event: None,
             halted: false,
             stopped: false,
             speed: false,
-            trace,
+            speed_switching: false,
+            tracer,
+            trace_error: None,
+            symbols: None,
+            #[cfg(feature = "debug")]
+            call_stack: CallStack::default(),
         })
     }
 
+    #[cfg(feature = "fs")]
+    fn trace_file() -> Result<Tracer> {
+        Tracer::create("gbc.trace", crate::trace::TraceConfig::default())
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn trace_file() -> Result<Tracer> {
+        Err(Error::InvalidValue("Tracing requires the `fs` feature".to_string()))
+    }
+
     /// Current clock cycle duration, in ns. This value is based
     /// on the current value in the speed I/O register.
     #[inline]
@@ -161,54 +244,147 @@ impl Cpu {
         self.memory.reset();
         self.dma = DmaController::new(self.cgb);
         self.ime = false;
+        self.ime_pending = false;
+        self.halt_bug = false;
+        self.locked = false;
+        self.event = None;
         self.halted = false;
         self.stopped = false;
         self.speed = false;
+        self.speed_switching = false;
+
+        #[cfg(feature = "debug")]
+        self.call_stack.clear();
+    }
+
+    /// Carry over everything that is not part of a save state from `prev`.
+    ///
+    /// This is used when loading a save state in place.
+    #[cfg(feature = "save")]
+    pub(crate) fn restore_from(&mut self, prev: &mut Cpu, persist: bool) -> Result<()> {
+        self.memory.controller().restore_from(prev.memory.controller(), persist)?;
+
+        self.tracer = prev.tracer.take();
+        self.symbols = prev.symbols.take();
+        Ok(())
     }
 
     /// Executes the next instruction and returns the number of cycles it
     /// took to complete.
     pub fn step(&mut self) -> (u16, Instruction) {
+        self.speed_switching = false;
+        self.memory.begin_step();
+
+        // A locked CPU does nothing, and does not even service interrupts
+        if self.locked {
+            return (4, Instruction::Nop);
+        }
+
+        if self.stopped {
+            // Leave STOP mode once any selected joypad line goes low
+            if self.memory.read(0xFF00) & 0x0F == 0x0F {
+                return (4, Instruction::Nop);
+            }
+
+            self.stopped = false;
+        }
+
         // Check for pending interrupts before fetching the next instruction.
-        // If an interrupt is serviced, PC will jump to the ISR address.
+        // If an interrupt is dispatched, PC jumps to the ISR address and the
+        // dispatch takes up this entire step.
+        self.arm_watchpoints(true);
         let int_cycles = self.service_interrupts();
+        self.arm_watchpoints(false);
+
+        if int_cycles > 0 {
+            let cycles = int_cycles as u16;
+            return (cycles + self.dma(cycles), Instruction::Nop);
+        }
 
         // If the CPU is halted, bail out
         if self.halted {
             return (4, Instruction::Nop);
         }
 
+        // An EI in the previous step takes effect now, so that interrupts are
+        // dispatched after this instruction at the earliest
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+
         // Fetch and decode the next instruction at PC
-        let (inst, size, cycles) = self.fetch(None);
+        let halt_bug = std::mem::take(&mut self.halt_bug);
+        let (inst, size, cycles) = if halt_bug {
+            // The opcode byte is read twice
+            let pc = self.registers.PC;
+            let data = [
+                self.memory.read(pc),
+                self.memory.read(pc),
+                self.memory.read(pc.wrapping_add(1)),
+            ];
+            Instruction::decode(data)
+        } else {
+            self.fetch(None)
+        };
+
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.trace(self, &inst) {
+                Ok(()) if tracer.done() => (),
+                Ok(()) => self.tracer = Some(tracer),
+                Err(err) => self.trace_error = Some(err.into()),
+            }
+        }
+
+        self.memory.log_code(self.registers.PC, size);
+
+        // Watchpoints only see the accesses made by the instruction itself, not its fetch
+        self.arm_watchpoints(true);
+        self.memory.log_reads(cdl::DATA);
 
-        if let Some(_) = &mut self.trace {
-            self.trace(&inst);
+        if halt_bug {
+            // Execute as if the instruction started one byte earlier, which gives the
+            // right next PC, relative jump targets and return addresses
+            self.registers.PC = self.registers.PC.wrapping_sub(1);
+        }
+
+        // The opcode and its operands take an M-cycle each to fetch
+        for _ in 0..size {
+            self.idle_cycle();
         }
 
         // Execute the instruction on this CPU
         let (jump, taken) = self.execute(inst);
+        self.arm_watchpoints(false);
+        self.memory.log_reads(0);
+
         let mut cycles = if !jump || jump && !taken {
             // For regular instructions and jumps that are *not* taken,
             // update the PC based on the size of this instruction
-            self.registers.PC += size as u16;
+            self.registers.PC = self.registers.PC.wrapping_add(size as u16);
             cycles.not_taken() as u16
         } else {
             // For jumps that are taken, the PC is updated within `execute()`
             cycles.taken() as u16
         };
 
-        if self.stopped {
-            // If we've entered STOP mode for a speed switch, block the CPU for a bit.
+        if self.speed_switching {
+            // Block the CPU while the speed switch takes place.
             // Also, do not run through DMA.
-            cycles += 8200;
+            cycles += Self::SPEED_SWITCH_CYCLES;
         } else {
-            cycles += int_cycles as u16;
             cycles += self.dma(cycles);
         }
 
         (cycles, inst)
     }
 
+    /// Returns `true` if the system clock did not run during the last step, i.e., the
+    /// CPU is in STOP mode or switching speed. The PPU and timer are paused.
+    pub fn clock_stopped(&self) -> bool {
+        self.stopped || self.speed_switching
+    }
+
     /// Switch CPU speed
     fn speed_switch(&mut self) {
         if !self.speed {
@@ -221,31 +397,118 @@ impl Cpu {
             self.speed = false;
         }
 
-        self.stopped = true;
+        self.speed_switching = true;
     }
 
-    #[inline]
-    fn trace(&mut self, inst: &Instruction) {
-        // Figure out the currently active ROM bank based on the memory region of PC
-        let pc = self.registers.PC;
-        let (memory_type, bank) = self.memory.memory_info(pc);
-        let f = &mut self.trace.as_mut().unwrap();
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Replace the tracer. The previous one, if any, is flushed.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        if let Some(mut prev) = std::mem::replace(&mut self.tracer, tracer) {
+            if let Err(err) = prev.flush() {
+                self.trace_error = Some(err.into());
+            }
+        }
+    }
+
+    /// Notify the tracer that a frame was completed
+    pub(crate) fn trace_frame(&mut self) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(err) = tracer.frame() {
+                self.tracer = None;
+                self.trace_error = Some(err.into());
+            } else if tracer.done() {
+                self.tracer = None;
+            }
+        }
+    }
 
-        // First, write the register state prior to executing this instruction
-        write!(f, "{}\n\n", self.registers).unwrap();
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
 
-        // Then write the instruction
-        write!(f, "{:03}:{}:{:#06X} - {}\n\n", bank, memory_type, pc, inst).unwrap();
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    /// Format `addr` as `label+offset`, based on the bank currently mapped there
+    pub fn symbol(&self, addr: u16) -> Option<String> {
+        let (_, bank) = self.memory.memory_info(addr);
+        self.symbols.as_ref()?.format(bank, addr)
+    }
+
+    /// Returns `true` if the CPU was locked up by an illegal opcode
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns the event that happened since the last call, if any
+    pub(crate) fn take_event(&mut self) -> Option<Event> {
+        self.event.take()
+    }
 
-        f.flush().unwrap();
+    /// Returns the first I/O error since the last call, if any
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        self.trace_error.take().or_else(|| self.memory.controller().take_error())
     }
 
     /// Execute a single step of DMA (if active).
     fn dma(&mut self, cycles: u16) -> u16 {
+        #[cfg(feature = "debug")]
+        {
+            self.memory.watchpoints().set_dma(true);
+            self.arm_watchpoints(true);
+        }
+
         let memory = &mut self.memory;
-        self.dma.step(cycles, memory)
+        memory.log_reads(cdl::DATA | cdl::GRAPHICS);
+        let cycles = self.dma.step(cycles, memory);
+        memory.log_reads(0);
+
+        #[cfg(feature = "debug")]
+        {
+            self.arm_watchpoints(false);
+            self.memory.watchpoints().set_dma(false);
+        }
+
+        cycles
+    }
+
+    /// Shadow call stack, maintained on CALL, RST, interrupt dispatch, RET and RETI
+    #[cfg(feature = "debug")]
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// Record a new frame on the shadow call stack, right after a jump to PC
+    #[cfg(feature = "debug")]
+    fn push_frame(&mut self, kind: FrameKind, call_site: u16, return_addr: u16) {
+        let target = self.registers.PC;
+
+        self.call_stack.push(Frame {
+            kind,
+            call_site,
+            call_bank: self.memory.memory_info(call_site).1,
+            target,
+            target_bank: self.memory.memory_info(target).1,
+            return_addr,
+            sp: self.registers.SP,
+        });
     }
 
+    /// Start or stop reporting memory accesses to the debugger's watchpoints
+    #[cfg(feature = "debug")]
+    #[inline]
+    fn arm_watchpoints(&self, armed: bool) {
+        self.memory.watchpoints().arm(armed, self.registers.PC);
+    }
+
+    #[cfg(not(feature = "debug"))]
+    #[inline(always)]
+    fn arm_watchpoints(&self, _armed: bool) {}
+
     /// Fetch the next instruction and return it
     pub fn fetch(&self, addr: Option<u16>) -> (Instruction, u8, Cycles) {
         let addr = addr.unwrap_or(self.registers.PC);
@@ -256,8 +519,8 @@ impl Cpu {
         // TODO: Evaluate the boundary cases
         let data: [u8; 3] = [
             self.memory.read(addr),
-            self.memory.read(addr+1),
-            self.memory.read(addr+2),
+            self.memory.read(addr.wrapping_add(1)),
+            self.memory.read(addr.wrapping_add(2)),
         ];
 
         // Decode the instruction
@@ -278,58 +541,74 @@ impl Cpu {
         result
     }
 
+    /// Interrupts that are both enabled and requested
+    #[inline]
+    fn pending_interrupts(&self) -> u8 {
+        let int_enable = self.memory.read(0xFFFF);
+        let int_flags = self.memory.read(0xFF0F);
+
+        // Only the lower 5 bits map to interrupts: the upper bits of IF always read 1
+        int_enable & int_flags & 0x1F
+    }
+
     /// Figure out which interrupts are pending and service the one with the
     /// highest priority.
     ///
-    /// Servicing an interrupt takes 20 clock cycles according to Pandocs.
+    /// Servicing an interrupt takes 20 clock cycles according to Pandocs, plus 4
+    /// cycles if the CPU has to wake up from HALT first. Returns 0 if no interrupt
+    /// was dispatched.
     ///
     /// See: pg. 27 of GB Programming Manual
     fn service_interrupts(&mut self) -> u8 {
-        let int_enable = self.memory.read(0xFFFF);
-        let int_flags = self.memory.read(0xFF0F);
-
-        // If no interrupts are pending, bail out now
-        if int_enable & int_flags == 0 {
+        if self.pending_interrupts() == 0 {
             return 0;
         }
 
         // If the CPU is currently halted and there is a pending interrupt,
         // leave HALT state, *even if IME is disabled*.
-        if self.halted {
-            self.halted = (int_enable & int_flags) == 0;
-        }
+        let wake_cycles = if self.halted { 4 } else { 0 };
+        self.halted = false;
 
         // If the IME is disabled, do not process any interrupts
         if !self.ime {
             return 0;
         }
 
-        // Iterate over each interrupt in priority order and service
-        // the first one
-        for int in 0..5 {
-            let enabled = (int_enable & 1 << int) != 0;
-            let pending = (int_flags & 1 << int) != 0;
+        self.ime = false;
 
-            if enabled && pending {
-                // Disable interrupts
-                self.ime = false;
+        // The pushes come after two idle M-cycles, plus one to wake up
+        for _ in 0..2 + wake_cycles / 4 {
+            self.idle_cycle();
+        }
 
-                // Clear the pending flag
-                self.memory.write(0xFF0F, int_flags & !(1 << int));
+        // Push current PC to the stack. The upper byte is pushed first, and the
+        // interrupt to service is only picked after that: if the push overwrites IE
+        // (SP = 0x0000), the dispatch can be cancelled, in which case PC is set to 0.
+        let pc = self.registers.PC;
+        self.registers.SP = self.registers.SP.wrapping_sub(1);
+        self.write_cycle(self.registers.SP, (pc >> 8) as u8);
 
-                // Push current PC to the stack
-                self.push(self.registers.PC);
+        let pending = self.pending_interrupts();
 
-                // Compute the ISR address to jump to
-                let isr = (int << 3) + 0x40;
+        self.registers.SP = self.registers.SP.wrapping_sub(1);
+        self.write_cycle(self.registers.SP, pc as u8);
 
-                self.registers.PC = isr;
+        self.registers.PC = if pending == 0 {
+            0x0000
+        } else {
+            // Service the highest priority interrupt, and clear its pending flag
+            let int = pending.trailing_zeros() as u16;
+            let int_flags = self.memory.read(0xFF0F);
+            self.memory.write(0xFF0F, int_flags & !(1 << int));
 
-                break;
-            }
-        }
+            // Compute the ISR address to jump to
+            (int << 3) + 0x40
+        };
 
-        20
+        #[cfg(feature = "debug")]
+        self.push_frame(FrameKind::Interrupt(self.registers.PC), pc, pc);
+
+        20 + wake_cycles
     }
 
     /// Trigger a particular interrupt
@@ -355,19 +634,46 @@ impl Cpu {
         match instruction {
             Nop => (),
             Halt => {
-                self.halted = true;
+                if !self.ime && self.pending_interrupts() != 0 {
+                    // HALT bug: the CPU does not halt, and the next byte is read twice
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             }
             Stop => {
-                if self.cgb && self.memory.io().prep_speed_switch & 0x1 != 0 {
-                    // Switch speed
-                    self.speed_switch();
+                // See: https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
+                let button_held = self.memory.read(0xFF00) & 0x0F != 0x0F;
+                let interrupt_pending = self.pending_interrupts() != 0;
+                let speed_switch = self.cgb && self.memory.io().prep_speed_switch & 0x1 != 0;
+
+                if interrupt_pending && !(speed_switch && self.ime && !button_held) {
+                    // STOP is a 1-byte opcode here, so the next byte is not skipped
+                    self.registers.PC = self.registers.PC.wrapping_sub(1);
+                }
+
+                if button_held {
+                    // DIV is not reset, and the CPU halts if no interrupt is pending
+                    self.halted = !interrupt_pending;
+                } else {
+                    // Writing to DIV resets it
+                    self.memory.write(0xFF04, 0u8);
+
+                    if speed_switch {
+                        self.speed_switch();
+                    } else {
+                        self.stopped = true;
+                        self.memory.ppu_mut().blank();
+                    }
                 }
             }
             Di => {
                 self.ime = false;
+                self.ime_pending = false;
             }
             Ei => {
-                self.ime = true;
+                // Takes effect after the next instruction
+                self.ime_pending = true;
             }
 
             // Load
@@ -383,25 +689,27 @@ impl Cpu {
                 }
                 (Arg::Reg8(dst), Arg::Mem(src)) => {
                     let addr = self.registers.read(src);
-                    let value = self.memory.read(addr);
+                    let value = self.read_cycle(addr);
                     self.registers.write(dst, value);
                 }
                 (Arg::Mem(dst), Arg::Reg8(src)) => {
                     let addr = self.registers.read(dst);
-                    self.memory.write(addr, self.registers.read(src));
+                    self.write_cycle(addr, self.registers.read(src));
                 }
                 (Arg::Mem(dst), Arg::Imm8(src)) => {
                     let addr = self.registers.read(dst);
-                    self.memory.write(addr, src);
+                    self.write_cycle(addr, src);
                 }
                 (Arg::MemImm(dst), Arg::Reg8(src)) => {
-                    self.memory.write(dst, self.registers.read(src));
+                    self.write_cycle(dst, self.registers.read(src));
                 }
                 (Arg::MemImm(dst), Arg::Reg16(src)) => {
-                    self.memory.write(dst, self.registers.read(src));
+                    let value = self.registers.read(src);
+                    self.write_cycle(dst, value as u8);
+                    self.write_cycle(dst.wrapping_add(1), (value >> 8) as u8);
                 }
                 (Arg::Reg8(dst), Arg::MemImm(src)) => {
-                    let value = self.memory.read(src);
+                    let value = self.read_cycle(src);
                     self.registers.write(dst, value);
                 }
                 (Arg::Reg16(Reg16::SP), Arg::Reg16(Reg16::HL)) => {
@@ -412,41 +720,42 @@ impl Cpu {
             },
             LdMemCA => {
                 let addr = 0xFF00 + self.registers.read(Reg8::C) as u16;
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
             }
             LdAMemC => {
                 let addr = 0xFF00 + self.registers.read(Reg8::C) as u16;
-                self.registers.write(Reg8::A, self.memory.read(addr));
+                let value = self.read_cycle(addr);
+                self.registers.write(Reg8::A, value);
             }
             LdiAMemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let value = self.memory.read(addr);
+                let value = self.read_cycle(addr);
                 self.registers.write(Reg8::A, value);
                 self.registers.write(Reg16::HL, addr.wrapping_add(1));
             }
             LdiMemHlA => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
                 self.registers.write(Reg16::HL, addr.wrapping_add(1));
             }
             LddAMemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let value = self.memory.read(addr);
+                let value = self.read_cycle(addr);
                 self.registers.write(Reg8::A, value);
                 self.registers.write(Reg16::HL, addr.wrapping_sub(1));
             }
             LddMemHlA => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
                 self.registers.write(Reg16::HL, addr.wrapping_sub(1));
             }
             LdhA { offset } => {
-                let value = self.memory.read(0xFF00 + offset as u16);
+                let value = self.read_cycle(0xFF00 + offset as u16);
                 self.registers.write(Reg8::A, value);
             }
             Ldh { offset } => {
                 let a = self.registers.read(Reg8::A);
-                self.memory.write(0xFF00 + offset as u16, a);
+                self.write_cycle(0xFF00 + offset as u16, a);
             }
             LdHlSpImm8i { offset } | AddSpImm8i { offset } => {
                 let offset = offset as u16;
@@ -502,24 +811,46 @@ impl Cpu {
                 jump = true;
 
                 if ok {
+                    // Checking the condition takes an M-cycle before the pop
+                    if cond != Cond::None {
+                        self.idle_cycle();
+                    }
+
                     // Pop address from the stack iff the condition is met
                     self.registers.PC = self.pop();
                     taken = true;
+
+                    #[cfg(feature = "debug")]
+                    self.call_stack.pop_to(self.registers.SP);
                 }
             }
+            Illegal(opcode) => {
+                // Leave PC pointing at the opcode
+                self.locked = true;
+                self.event = Some(Event::Locked { opcode, addr: self.registers.PC });
+                jump = true;
+                taken = true;
+            }
             RetI => {
                 let addr = self.pop();
                 self.registers.PC = addr;
                 self.ime = true;
                 jump = true;
                 taken = true;
+
+                #[cfg(feature = "debug")]
+                self.call_stack.pop_to(self.registers.SP);
             }
             Rst { offset } => {
                 // Push next PC onto stack, then jump to offset
-                self.push(self.registers.PC + 1);
+                let pc = self.registers.PC;
+                self.push(pc + 1);
                 self.registers.PC = 0x0000 + offset as u16;
                 jump = true;
                 taken = true;
+
+                #[cfg(feature = "debug")]
+                self.push_frame(FrameKind::Rst, pc, pc + 1);
             }
             Jp { addr, cond } | Call { addr, cond } => {
                 let ok = match cond {
@@ -534,6 +865,9 @@ impl Cpu {
 
                 if ok {
                     // If this is a CALL, push the *next* PC to the stack
+                    #[cfg(feature = "debug")]
+                    let call_site = self.registers.PC;
+
                     if let Call { .. } = instruction {
                         // CALL is always 3 bytes long
                         let next = self.registers.PC + 3;
@@ -542,6 +876,11 @@ impl Cpu {
 
                     self.registers.PC = addr;
                     taken = true;
+
+                    #[cfg(feature = "debug")]
+                    if let Call { .. } = instruction {
+                        self.push_frame(FrameKind::Call, call_site, call_site + 3);
+                    }
                 }
             }
             JpHl => {
@@ -595,9 +934,9 @@ impl Cpu {
                     }
                     Arg::MemHl => {
                         let addr = self.registers.read(Reg16::HL);
-                        let value = self.memory.read(addr);
+                        let value = self.read_cycle(addr);
                         let value = (value << 4) | (value >> 4);
-                        self.memory.write(addr, value);
+                        self.write_cycle(addr, value);
                         value
                     }
                     _ => unreachable!("Unexpected dst: {}", dst),
@@ -615,7 +954,7 @@ impl Cpu {
                     Arg::Reg8(dst) => self.registers.read(dst),
                     Arg::MemHl => {
                         let addr = self.registers.read(Reg16::HL);
-                        self.memory.read(addr)
+                        self.read_cycle(addr)
                     }
                     _ => unreachable!("Unexpected dst: {}", dst),
                 };
@@ -667,7 +1006,7 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -708,7 +1047,7 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, value);
+                self.write_cycle(addr, value);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -734,7 +1073,7 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -764,7 +1103,7 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, value);
+                self.write_cycle(addr, value);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -776,11 +1115,29 @@ impl Cpu {
         self.registers.set(Flag::Carry, carry);
     }
 
+    /// Read memory in the next M-cycle of the current step. The timer runs for that
+    /// M-cycle first, so that reads of its registers see it as of the access.
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.memory.tick();
+        self.memory.read(addr)
+    }
+
+    /// Write memory in the next M-cycle of the current step
+    fn write_cycle(&mut self, addr: u16, value: u8) {
+        self.memory.tick();
+        self.memory.write(addr, value);
+    }
+
+    /// Next M-cycle of the current step, without a memory access
+    fn idle_cycle(&mut self) {
+        self.memory.tick();
+    }
+
     /// Helper that pops 2 bytes off the stack
     fn pop(&mut self) -> u16 {
         // Read upper and lower bytes from stack.
-        let lower = self.memory.read(self.registers.SP);
-        let upper = self.memory.read(self.registers.SP + 1);
+        let lower = self.read_cycle(self.registers.SP);
+        let upper = self.read_cycle(self.registers.SP + 1);
         let value = (upper as u16) << 8 | lower as u16;
 
         // Increment SP
@@ -794,11 +1151,14 @@ impl Cpu {
         let lower = value as u8;
         let upper = (value >> 8) as u8;
 
+        // SP is decremented in an M-cycle of its own, before the writes
+        self.idle_cycle();
+
         // Write upper and lower bytes seperately to the stack.
         // We cannot use the `MemoryWrite` trait because it assumes
         // that memory addresses increase instead of decrease.
-        self.memory.write(self.registers.SP-1, upper);
-        self.memory.write(self.registers.SP-2, lower);
+        self.write_cycle(self.registers.SP-1, upper);
+        self.write_cycle(self.registers.SP-2, lower);
 
         // Decrement SP
         self.registers.SP -= 2;
@@ -812,7 +1172,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -838,7 +1198,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -890,7 +1250,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -919,7 +1279,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -953,7 +1313,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -1000,10 +1360,10 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let curr = self.memory.read(addr);
+                let curr = self.read_cycle(addr);
                 let result = curr.wrapping_add(1);
                 half_carry = curr.half_carry(1);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
                 result as u16
             }
             _ => unreachable!("Unexpected dst: {}", dst),
@@ -1041,13 +1401,13 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let curr = self.memory.read(addr);
+                let curr = self.read_cycle(addr);
 
                 // If lower nibble == 0, set the half-carry bit
                 half_carry = curr & 0x0F == 0;
 
                 let result = curr.wrapping_sub(1);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
 
                 result as u16
             }
@@ -1067,7 +1427,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.read(dst),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -1084,7 +1444,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.write(dst, result),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -1127,11 +1487,21 @@ impl Cpu {
     pub fn memory(&self) -> &MemoryBus {
         &self.memory
     }
+
+    /// Write a program to ROM at 0x100 and point PC at it
+    #[cfg(test)]
+    pub(crate) fn load_program(&mut self, program: &[u8]) {
+        for (i, byte) in program.iter().enumerate() {
+            self.memory.controller().rom.write(0x100 + i as u16, *byte);
+        }
+        self.registers.PC = 0x100;
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::joypad::{JoypadEvent, JoypadInput};
 
     fn get_cpu() -> Cpu {
         Cpu::new(true)
@@ -1146,8 +1516,7 @@ mod test {
         cpu.registers.write(Reg8::B, 0x40u8);
 
         // Enable interrupts
-        let inst = Instruction::Ei;
-        cpu.execute(inst);
+        cpu.ime = true;
 
         // Enable VBLANK and LCD STAT
         cpu.memory.write(0xFFFF, 0x03u8);
@@ -1171,10 +1540,15 @@ mod test {
         cpu.trigger_interrupt(Interrupt::Vblank);
         cpu.trigger_interrupt(Interrupt::LcdStat);
 
-        // Execute a CPU step and verify that the ADD
-        // in the VBLANK ISR was executed
-        cpu.step();
+        // Execute a CPU step to dispatch the VBLANK interrupt
+        let (cycles, _) = cpu.step();
+        assert_eq!(cycles, 20);
         assert!(!cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x40);
+        assert_eq!(cpu.registers.SP, 0xFFFC);
+
+        // Step again and verify that the ADD in the VBLANK ISR was executed
+        cpu.step();
         assert_eq!(cpu.registers.PC, 0x41);
         assert_eq!(cpu.registers.read(Reg8::A), 0x41);
 
@@ -1184,9 +1558,11 @@ mod test {
         assert!(cpu.ime);
         assert_eq!(cpu.registers.PC, 0x1000);
 
-        // Step again -> this should trigger LcdStat and execute a NOP
+        // Step again -> this should trigger LcdStat, then execute a NOP
         cpu.step();
         assert!(!cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x48);
+        cpu.step();
         assert_eq!(cpu.registers.PC, 0x49);
 
         // Last step -> verify PC is restored
@@ -1472,6 +1848,265 @@ mod test {
         assert_eq!(cpu.registers.read(Reg8::A), 0x45);
     }
 
+    #[test]
+    fn ei_delay() {
+        let mut cpu = get_cpu();
+
+        // EI, NOP, NOP
+        cpu.load_program(&[0xFB, 0x00, 0x00]);
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        // The instruction after EI runs before the interrupt is dispatched
+        cpu.step();
+        assert!(!cpu.ime);
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x102);
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x40);
+
+        // EI, DI, NOP: DI cancels the pending EI
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0xFB, 0xF3, 0x00]);
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x103);
+    }
+
+    #[test]
+    fn code_data_log() {
+        let mut cpu = get_cpu();
+        cpu.memory.controller().cdl = Some(crate::cdl::CodeDataLog::new(0x8000, 0));
+
+        // LD A, (0x0200); LD A, 0x03; LDH (0x46), A: OAM DMA from 0x0300
+        cpu.load_program(&[0xFA, 0x00, 0x02, 0x3E, 0x03, 0xE0, 0x46]);
+        for _ in 0..200 {
+            cpu.step();
+        }
+
+        let cdl = cpu.memory.cdl().unwrap();
+        assert_eq!(cdl.flags(0x100), cdl::EXEC_FIRST);
+        assert_eq!(cdl.flags(0x102), cdl::EXEC_OPERAND);
+        assert_eq!(cdl.flags(0x200), cdl::DATA);
+        assert_eq!(cdl.flags(0x300), cdl::DATA | cdl::GRAPHICS);
+        assert_eq!(cdl.flags(0x39F), cdl::DATA | cdl::GRAPHICS);
+        assert_eq!(cdl.flags(0x3A0), 0);
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut cpu = get_cpu();
+
+        // HALT, INC A, NOP
+        cpu.load_program(&[0x76, 0x3C, 0x00]);
+        cpu.registers.write(Reg8::A, 0u8);
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        // IME is disabled with an interrupt pending: the CPU does not halt,
+        // and INC A runs twice
+        cpu.step();
+        assert!(!cpu.halted);
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x101);
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x102);
+        assert_eq!(cpu.registers.read(Reg8::A), 2);
+
+        // Without a pending interrupt, HALT works as usual
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x76, 0x3C, 0x00]);
+        cpu.step();
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn halt_wake() {
+        // HALT, INC A, NOP
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x76, 0x3C, 0x00]);
+        cpu.registers.write(Reg8::A, 0u8);
+        cpu.memory.write(0xFFFF, 0x01u8);
+
+        cpu.step();
+        assert!(cpu.halted);
+        assert_eq!(cpu.step().0, 4);
+
+        // With IME disabled, an interrupt wakes the CPU up without being dispatched
+        cpu.trigger_interrupt(Interrupt::Vblank);
+        cpu.step();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.registers.PC, 0x102);
+        assert_eq!(cpu.registers.read(Reg8::A), 1);
+
+        // With IME enabled, waking up adds an M-cycle to the dispatch
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x76, 0x00]);
+        cpu.ime = true;
+        cpu.memory.write(0xFFFF, 0x01u8);
+
+        cpu.step();
+        assert!(cpu.halted);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        let (cycles, _) = cpu.step();
+        assert_eq!(cycles, 24);
+        assert_eq!(cpu.registers.PC, 0x40);
+        assert_eq!(cpu.pop(), 0x101);
+    }
+
+    #[test]
+    fn ie_push() {
+        // Pushing the upper byte of PC (0x01) to IE keeps VBLANK enabled
+        let mut cpu = get_cpu();
+        cpu.ime = true;
+        cpu.registers.PC = 0x0100;
+        cpu.registers.SP = 0x0000;
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x40);
+
+        // Pushing 0x02 disables VBLANK, which cancels the dispatch
+        let mut cpu = get_cpu();
+        cpu.ime = true;
+        cpu.registers.PC = 0x0200;
+        cpu.registers.SP = 0x0000;
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x0000);
+        assert_eq!(cpu.registers.SP, 0xFFFE);
+        assert!(!cpu.ime);
+
+        // The interrupt is still pending
+        assert_eq!(cpu.pending_interrupts(), 0);
+        assert_eq!(cpu.memory.read(0xFF0F) & 0x1F, 0x01);
+    }
+
+    #[test]
+    fn stop_speed_switch() {
+        let mut cpu = get_cpu();
+
+        // STOP, NOP
+        cpu.load_program(&[0x10, 0x00, 0x00]);
+        cpu.memory.timer().step(1024);
+
+        // Only bit 0 of KEY1 is writable
+        cpu.memory.write(0xFF4D, 0x81u8);
+        assert_eq!(cpu.memory.read(0xFF4D), 0x7F);
+
+        let (cycles, _) = cpu.step();
+        assert_eq!(cycles, 4 + Cpu::SPEED_SWITCH_CYCLES);
+        assert!(cpu.speed);
+        assert!(cpu.clock_stopped());
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers.PC, 0x102);
+        assert_eq!(cpu.memory.read(0xFF04), 0);
+        assert_eq!(cpu.memory.read(0xFF4D), 0xFE);
+
+        // Unlike STOP mode, a speed switch leaves the LCD alone
+        assert!(cpu.memory.ppu_mut().frame_buffer().is_none());
+
+        // The pause only lasts for a single step
+        cpu.step();
+        assert!(!cpu.clock_stopped());
+    }
+
+    #[test]
+    fn stop_mode() {
+        // No speed switch on DMG, even if KEY1 is written to
+        let mut cpu = Cpu::new(false);
+        cpu.load_program(&[0x10, 0x00, 0x00]);
+        cpu.memory.write(0xFF4D, 0x01u8);
+        cpu.memory.timer().step(1024);
+
+        // Select the buttons
+        cpu.memory.write(0xFF00, 0x10u8);
+
+        cpu.step();
+        assert!(cpu.stopped);
+        assert!(!cpu.speed);
+        assert_eq!(cpu.memory.read(0xFF04), 0);
+
+        // The LCD turns white
+        let frame = cpu.memory.ppu_mut().frame_buffer().unwrap();
+        assert!(frame.to_rgba().iter().all(|&value| value == 0xFF));
+
+        // The CPU stays stopped until a selected input is pressed
+        cpu.memory.joypad().handle_event(&JoypadEvent::Down(JoypadInput::Up));
+        assert_eq!(cpu.step(), (4, Instruction::Nop));
+        assert_eq!(cpu.registers.PC, 0x102);
+
+        cpu.memory.joypad().handle_event(&JoypadEvent::Down(JoypadInput::A));
+        cpu.step();
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers.PC, 0x103);
+    }
+
+    #[test]
+    fn stop_button_held() {
+        // A selected button is held: STOP enters HALT mode instead, and DIV is not reset
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x10, 0x00, 0x00]);
+        cpu.memory.timer().step(1024);
+        cpu.memory.write(0xFF00, 0x10u8);
+        cpu.memory.joypad().handle_event(&JoypadEvent::Down(JoypadInput::Start));
+
+        cpu.step();
+        assert!(cpu.halted);
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers.PC, 0x102);
+        assert_eq!(cpu.memory.read(0xFF04), 4);
+
+        // With an interrupt pending, STOP is a 1-byte NOP
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x10, 0x00, 0x00]);
+        cpu.memory.write(0xFF00, 0x10u8);
+        cpu.memory.joypad().handle_event(&JoypadEvent::Down(JoypadInput::Start));
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        cpu.step();
+        assert!(!cpu.halted);
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers.PC, 0x101);
+    }
+
+    #[test]
+    fn illegal_opcode() {
+        let mut cpu = get_cpu();
+        cpu.load_program(&[0x00, 0xED, 0x00]);
+        cpu.ime = true;
+        cpu.memory.write(0xFFFF, 0x01u8);
+
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_locked());
+        assert_eq!(cpu.take_event(), Some(Event::Locked { opcode: 0xED, addr: 0x101 }));
+        assert_eq!(cpu.take_event(), None);
+
+        // Interrupts are not serviced either
+        cpu.trigger_interrupt(Interrupt::Vblank);
+        assert_eq!(cpu.step(), (4, Instruction::Nop));
+        assert_eq!(cpu.registers.PC, 0x101);
+
+        // The disassembler does not choke on it
+        let insts = cpu.disassemble(2, Some(0x101));
+        assert_eq!(insts[0], (Instruction::Illegal(0xED), 0x101));
+        assert_eq!(insts[1], (Instruction::Nop, 0x102));
+
+        cpu.reset();
+        assert!(!cpu.is_locked());
+    }
+
     #[test]
     fn push_and_pop() {
         let mut cpu = get_cpu();
@@ -1665,4 +2300,28 @@ mod test {
         cpu.execute(inst);
         assert_eq!(cpu.registers.read(Reg8::B), 0x7);
     }
+
+    #[test]
+    fn timer_access_cycle() {
+        let mut cpu = get_cpu();
+
+        // LDH A, (TIMA)
+        cpu.load_program(&[0xF0, 0x05]);
+
+        // TIMA ticks every 4 M-cycles, the next time in 3 M-cycles
+        cpu.memory.write(0xFF07, 0b101u8);
+        cpu.memory.timer().step(4);
+
+        // TIMA is read in the third M-cycle, once it ticked
+        let (cycles, _) = cpu.step();
+        assert_eq!(cycles, 12);
+        assert_eq!(cpu.registers.read(Reg8::A), 1);
+
+        // The timer does not run again for the M-cycles the CPU already ran it for
+        cpu.memory.step(cycles, false, &mut Vec::new());
+        cpu.memory.timer().step(12);
+        assert_eq!(cpu.memory.read(0xFF05), 1);
+        cpu.memory.timer().step(4);
+        assert_eq!(cpu.memory.read(0xFF05), 2);
+    }
 }
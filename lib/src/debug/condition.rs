@@ -0,0 +1,330 @@
+//! Breakpoint conditions
+//!
+//! A condition compares registers, memory and constants, e.g., `A == 0x12 && [0xC000] > 3`.
+//! Memory operands are written in brackets, and take either a constant address or a
+//! 16-bit register (`[HL]`). Comparisons can be combined with `&&`, `||` and parentheses.
+use crate::cpu::Cpu;
+use crate::error::{Error, Result};
+use crate::memory::MemoryRead;
+use crate::registers::{Reg16, Reg8, RegisterOps};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Value(u16),
+
+    /// Byte in memory at the address given by the inner operand
+    Memory(Box<Operand>),
+}
+
+impl Operand {
+    fn eval(&self, cpu: &Cpu) -> u16 {
+        match self {
+            Self::Reg8(reg) => cpu.registers.read(*reg) as u16,
+            Self::Reg16(reg) => cpu.registers.read(*reg),
+            Self::Value(value) => *value,
+            Self::Memory(addr) => {
+                let value: u8 = cpu.memory.read(addr.eval(cpu));
+                value as u16
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reg8(reg) => write!(f, "{}", reg),
+            Self::Reg16(reg) => write!(f, "{}", reg),
+            Self::Value(value) => write!(f, "{:#X}", value),
+            Self::Memory(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        };
+
+        write!(f, "{}", op)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    /// Parse a condition from its text form
+    pub fn parse(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+
+        let condition = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(Error::InvalidValue(format!("Unexpected {:?} in condition", token)));
+        }
+
+        Ok(condition)
+    }
+
+    /// Evaluate the condition against the current CPU state
+    pub fn eval(&self, cpu: &Cpu) -> bool {
+        match self {
+            Self::Compare(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(cpu), rhs.eval(cpu));
+                match op {
+                    Comparison::Eq => lhs == rhs,
+                    Comparison::Ne => lhs != rhs,
+                    Comparison::Lt => lhs < rhs,
+                    Comparison::Le => lhs <= rhs,
+                    Comparison::Gt => lhs > rhs,
+                    Comparison::Ge => lhs >= rhs,
+                }
+            }
+            Self::And(lhs, rhs) => lhs.eval(cpu) && rhs.eval(cpu),
+            Self::Or(lhs, rhs) => lhs.eval(cpu) || rhs.eval(cpu),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Compare(lhs, op, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+            Self::And(lhs, rhs) => {
+                // `&&` binds tighter than `||`
+                let wrap = |c: &Condition| match c {
+                    Self::Or(..) => format!("({})", c),
+                    _ => c.to_string(),
+                };
+                write!(f, "{} && {}", wrap(lhs), wrap(rhs))
+            }
+            Self::Or(lhs, rhs) => write!(f, "{} || {}", lhs, rhs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u16),
+    Compare(Comparison),
+    And,
+    Or,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let invalid = || Error::InvalidValue(format!("Invalid condition: {}", input));
+
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Compare(Comparison::Eq),
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Compare(Comparison::Ne),
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Compare(Comparison::Le),
+            '<' => Token::Compare(Comparison::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Compare(Comparison::Ge),
+            '>' => Token::Compare(Comparison::Gt),
+            c if c.is_ascii_alphanumeric() || c == '$' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                }
+
+                if c.is_ascii_digit() || c == '$' {
+                    Token::Number(parse_number(&word).ok_or_else(invalid)?)
+                } else {
+                    Token::Ident(word.to_ascii_uppercase())
+                }
+            }
+            _ => return Err(invalid()),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Numbers are decimal, or hex with a `0x` or `$` prefix
+fn parse_number(word: &str) -> Option<u16> {
+    if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).or_else(|| word.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+/// Recursive descent parser, with `||` binding looser than `&&`
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token.ok_or_else(|| Error::InvalidValue("Unexpected end of condition".to_string()))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next()? {
+            token if *token == expected => Ok(()),
+            token => Err(Error::InvalidValue(format!("Expected {:?} in condition, got {:?}", expected, token))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = Condition::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Condition> {
+        let mut lhs = self.comparison()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = Condition::And(Box::new(lhs), Box::new(self.comparison()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<Condition> {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let condition = self.or()?;
+            self.expect(Token::RParen)?;
+            return Ok(condition);
+        }
+
+        let lhs = self.operand()?;
+        let op = match self.next()? {
+            Token::Compare(op) => *op,
+            token => return Err(Error::InvalidValue(format!("Expected a comparison, got {:?}", token))),
+        };
+        let rhs = self.operand()?;
+
+        Ok(Condition::Compare(lhs, op, rhs))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let operand = match self.next()? {
+            Token::Number(value) => Operand::Value(*value),
+            Token::Ident(name) => match name.as_str() {
+                "A" => Operand::Reg8(Reg8::A),
+                "F" => Operand::Reg8(Reg8::F),
+                "B" => Operand::Reg8(Reg8::B),
+                "C" => Operand::Reg8(Reg8::C),
+                "D" => Operand::Reg8(Reg8::D),
+                "E" => Operand::Reg8(Reg8::E),
+                "H" => Operand::Reg8(Reg8::H),
+                "L" => Operand::Reg8(Reg8::L),
+                "AF" => Operand::Reg16(Reg16::AF),
+                "BC" => Operand::Reg16(Reg16::BC),
+                "DE" => Operand::Reg16(Reg16::DE),
+                "HL" => Operand::Reg16(Reg16::HL),
+                "SP" => Operand::Reg16(Reg16::SP),
+                "PC" => Operand::Reg16(Reg16::PC),
+                name => return Err(Error::InvalidValue(format!("Unknown register in condition: {}", name))),
+            },
+            Token::LBracket => {
+                let addr = match self.next()? {
+                    Token::Number(value) => Operand::Value(*value),
+                    Token::Ident(name) => match name.as_str() {
+                        "BC" => Operand::Reg16(Reg16::BC),
+                        "DE" => Operand::Reg16(Reg16::DE),
+                        "HL" => Operand::Reg16(Reg16::HL),
+                        "SP" => Operand::Reg16(Reg16::SP),
+                        name => return Err(Error::InvalidValue(format!("Invalid address in condition: {}", name))),
+                    },
+                    token => return Err(Error::InvalidValue(format!("Invalid address in condition: {:?}", token))),
+                };
+                self.expect(Token::RBracket)?;
+
+                Operand::Memory(Box::new(addr))
+            }
+            token => return Err(Error::InvalidValue(format!("Expected an operand, got {:?}", token))),
+        };
+
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryWrite;
+
+    #[test]
+    fn parse() {
+        let condition = Condition::parse("a == 0x12 && [$C000] > 3 || (hl != 300 && [HL] <= 0)").unwrap();
+        assert_eq!(condition.to_string(), "A == 0x12 && [0xC000] > 0x3 || HL != 0x12C && [HL] <= 0x0");
+
+        // The printed form parses back to the same condition
+        assert_eq!(Condition::parse(&condition.to_string()).unwrap(), condition);
+
+        for invalid in ["", "A", "A == ", "A = 1", "X == 1", "[A] == 1", "[0xC000 == 1", "A == 1 &&", "(A == 1"] {
+            assert!(Condition::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn eval() {
+        let mut cpu = Cpu::new(true);
+        cpu.registers.write(Reg8::A, 0x12);
+        cpu.registers.write(Reg16::HL, 0xC001);
+        cpu.memory.write(0xC000u16, 4u8);
+        cpu.memory.write(0xC001u16, 1u8);
+
+        let eval = |condition: &str| Condition::parse(condition).unwrap().eval(&cpu);
+        assert!(eval("A == 0x12 && [0xC000] > 3"));
+        assert!(!eval("A == 0x12 && [0xC000] > 4"));
+        assert!(eval("A != 0x12 || [HL] == 1"));
+        assert!(eval("(A < 0x12 || A >= 0x12) && HL == 0xC001"));
+    }
+}
@@ -0,0 +1,595 @@
+//! GDB remote serial protocol stub
+//!
+//! This lets GDB (or any other RSP client) drive the debugger over TCP. The register
+//! file is exposed as six 16-bit little-endian registers: AF, BC, DE, HL, SP and PC,
+//! in that order. The layout is described to the client in `target.xml`.
+//!
+//! See: https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use super::watch::{WatchKind, Watchpoint};
+use super::{Breakpoint, Debugger, Detach, Frontend, Mode, StopReason};
+use crate::cpu::Cpu;
+use crate::error::Result;
+use crate::memory::{MemoryRead, MemoryWrite};
+use crate::registers::{Reg16, RegisterOps};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gbc.sm83.core">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Registers in the order they are sent to the client
+const REGISTERS: [Reg16; 6] = [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC];
+
+/// Largest memory read or write handled in a single packet
+const MAX_MEMORY_LEN: usize = 0x800;
+
+/// Number of CPU steps between two checks for an interrupt from the client
+const POLL_INTERVAL: u32 = 0x10000;
+
+/// Debugger frontend that serves a GDB client over TCP
+pub struct GdbStub {
+    listener: TcpListener,
+
+    /// Connection to the current client, if any
+    client: Option<BufReader<TcpStream>>,
+
+    /// The CPU was resumed by the client, which now expects a stop reply
+    running: bool,
+
+    /// Why the CPU last stopped
+    last_stop: StopReason,
+
+    /// Number of calls to `interrupted` since the last poll
+    polls: u32,
+}
+
+impl GdbStub {
+    /// Listen for GDB clients on the given address.
+    ///
+    /// This does not block: the stub waits for a client the first time the debugger
+    /// stops the CPU.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+
+        Ok(Self {
+            listener,
+            client: None,
+            running: false,
+            last_stop: StopReason::Step,
+            polls: 0,
+        })
+    }
+
+    /// Address the stub is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        let (stream, addr) = self.listener.accept()?;
+        log::info!("GDB client connected from {}", addr);
+
+        stream.set_nodelay(true)?;
+        self.client = Some(BufReader::new(stream));
+        self.running = false;
+
+        Ok(())
+    }
+
+    /// Read the next packet from the client. Acks and interrupts are skipped.
+    fn read_packet(&mut self) -> Result<Option<String>> {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+
+        // Skip everything up to the start of the packet
+        let mut skipped = Vec::new();
+        if client.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        if client.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+            return Ok(None);
+        }
+
+        let mut checksum = [0u8; 2];
+        std::io::Read::read_exact(client, &mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected != Some(actual) {
+            client.get_mut().write_all(b"-")?;
+            return self.read_packet();
+        }
+
+        client.get_mut().write_all(b"+")?;
+
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let stream = client.get_mut();
+        write!(stream, "${}#{:02x}", data, checksum)?;
+        stream.flush()?;
+
+        Ok(())
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.last_stop {
+            StopReason::Watchpoint(hit) => {
+                let kind = match hit.kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write | WatchKind::Change => "watch",
+                    WatchKind::Access => "awatch",
+                };
+
+                format!("T05{}:{:x};", kind, hit.addr)
+            }
+            StopReason::Locked { .. } => "S04".to_string(),
+            StopReason::Interrupted => "S02".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    /// Handle a single packet. Returns `true` if the CPU should resume.
+    fn handle(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, packet: &str) -> Result<bool> {
+        // Packets come from the network, and do not have to start with an ASCII character
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.stop_reply(),
+            "g" => REGISTERS
+                .iter()
+                .map(|reg| {
+                    let value: u16 = cpu.registers.read(*reg);
+                    format!("{:02x}{:02x}", value as u8, value >> 8)
+                })
+                .collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == REGISTERS.len() * 2 => {
+                    for (reg, value) in REGISTERS.iter().zip(bytes.chunks(2)) {
+                        cpu.registers.write(*reg, u16::from_le_bytes([value[0], value[1]]));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| REGISTERS.get(n)) {
+                Some(reg) => {
+                    let value: u16 = cpu.registers.read(*reg);
+                    format!("{:02x}{:02x}", value as u8, value >> 8)
+                }
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let reg = args.split_once('=').and_then(|(n, value)| {
+                    let reg = REGISTERS.get(usize::from_str_radix(n, 16).ok()?)?;
+                    let value = decode_hex(value).filter(|v| v.len() == 2)?;
+                    Some((reg, u16::from_le_bytes([value[0], value[1]])))
+                });
+
+                match reg {
+                    Some((reg, value)) => {
+                        cpu.registers.write(*reg, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) if len <= MAX_MEMORY_LEN => (0..len)
+                    .map(|i| {
+                        let value: u8 = cpu.memory.read(addr.wrapping_add(i as u16));
+                        format!("{:02x}", value)
+                    })
+                    .collect(),
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_addr_len(range)?;
+                    let data = decode_hex(data).filter(|data| data.len() == len)?;
+                    Some((addr, data))
+                });
+
+                match write {
+                    Some((addr, data)) => {
+                        for (i, value) in data.into_iter().enumerate() {
+                            cpu.memory.write(addr.wrapping_add(i as u16), value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    cpu.registers.PC = addr;
+                }
+
+                return Ok(self.resume(debugger, command == "s"));
+            }
+            "Z" | "z" => match self.update_point(debugger, cpu, command == "Z", args) {
+                Some(reply) => reply.to_string(),
+                None => String::new(),
+            },
+            "H" | "T" => "OK".to_string(),
+            "k" => {
+                // No reply is expected
+                self.client = None;
+                debugger.detach(Detach::Kill);
+                return Ok(true);
+            }
+            "D" => {
+                self.send("OK")?;
+                self.client = None;
+                debugger.set_mode(Mode::Continue);
+                return Ok(true);
+            }
+            _ => {
+                if packet == "vCont?" {
+                    "vCont;c;C;s;S".to_string()
+                } else if let Some(actions) = packet.strip_prefix("vCont;") {
+                    // Only one thread: the first action applies
+                    let step = actions.starts_with('s') || actions.starts_with('S');
+                    return Ok(self.resume(debugger, step));
+                } else if packet.starts_with("qSupported") {
+                    format!("PacketSize={:x};qXfer:features:read+", MAX_MEMORY_LEN * 2 + 16)
+                } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+                    match parse_addr_len(range) {
+                        Some((offset, len)) => {
+                            let offset = (offset as usize).min(TARGET_XML.len());
+                            let end = offset.saturating_add(len).min(TARGET_XML.len());
+                            let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+                            format!("{}{}", prefix, &TARGET_XML[offset..end])
+                        }
+                        None => "E01".to_string(),
+                    }
+                } else if packet == "qAttached" {
+                    "1".to_string()
+                } else if packet == "qC" {
+                    "QC1".to_string()
+                } else if packet == "qfThreadInfo" {
+                    "m1".to_string()
+                } else if packet == "qsThreadInfo" {
+                    "l".to_string()
+                } else {
+                    // Unsupported
+                    String::new()
+                }
+            }
+        };
+
+        self.send(&reply)?;
+
+        Ok(false)
+    }
+
+    fn resume(&mut self, debugger: &mut Debugger, step: bool) -> bool {
+        debugger.set_mode(if step { Mode::Step } else { Mode::Continue });
+        self.running = true;
+        true
+    }
+
+    /// Insert (`Z`) or remove (`z`) a breakpoint or watchpoint. Returns `None` for
+    /// unsupported types.
+    fn update_point(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, insert: bool, args: &str) -> Option<&'static str> {
+        let mut args = args.split(',');
+        let kind = args.next()?;
+        let addr = u16::from_str_radix(args.next()?, 16).ok()?;
+        let len = usize::from_str_radix(args.next()?, 16).ok()?.max(1);
+        if len > 0x10000 {
+            return Some("E01");
+        }
+
+        let end = (addr as usize + len - 1).min(0xFFFF) as u16;
+
+        let watch_kind = match kind {
+            // Software and hardware breakpoints are handled the same way
+            "0" | "1" => {
+                let existing = debugger
+                    .breakpoints()
+                    .iter()
+                    .position(|b| b.addr == addr && b.bank.is_none() && b.condition.is_none());
+
+                match (insert, existing) {
+                    (true, None) => {
+                        debugger.add_breakpoint(Breakpoint::new(addr));
+                    }
+                    (false, Some(index)) => {
+                        debugger.remove_breakpoint(index);
+                    }
+                    _ => (),
+                }
+
+                return Some("OK");
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+
+        let watchpoint = Watchpoint::new(addr, end, watch_kind);
+        let watchpoints = cpu.memory.watchpoints_mut();
+        let existing = watchpoints.list().iter().position(|w| *w == watchpoint);
+
+        match (insert, existing) {
+            (true, None) => {
+                watchpoints.add(watchpoint);
+            }
+            (false, Some(index)) => {
+                watchpoints.remove(index);
+            }
+            _ => (),
+        }
+
+        Some("OK")
+    }
+}
+
+impl Frontend for GdbStub {
+    fn stopped(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, reason: StopReason) {
+        self.last_stop = reason;
+
+        loop {
+            if self.client.is_none() {
+                log::info!("Waiting for a GDB client on {:?}", self.listener.local_addr());
+                if let Err(e) = self.accept() {
+                    log::error!("Failed to accept GDB client: {}", e);
+                    debugger.detach(Detach::Error(e.to_string()));
+                    return;
+                }
+            }
+
+            if std::mem::take(&mut self.running) {
+                let reply = self.stop_reply();
+                if let Err(e) = self.send(&reply) {
+                    log::error!("GDB connection error: {}", e);
+                    self.client = None;
+                    continue;
+                }
+            }
+
+            let packet = match self.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    log::info!("GDB client disconnected");
+                    self.client = None;
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("GDB connection error: {}", e);
+                    self.client = None;
+                    continue;
+                }
+            };
+
+            match self.handle(debugger, cpu, &packet) {
+                Ok(true) => return,
+                Ok(false) => (),
+                Err(e) => {
+                    log::error!("GDB connection error: {}", e);
+                    self.client = None;
+                }
+            }
+        }
+    }
+
+    fn interrupted(&mut self) -> bool {
+        self.polls += 1;
+        if self.polls < POLL_INTERVAL {
+            return false;
+        }
+        self.polls = 0;
+
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => {
+                // A new client connecting stops the CPU
+                let _ = self.listener.set_nonblocking(true);
+                let client = self.listener.accept();
+                let _ = self.listener.set_nonblocking(false);
+
+                return match client {
+                    Ok((stream, addr)) => {
+                        log::info!("GDB client connected from {}", addr);
+                        let _ = stream.set_nodelay(true);
+                        self.client = Some(BufReader::new(stream));
+                        true
+                    }
+                    Err(_) => false,
+                };
+            }
+        };
+
+        // Look for a Ctrl-C (0x03) from the client without blocking
+        if client.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let interrupted = match client.fill_buf() {
+            Ok([]) => {
+                // Disconnected: let the CPU run
+                self.client = None;
+                return false;
+            }
+            Ok(data) => match data.iter().position(|b| *b == 0x03) {
+                Some(pos) => {
+                    client.consume(pos + 1);
+                    true
+                }
+                None => false,
+            },
+            // Nothing to read
+            Err(_) => false,
+        };
+
+        let _ = client.get_ref().set_nonblocking(false);
+
+        interrupted
+    }
+}
+
+/// Parse `addr,len`, in hex
+fn parse_addr_len(input: &str) -> Option<(u16, usize)> {
+    let (addr, len) = input.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..input.len()).step_by(2).map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    /// Minimal RSP client: sends a packet and returns the reply
+    fn request(stream: &mut TcpStream, packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+
+        let mut reply = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+
+        // Skip the checksum, and ack
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn session() {
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+        let addr = stub.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+
+            // The reply to "c" is the stop reply, once the CPU stops again
+            let packets = [
+                "?",
+                "g",
+                "P3=01c0",
+                "Mc002,1:55",
+                "mc002,1",
+                "m100,3",
+                "Z0,102,1",
+                "Z2,c000,2",
+                "c",
+                "?",
+                "z0,102,1",
+                "qXfer:features:read:target.xml:0,a",
+                "qXfer:features:read:target.xml:10,ffffffffffffffff",
+                "Z2,c000,10001",
+                "z2,ff00,ffff",
+                "\u{e9}",
+                "D",
+            ];
+            for packet in packets {
+                replies.push(request(&mut stream, packet));
+            }
+
+            replies
+        });
+
+        // INC A; LD (HL), A; NOP
+        let mut cpu = Cpu::new(true);
+        cpu.load_program(&[0x3C, 0x77, 0x00]);
+        cpu.registers.write(Reg16::AF, 0x1180);
+
+        let mut debugger = Debugger::new();
+        for _ in 0..10 {
+            if let Some(reason) = debugger.check(&cpu) {
+                stub.stopped(&mut debugger, &mut cpu, reason);
+            }
+
+            if debugger.mode() == Mode::Continue && stub.client.is_none() {
+                break;
+            }
+
+            cpu.step();
+        }
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0], "S05");
+
+        // AF first, PC last, little-endian
+        assert_eq!(replies[1].len(), 24);
+        assert!(replies[1].starts_with("8011"));
+        assert!(replies[1].ends_with("0001"));
+
+        assert_eq!(&replies[2..8], ["OK", "OK", "55", "3c7700", "OK", "OK"]);
+        assert_eq!(replies[8], "T05watch:c001;");
+        assert_eq!(replies[9], "T05watch:c001;");
+        assert_eq!(replies[10], "OK");
+        assert_eq!(replies[11], "m<?xml vers");
+        assert!(replies[12].starts_with('l'));
+        assert_eq!(&replies[13..], ["E01", "OK", "", "OK"]);
+        assert!(debugger.breakpoints().is_empty());
+
+        assert_eq!(cpu.registers.read(Reg16::HL), 0xC001);
+        let value: u8 = cpu.memory.read(0xC001);
+        assert_eq!(value, 0x12);
+    }
+
+    #[test]
+    fn kill() {
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+        let addr = stub.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            assert_eq!(request(&mut stream, "?"), "S05");
+            write!(stream, "$k#6b").unwrap();
+        });
+
+        // The session ends, and the caller decides what to do with the emulator
+        let mut cpu = Cpu::new(true);
+        let mut debugger = Debugger::new();
+        stub.stopped(&mut debugger, &mut cpu, StopReason::Step);
+        client.join().unwrap();
+
+        assert_eq!(debugger.take_detach(), Some(Detach::Kill));
+        assert_eq!(debugger.mode(), Mode::Continue);
+        assert!(stub.client.is_none());
+    }
+}
@@ -0,0 +1,764 @@
+//! Breakpoint, step and continue engine for debugging ROMs.
+//!
+//! The `Debugger` is checked before every instruction and decides whether the CPU
+//! should stop there. When it does, the `Frontend` attached to the `Gameboy` is handed
+//! the `StopReason` along with the debugger and CPU, and stays in control until it
+//! returns. The CLI REPL (`repl::Repl`) is one such frontend.
+use std::collections::VecDeque;
+use std::io::{BufWriter, Write};
+
+use crate::cpu::Cpu;
+use crate::instructions::Instruction;
+use crate::memory::MemoryRead;
+
+pub mod condition;
+pub mod gdb;
+pub mod profile;
+pub mod repl;
+pub mod stack;
+pub mod watch;
+
+use condition::Condition;
+use watch::WatchHit;
+
+/// Something that drives the debugger once the CPU stops (e.g., a REPL or a GDB stub)
+pub trait Frontend {
+    /// Called when the debugger stops the CPU, right before the instruction at PC is
+    /// executed. Execution resumes once this returns, based on `Debugger::mode`.
+    fn stopped(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, reason: StopReason);
+
+    /// Called before every CPU step while the CPU runs. Returning `true` stops the CPU
+    /// with `StopReason::Interrupted` (e.g., Ctrl-C from a remote client).
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+/// How the debugger lets the CPU run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Run until a breakpoint is hit
+    Continue,
+
+    /// Run until the `n`th breakpoint hit
+    ContinueN(u32),
+
+    /// Run until PC reaches the given address, or a breakpoint is hit
+    ContinuePc(u16),
+
+    /// Stop before the next instruction
+    Step,
+
+    /// Stop after `n` instructions
+    StepN(u32),
+
+    /// Stop after the next instruction, once the call stack is back to the given depth
+    /// (i.e., step over calls, RSTs and interrupts), or a breakpoint is hit
+    StepOver(usize),
+
+    /// Run until the call stack is shallower than the given depth (i.e., the current
+    /// frame returned), or a breakpoint is hit
+    Finish(usize),
+}
+
+/// Why the debugger stopped the CPU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// Stepping is done
+    Step,
+
+    /// The breakpoint with the given index was hit
+    Breakpoint(usize),
+
+    /// PC reached the address given to `Mode::ContinuePc`
+    Reached(u16),
+
+    /// The CPU was locked up by an illegal opcode
+    Locked { opcode: u8, addr: u16 },
+
+    /// A watchpoint was hit during the last step
+    Watchpoint(WatchHit),
+
+    /// The frontend asked to stop
+    Interrupted,
+
+    /// The frame given to `Mode::Finish` returned
+    Finished,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Step => write!(f, "Step"),
+            Self::Breakpoint(index) => write!(f, "Breakpoint {}", index),
+            Self::Reached(addr) => write!(f, "Reached {:#06X}", addr),
+            Self::Locked { opcode, addr } => {
+                write!(f, "CPU locked up by illegal opcode {:#04X} at {:#06X}", opcode, addr)
+            }
+            Self::Watchpoint(hit) => write!(f, "{}", hit),
+            Self::Interrupted => write!(f, "Interrupted"),
+            Self::Finished => write!(f, "Returned"),
+        }
+    }
+}
+
+/// Why a frontend ended the debugging session (see `Debugger::detach`)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detach {
+    /// The client asked to kill the program (e.g., GDB `kill`)
+    Kill,
+
+    /// The frontend failed and cannot go on
+    Error(String),
+}
+
+impl std::fmt::Display for Detach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kill => write!(f, "Killed by the debugger"),
+            Self::Error(e) => write!(f, "Debugger error: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+
+    /// Only stop while this bank is mapped at the address, if set
+    pub bank: Option<u16>,
+
+    /// Only stop if this evaluates to true, if set
+    pub condition: Option<Condition>,
+
+    pub enabled: bool,
+
+    /// Number of times the breakpoint was hit, with its condition true
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    /// Break on the given address in any bank
+    pub fn new(addr: u16) -> Self {
+        Self {
+            addr,
+            bank: None,
+            condition: None,
+            enabled: true,
+            hits: 0,
+        }
+    }
+
+    fn matches(&self, cpu: &Cpu, pc: u16, bank: u16) -> bool {
+        self.enabled
+            && self.addr == pc
+            && self.bank.is_none_or(|b| b == bank)
+            && self.condition.as_ref().is_none_or(|c| c.eval(cpu))
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr)?,
+            None => write!(f, "{:#06X}", self.addr)?,
+        }
+
+        write!(f, ", enabled = {}, hits = {}", self.enabled, self.hits)?;
+
+        if let Some(condition) = &self.condition {
+            write!(f, ", if {}", condition)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+
+    /// Last `HISTORY_SIZE` instructions executed, along with their address and bank
+    history: VecDeque<(Instruction, u16, u16)>,
+
+    /// Total number of instructions executed while the debugger was attached
+    executed: u64,
+
+    /// Every instruction executed is written here, if set
+    instruction_dump: Option<BufWriter<Box<dyn Write + Send>>>,
+
+    /// Set once a lock-up has been reported, so that it is only reported once
+    locked: bool,
+
+    /// Set by the frontend to end the session, until picked up by `take_detach`
+    detach: Option<Detach>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    const HISTORY_SIZE: usize = 10_000;
+
+    /// Create a debugger that stops before the first instruction
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Step,
+            breakpoints: Vec::new(),
+            history: VecDeque::new(),
+            executed: 0,
+            instruction_dump: None,
+            locked: false,
+            detach: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Set how the CPU runs once the frontend returns
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// End the debugging session: the CPU keeps running, and the emulator is expected
+    /// to check `take_detach` and decide what to do (e.g., exit).
+    pub fn detach(&mut self, reason: Detach) {
+        self.mode = Mode::Continue;
+        self.detach = Some(reason);
+    }
+
+    /// Returns why the frontend ended the session since the last call, if it did
+    pub fn take_detach(&mut self) -> Option<Detach> {
+        self.detach.take()
+    }
+
+    /// Step over the next instruction: if it calls a function (CALL or RST, taken) or an
+    /// interrupt is dispatched, run until it returns.
+    pub fn step_over(&mut self, cpu: &Cpu) {
+        self.mode = Mode::StepOver(cpu.call_stack().depth());
+    }
+
+    /// Run until the current frame returns. Returns `false` if there is no frame on
+    /// the call stack.
+    pub fn finish(&mut self, cpu: &Cpu) -> bool {
+        let depth = cpu.call_stack().depth();
+        if depth > 0 {
+            self.mode = Mode::Finish(depth);
+        }
+
+        depth > 0
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Add a breakpoint, and return its index.
+    ///
+    /// If there already is a breakpoint with the same address, bank and condition, it is
+    /// enabled instead.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let existing = self.breakpoints.iter().position(|b| {
+            b.addr == breakpoint.addr && b.bank == breakpoint.bank && b.condition == breakpoint.condition
+        });
+
+        if let Some(index) = existing {
+            self.breakpoints[index].enabled = true;
+            return index;
+        }
+
+        self.breakpoints.push(breakpoint);
+        self.breakpoints.len() - 1
+    }
+
+    /// Set or clear the condition of the breakpoint with the given index. Returns `None`
+    /// if there is no such breakpoint.
+    pub fn set_breakpoint_condition(&mut self, index: usize, condition: Option<Condition>) -> Option<()> {
+        self.breakpoints.get_mut(index).map(|b| b.condition = condition)
+    }
+
+    /// Delete the breakpoint with the given index
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        if index < self.breakpoints.len() {
+            Some(self.breakpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Enable or disable the breakpoint with the given index. Returns `None` if there
+    /// is no such breakpoint.
+    pub fn set_breakpoint_enabled(&mut self, index: usize, enabled: bool) -> Option<()> {
+        self.breakpoints.get_mut(index).map(|b| b.enabled = enabled)
+    }
+
+    /// Executed instructions, oldest first
+    pub fn history(&self) -> impl DoubleEndedIterator<Item = &(Instruction, u16, u16)> + ExactSizeIterator {
+        self.history.iter()
+    }
+
+    /// Total number of instructions executed so far
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        self.executed = 0;
+    }
+
+    /// Write every executed instruction to `writer`, or stop doing so if `None`
+    pub fn set_instruction_dump(&mut self, writer: Option<Box<dyn Write + Send>>) {
+        self.instruction_dump = writer.map(BufWriter::new);
+    }
+
+    /// Count a hit on every breakpoint matching the instruction at PC, and return the
+    /// index of the first one
+    fn breakpoint_hit(&mut self, cpu: &Cpu) -> Option<usize> {
+        let pc = cpu.registers.PC;
+        let (_, bank) = cpu.memory.memory_info(pc);
+
+        let mut hit = None;
+        for (index, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.matches(cpu, pc, bank) {
+                breakpoint.hits += 1;
+                hit = hit.or(Some(index));
+            }
+        }
+
+        hit
+    }
+
+    /// Check whether the CPU should stop before executing the instruction at PC.
+    ///
+    /// This is called before every CPU step. After a stop, the mode goes back to
+    /// `Mode::Step`: it is up to the frontend to pick how to resume.
+    pub fn check(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        if cpu.is_locked() {
+            if self.locked {
+                return None;
+            }
+
+            self.locked = true;
+            self.mode = Mode::Step;
+
+            let addr = cpu.registers.PC;
+            let opcode = cpu.memory.read(addr);
+            return Some(StopReason::Locked { opcode, addr });
+        }
+
+        self.locked = false;
+
+        // Watchpoints are hit in the middle of the last step, so the CPU stops right after it
+        let watch_hit = cpu.memory.watchpoints().take_hit().map(StopReason::Watchpoint);
+
+        // If the CPU is currently halted or stopped, keep waiting
+        if cpu.halted || cpu.stopped {
+            if watch_hit.is_some() {
+                self.mode = Mode::Step;
+            }
+
+            return watch_hit;
+        }
+
+        let pc = cpu.registers.PC;
+        let (_, bank) = cpu.memory.memory_info(pc);
+
+        // Keep track of each instruction the CPU executes
+        let (inst, _, _) = cpu.fetch(None);
+        if self.history.len() == Self::HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((inst, pc, bank));
+        self.executed += 1;
+
+        // Dump each instruction to a file. Failing to do so stops the dump.
+        if let Some(f) = self.instruction_dump.as_mut() {
+            if let Err(e) = writeln!(f, "{}", format_instruction(cpu, &inst, pc, bank)) {
+                log::error!("Failed to dump instruction: {}", e);
+                self.instruction_dump = None;
+            }
+        }
+
+        // Breakpoints count their hits even while stepping
+        let breakpoint = self.breakpoint_hit(cpu);
+
+        let reason = match self.mode {
+            _ if watch_hit.is_some() => watch_hit,
+            Mode::Step => Some(StopReason::Step),
+            Mode::StepN(n) if n > 1 => {
+                self.mode = Mode::StepN(n - 1);
+                None
+            }
+            Mode::StepN(_) => Some(StopReason::Step),
+            Mode::Continue => breakpoint.map(StopReason::Breakpoint),
+            Mode::ContinueN(n) => match breakpoint {
+                Some(_) if n > 1 => {
+                    self.mode = Mode::ContinueN(n - 1);
+                    None
+                }
+                hit => hit.map(StopReason::Breakpoint),
+            },
+            Mode::ContinuePc(addr) if pc == addr => Some(StopReason::Reached(addr)),
+            Mode::ContinuePc(_) => breakpoint.map(StopReason::Breakpoint),
+            Mode::StepOver(depth) if cpu.call_stack().depth() <= depth => Some(StopReason::Step),
+            Mode::Finish(depth) if cpu.call_stack().depth() < depth => Some(StopReason::Finished),
+            Mode::StepOver(_) | Mode::Finish(_) => breakpoint.map(StopReason::Breakpoint),
+        };
+
+        if reason.is_some() {
+            self.mode = Mode::Step;
+        }
+
+        reason
+    }
+}
+
+/// Format an instruction at `addr` in `bank` for display. The address and jump target
+/// are followed by their label, if there are symbols for them.
+fn format_instruction(cpu: &Cpu, inst: &Instruction, addr: u16, bank: u16) -> String {
+    let symbols = match cpu.symbols() {
+        Some(symbols) => symbols,
+        None => return format!("{:#06x}: {}", addr, inst),
+    };
+
+    let mut line = match symbols.format(bank, addr) {
+        Some(label) => format!("{:#06x} <{}>: {}", addr, label, inst),
+        None => format!("{:#06x}: {}", addr, inst),
+    };
+
+    if let Some(target) = inst.jump_target(addr) {
+        // Jumps within the same region of memory stay in the same bank
+        let target_bank = if target & 0xC000 == addr & 0xC000 {
+            bank
+        } else {
+            cpu.memory.memory_info(target).1
+        };
+
+        if let Some(label) = symbols.format(target_bank, target) {
+            line.push_str(" ; ");
+            line.push_str(&label);
+        }
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryWrite;
+    use crate::cpu::Interrupt;
+    use crate::registers::{Flag, Reg8, RegisterOps};
+    use crate::symbols::Symbols;
+    use watch::{WatchKind, Watchpoint};
+
+    /// CPU with the given program at 0x100, and PC pointing at it
+    fn get_cpu(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(true);
+        cpu.load_program(program);
+        cpu
+    }
+
+    /// Run the CPU until the debugger stops it, for at most `limit` steps
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu, limit: usize) -> Option<StopReason> {
+        for _ in 0..limit {
+            if let Some(reason) = debugger.check(cpu) {
+                return Some(reason);
+            }
+            cpu.step();
+        }
+        None
+    }
+
+    #[test]
+    fn step() {
+        let mut cpu = get_cpu(&[0x00; 8]);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.check(&cpu), Some(StopReason::Step));
+
+        // Resuming executes the instruction the CPU stopped at
+        debugger.set_mode(Mode::StepN(3));
+        cpu.step();
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Step));
+        assert_eq!(cpu.registers.PC, 0x103);
+        assert_eq!(debugger.mode(), Mode::Step);
+        assert_eq!(debugger.executed(), 4);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = get_cpu(&[0x00; 8]);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        assert_eq!(debugger.add_breakpoint(Breakpoint::new(0x103)), 0);
+        assert_eq!(debugger.add_breakpoint(Breakpoint::new(0x105)), 1);
+        assert_eq!(debugger.add_breakpoint(Breakpoint::new(0x103)), 0);
+
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Breakpoint(0)));
+        assert_eq!(cpu.registers.PC, 0x103);
+
+        // Disabled breakpoints are skipped
+        debugger.set_breakpoint_enabled(1, false);
+        debugger.set_mode(Mode::Continue);
+        cpu.step();
+        assert_eq!(run(&mut debugger, &mut cpu, 4), None);
+
+        let removed = Breakpoint {
+            enabled: false,
+            ..Breakpoint::new(0x105)
+        };
+        assert_eq!(debugger.remove_breakpoint(1), Some(removed));
+        assert_eq!(debugger.remove_breakpoint(1), None);
+        assert_eq!(debugger.set_breakpoint_enabled(1, true), None);
+    }
+
+    #[test]
+    fn breakpoint_bank_condition() {
+        // INC A; JR -3
+        let mut cpu = get_cpu(&[0x3C, 0x18, 0xFD]);
+        cpu.registers.write(Reg8::A, 0);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        // ROM bank 0 is mapped at 0x101
+        let mut other_bank = Breakpoint::new(0x101);
+        other_bank.bank = Some(1);
+        debugger.add_breakpoint(other_bank);
+
+        let mut conditional = Breakpoint::new(0x101);
+        conditional.bank = Some(0);
+        conditional.condition = Some(Condition::parse("A == 3 && [HL] == [HL]").unwrap());
+        assert_eq!(debugger.add_breakpoint(conditional), 1);
+
+        assert_eq!(run(&mut debugger, &mut cpu, 20), Some(StopReason::Breakpoint(1)));
+        assert_eq!(cpu.registers.read(Reg8::A), 3);
+
+        let hits: Vec<u32> = debugger.breakpoints().iter().map(|b| b.hits).collect();
+        assert_eq!(hits, vec![0, 1]);
+        assert_eq!(debugger.breakpoints()[1].to_string(), "00:0101, enabled = true, hits = 1, if A == 0x3 && [HL] == [HL]");
+
+        // Clearing the condition makes it stop on the next iteration
+        debugger.set_breakpoint_condition(1, None);
+        debugger.set_mode(Mode::Continue);
+        cpu.step();
+        assert_eq!(run(&mut debugger, &mut cpu, 20), Some(StopReason::Breakpoint(1)));
+        assert_eq!(cpu.registers.read(Reg8::A), 4);
+        assert_eq!(debugger.breakpoints()[1].hits, 2);
+    }
+
+    #[test]
+    fn continue_n() {
+        // JR -2: loops on itself
+        let mut cpu = get_cpu(&[0x18, 0xFE]);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(Breakpoint::new(0x100));
+        debugger.set_mode(Mode::ContinueN(3));
+
+        for _ in 0..2 {
+            assert_eq!(debugger.check(&cpu), None);
+            cpu.step();
+        }
+        assert_eq!(debugger.check(&cpu), Some(StopReason::Breakpoint(0)));
+    }
+
+    #[test]
+    fn step_over() {
+        // CALL 0x0108; RST 0x08; CALL NZ, 0x0108; NOP; NOP; RET
+        let mut cpu = get_cpu(&[0xCD, 0x08, 0x01, 0xCF, 0xC4, 0x08, 0x01, 0x00, 0x00, 0xC9]);
+        cpu.memory.controller().rom.write(0x0008u16, 0xC9u8);
+        cpu.registers.SP = 0xFFFE;
+        cpu.registers.set(Flag::Zero, true);
+        let mut debugger = Debugger::new();
+
+        // The frontend resumes the CPU right after picking the mode
+        let step_over = |debugger: &mut Debugger, cpu: &mut Cpu| {
+            debugger.step_over(cpu);
+            cpu.step();
+            run(debugger, cpu, 10)
+        };
+
+        for pc in [0x103, 0x104, 0x107, 0x108] {
+            assert_eq!(step_over(&mut debugger, &mut cpu), Some(StopReason::Step));
+            assert_eq!(cpu.registers.PC, pc);
+            assert_eq!(cpu.call_stack().depth(), 0);
+        }
+
+        // Nothing to finish at the top level
+        assert!(!debugger.finish(&cpu));
+    }
+
+    #[test]
+    fn finish() {
+        // CALL 0x0104; NOP; 0x104: CALL 0x0108; RET; 0x108: NOP; RET
+        let mut cpu = get_cpu(&[0xCD, 0x04, 0x01, 0x00, 0xCD, 0x08, 0x01, 0xC9, 0x00, 0xC9]);
+        cpu.registers.SP = 0xFFFE;
+        let mut debugger = Debugger::new();
+
+        cpu.step();
+        cpu.step();
+        let frames = cpu.call_stack().frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[1].call_site, frames[1].target, frames[1].return_addr), (0x104, 0x108, 0x107));
+
+        assert!(debugger.finish(&cpu));
+        cpu.step();
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Finished));
+        assert_eq!(cpu.registers.PC, 0x107);
+        assert_eq!(cpu.call_stack().depth(), 1);
+
+        // Breakpoints in the frame stop it early
+        debugger.add_breakpoint(Breakpoint::new(0x109));
+        cpu.registers.PC = 0x104;
+        cpu.step();
+        assert!(debugger.finish(&cpu));
+        cpu.step();
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Breakpoint(0)));
+    }
+
+    #[test]
+    fn interrupt_frame() {
+        // EI; NOP; NOP. ISR: RETI
+        let mut cpu = get_cpu(&[0xFB, 0x00, 0x00]);
+        cpu.memory.controller().rom.write(0x0040u16, 0xD9u8);
+        cpu.memory.write(0xFFFFu16, 0x01u8);
+        cpu.registers.SP = 0xFFFE;
+
+        cpu.step();
+        cpu.trigger_interrupt(Interrupt::Vblank);
+        cpu.step();
+        cpu.step();
+
+        let frames = cpu.call_stack().frames();
+        assert_eq!(cpu.registers.PC, 0x40);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].kind, stack::FrameKind::Interrupt(0x40));
+        assert_eq!(frames[0].return_addr, 0x102);
+        assert_eq!(frames[0].kind.to_string(), "VBlank interrupt");
+
+        cpu.step();
+        assert_eq!(cpu.call_stack().depth(), 0);
+    }
+
+    #[test]
+    fn symbols() {
+        // CALL 0x0108; JR -2
+        let mut cpu = get_cpu(&[0xCD, 0x08, 0x01, 0x18, 0xFE]);
+        let (inst, _, _) = cpu.fetch(None);
+        assert_eq!(format_instruction(&cpu, &inst, 0x100, 0), "0x0100: call 0x0108");
+
+        cpu.set_symbols(Some(Symbols::parse("00:0100 Start\n00:0108 Func").unwrap()));
+        assert_eq!(format_instruction(&cpu, &inst, 0x100, 0), "0x0100 <Start>: call 0x0108 ; Func");
+
+        let (inst, _, _) = cpu.fetch(Some(0x103));
+        assert_eq!(format_instruction(&cpu, &inst, 0x103, 0), "0x0103 <Start+0x3>: jr -2 ; Start+0x3");
+
+        // Labels in other banks are not used
+        assert_eq!(format_instruction(&cpu, &inst, 0x103, 1), "0x0103: jr -2");
+    }
+
+    #[test]
+    fn locked() {
+        let mut cpu = get_cpu(&[0x00, 0xDB]);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        let reason = run(&mut debugger, &mut cpu, 10);
+        assert_eq!(reason, Some(StopReason::Locked { opcode: 0xDB, addr: 0x101 }));
+
+        // Only reported once
+        debugger.set_mode(Mode::Continue);
+        assert_eq!(run(&mut debugger, &mut cpu, 10), None);
+
+        let history: Vec<u16> = debugger.history().map(|(_, pc, _)| *pc).collect();
+        assert_eq!(history, vec![0x100, 0x101]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LD A, 0x42; LD (0xC000), A; LD (0xC000), A; LD A, (0xC000)
+        let program = [0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xEA, 0x00, 0xC0, 0xFA, 0x00, 0xC0, 0x00];
+        let mut cpu = get_cpu(&program);
+        let old = cpu.memory.read(0xC000);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        let watchpoints = cpu.memory.watchpoints_mut();
+        watchpoints.add(Watchpoint::new(0xC000, 0xC000, WatchKind::Change));
+        watchpoints.add(Watchpoint::new(0xC000, 0xC00F, WatchKind::Read));
+
+        // The CPU stops right after the access
+        let hit = WatchHit {
+            index: 0,
+            kind: WatchKind::Change,
+            addr: 0xC000,
+            bank: 0,
+            pc: 0x102,
+            old,
+            new: 0x42,
+            write: true,
+            dma: false,
+        };
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Watchpoint(hit)));
+        assert_eq!(cpu.registers.PC, 0x105);
+
+        // Writing the same value again is not a change
+        debugger.set_mode(Mode::Continue);
+        let hit = WatchHit {
+            index: 1,
+            kind: WatchKind::Read,
+            pc: 0x108,
+            old: 0x42,
+            write: false,
+            ..hit
+        };
+        assert_eq!(run(&mut debugger, &mut cpu, 10), Some(StopReason::Watchpoint(hit)));
+    }
+
+    #[test]
+    fn watchpoint_bank() {
+        // Write to 0xD000 in WRAM bank 2, then in bank 3
+        let program = [
+            0x3E, 0x02, 0xE0, 0x70, 0xEA, 0x00, 0xD0, 0x3E, 0x03, 0xE0, 0x70, 0xEA, 0x00, 0xD0, 0x00,
+        ];
+        let mut cpu = get_cpu(&program);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        let mut watchpoint = Watchpoint::new(0xD000, 0xD000, WatchKind::Write);
+        watchpoint.bank = Some(3);
+        cpu.memory.watchpoints_mut().add(watchpoint);
+
+        match run(&mut debugger, &mut cpu, 10) {
+            Some(StopReason::Watchpoint(hit)) => {
+                assert_eq!((hit.pc, hit.bank, hit.new), (0x10B, 3, 0x03));
+            }
+            reason => panic!("unexpected stop: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn watchpoint_dma() {
+        // LD A, 0xC0; LDH (0x46), A: OAM DMA from 0xC000
+        let mut cpu = get_cpu(&[0x3E, 0xC0, 0xE0, 0x46, 0x00, 0x00, 0x00, 0x00]);
+        cpu.memory.write(0xC000u16, 0x12u8);
+        let mut debugger = Debugger::new();
+        debugger.set_mode(Mode::Continue);
+
+        cpu.memory.watchpoints_mut().add(Watchpoint::new(0xFE00, 0xFE9F, WatchKind::Write));
+
+        match run(&mut debugger, &mut cpu, 10) {
+            Some(StopReason::Watchpoint(hit)) => {
+                assert_eq!((hit.addr, hit.new, hit.dma), (0xFE00, 0x12, true));
+            }
+            reason => panic!("unexpected stop: {:?}", reason),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Cycle profiler
+//!
+//! Attributes the cycles taken by every CPU step to the `bank:PC` of the instruction,
+//! and to the shadow call stack it ran in. Cycles spent halted (or stopped) are only
+//! counted per frame, which tells how busy the CPU was: a frame that never halts is
+//! likely a lag frame.
+//!
+//! Call stacks can be written in the folded format read by `flamegraph.pl` and
+//! `inferno-flamegraph`: one `outer;inner;leaf cycles` line per stack.
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::cpu::Cpu;
+use crate::symbols::Symbols;
+
+use super::stack::FrameKind;
+
+/// A function in a call stack: the target of a frame, or the label containing PC
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Node {
+    bank: u16,
+    addr: u16,
+
+    /// What created the frame, or `None` for the function containing PC
+    kind: Option<FrameKind>,
+}
+
+impl Node {
+    fn name(&self, symbols: Option<&Symbols>) -> String {
+        match (symbols.and_then(|symbols| symbols.format(self.bank, self.addr)), self.kind) {
+            (Some(label), _) => label,
+            (None, Some(kind @ FrameKind::Interrupt(_))) => kind.to_string(),
+            (None, _) => format!("{:02X}:{:04X}", self.bank, self.addr),
+        }
+    }
+}
+
+/// CPU usage during a single frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Cycles taken, including cycles spent halted
+    pub cycles: u64,
+
+    /// Cycles spent halted or stopped
+    pub halted: u64,
+}
+
+impl FrameStats {
+    /// Percentage of the frame the CPU was running
+    pub fn usage(&self) -> f64 {
+        if self.cycles == 0 {
+            return 0.0;
+        }
+
+        (self.cycles - self.halted) as f64 * 100.0 / self.cycles as f64
+    }
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    /// Cycles by (bank, PC)
+    addresses: HashMap<(u16, u16), u64>,
+
+    /// Interned call stacks, outermost function first, and cycles by stack id
+    stacks: HashMap<Vec<Node>, usize>,
+    cycles: Vec<u64>,
+
+    /// Completed frames
+    frames: Vec<FrameStats>,
+
+    /// Frame in progress
+    frame: FrameStats,
+
+    /// State before the current step: (bank, PC, halted) and call stack id
+    sample: (u16, u16, bool),
+    stack: usize,
+
+    /// What the current call stack was built from
+    key: Option<StackKey>,
+}
+
+/// The parts of the CPU state a call stack is built from. The stack is only rebuilt
+/// when they change, which is rare compared to steps.
+#[derive(Clone, Copy, PartialEq)]
+struct StackKey {
+    depth: usize,
+
+    /// Target and kind of the innermost frame
+    top: Option<(u16, u16, FrameKind)>,
+
+    /// Function containing PC
+    leaf: Option<(u16, u16)>,
+
+    /// Address of the symbols, as they can be loaded or replaced at any time
+    symbols: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture the CPU state before a step
+    pub(crate) fn begin(&mut self, cpu: &Cpu) {
+        let pc = cpu.registers.PC;
+        let (_, bank) = cpu.memory.memory_info(pc);
+        self.sample = (bank, pc, cpu.halted || cpu.clock_stopped());
+
+        let symbols = cpu.symbols();
+        let frames = cpu.call_stack().frames();
+        let leaf = symbols.and_then(|symbols| symbols.lookup(bank, pc)).map(|(_, offset)| (bank, pc - offset));
+        let key = StackKey {
+            depth: frames.len(),
+            top: frames.last().map(|frame| (frame.target_bank, frame.target, frame.kind)),
+            leaf,
+            symbols: symbols.map_or(0, |symbols| symbols as *const Symbols as usize),
+        };
+        if self.key == Some(key) {
+            return;
+        }
+        self.key = Some(key);
+
+        // With symbols, stacks start from the function the outermost frame was called
+        // from (e.g., the main loop), and end with the function containing PC
+        let mut stack = Vec::with_capacity(frames.len() + 2);
+        if let Some((outer, (_, offset))) = frames.first().and_then(|frame| {
+            symbols?.lookup(frame.call_bank, frame.call_site).map(|label| (frame, label))
+        }) {
+            stack.push(Node { bank: outer.call_bank, addr: outer.call_site - offset, kind: None });
+        }
+
+        stack.extend(frames.iter().map(|frame| Node {
+            bank: frame.target_bank,
+            addr: frame.target,
+            kind: Some(frame.kind),
+        }));
+
+        // Add the function containing PC, unless the innermost frame is already it
+        if let Some((bank, addr)) = leaf {
+            if stack.last().is_none_or(|node| (node.bank, node.addr) != (bank, addr)) {
+                stack.push(Node { bank, addr, kind: None });
+            }
+        }
+
+        let next = self.cycles.len();
+        self.stack = *self.stacks.entry(stack).or_insert(next);
+        if self.stack == next {
+            self.cycles.push(0);
+        }
+    }
+
+    /// Attribute the cycles taken by the step to the state captured by `begin`
+    pub(crate) fn end(&mut self, cycles: u16) {
+        let cycles = cycles as u64;
+        let (bank, pc, halted) = self.sample;
+
+        self.frame.cycles += cycles;
+        if halted {
+            self.frame.halted += cycles;
+            return;
+        }
+
+        *self.addresses.entry((bank, pc)).or_insert(0) += cycles;
+
+        if let Some(total) = self.cycles.get_mut(self.stack) {
+            *total += cycles;
+        }
+    }
+
+    /// Called at the start of every VBLANK
+    pub(crate) fn end_frame(&mut self) {
+        self.frames.push(std::mem::take(&mut self.frame));
+    }
+
+    /// Completed frames, oldest first
+    pub fn frames(&self) -> &[FrameStats] {
+        &self.frames
+    }
+
+    /// Cycles taken since profiling started, including cycles spent halted
+    pub fn total(&self) -> FrameStats {
+        self.frames.iter().fold(self.frame, |total, frame| FrameStats {
+            cycles: total.cycles + frame.cycles,
+            halted: total.halted + frame.halted,
+        })
+    }
+
+    /// (bank, PC, cycles) of the `count` instructions that took the most cycles
+    pub fn hot_addresses(&self, count: usize) -> Vec<(u16, u16, u64)> {
+        let mut addresses: Vec<_> = self.addresses.iter().map(|(&(bank, pc), &cycles)| (bank, pc, cycles)).collect();
+        addresses.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// (label, cycles) of the `count` functions that took the most cycles, excluding
+    /// the functions they call. Instructions without a label are counted under `??`.
+    pub fn hot_functions(&self, symbols: &Symbols, count: usize) -> Vec<(String, u64)> {
+        let mut functions: HashMap<&str, u64> = HashMap::new();
+        for (&(bank, pc), &cycles) in &self.addresses {
+            let name = symbols.lookup(bank, pc).map_or("??", |(name, _)| name);
+            *functions.entry(name).or_insert(0) += cycles;
+        }
+
+        let mut functions: Vec<_> = functions.into_iter().map(|(name, cycles)| (name.to_string(), cycles)).collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        functions.truncate(count);
+        functions
+    }
+
+    /// Write the call stacks in the folded format used by flame graph tools
+    pub fn write_folded<W: Write>(&self, mut w: W, symbols: Option<&Symbols>) -> std::io::Result<()> {
+        // Stacks can end up with the same names, e.g., if symbols were loaded halfway
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        for (stack, &id) in &self.stacks {
+            // Stacks only seen while halted
+            let cycles = self.cycles[id];
+            if cycles == 0 {
+                continue;
+            }
+
+            let names: Vec<String> = stack.iter().map(|node| node.name(symbols)).collect();
+            let stack = if names.is_empty() { "root".to_string() } else { names.join(";") };
+            *stacks.entry(stack).or_insert(0) += cycles;
+        }
+
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort();
+
+        for (stack, cycles) in stacks {
+            writeln!(w, "{} {}", stack, cycles)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryWrite;
+
+    fn run(cpu: &mut Cpu, profiler: &mut Profiler, steps: usize) {
+        for _ in 0..steps {
+            profiler.begin(cpu);
+            let (cycles, _) = cpu.step();
+            profiler.end(cycles);
+        }
+    }
+
+    #[test]
+    fn profile() {
+        let mut cpu = Cpu::new(true);
+        let program = [
+            0xCD, 0x00, 0x02, // 0x100: call 0x200
+            0x76, // 0x103: halt
+        ];
+        cpu.load_program(&program);
+        // 0x200: nop; ret
+        cpu.memory.controller().rom.write(0x200u16, 0x00u8);
+        cpu.memory.controller().rom.write(0x201u16, 0xC9u8);
+        cpu.registers.SP = 0xFFFE;
+
+        let mut symbols = Symbols::default();
+        symbols.insert(0, 0x100, "Main");
+        symbols.insert(0, 0x200, "Func");
+        cpu.set_symbols(Some(symbols));
+
+        // call, nop, ret, halt
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 4);
+        profiler.end_frame();
+
+        // Halted
+        run(&mut cpu, &mut profiler, 2);
+        profiler.end_frame();
+
+        assert_eq!(profiler.hot_addresses(2), [(0, 0x100, 24), (0, 0x201, 16)]);
+
+        let symbols = cpu.symbols().unwrap();
+        assert_eq!(profiler.hot_functions(symbols, 5), [("Main".to_string(), 28), ("Func".to_string(), 20)]);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, Some(symbols)).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "Main 28\nMain;Func 20\n");
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, None).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "00:0100 28\n00:0100;00:0200 20\n");
+
+        // Main, Main;Func
+        assert_eq!(profiler.stacks.len(), 2);
+
+        let frames = profiler.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], FrameStats { cycles: 48, halted: 0 });
+        assert_eq!(frames[1], FrameStats { cycles: 8, halted: 8 });
+        assert_eq!(frames[1].usage(), 0.0);
+        assert_eq!(profiler.total().cycles, 56);
+
+        // Without symbols, there is no function for PC
+        let mut cpu = Cpu::new(true);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 1);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, None).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "root 4\n");
+    }
+}
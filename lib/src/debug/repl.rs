@@ -0,0 +1,461 @@
+//! GDB-like debugger REPL on stdin/stdout
+use std::fs::File;
+use std::io::Write;
+
+use super::condition::Condition;
+use super::stack::FrameKind;
+use super::watch::{WatchKind, Watchpoint};
+use super::{format_instruction, Breakpoint, Debugger, Frontend, Mode, StopReason};
+use crate::cpu::Cpu;
+use crate::memory::{MemoryRead, MemoryWrite};
+use crate::search::{MemorySearch, SearchFilter, ValueType};
+
+const DEBUG_DUMP_FILE: &str = "dump.txt";
+
+fn parse_u16(input: &str) -> Option<u16> {
+    if let Some(hex) = input.strip_prefix("0x").or_else(|| input.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        input.parse().ok()
+    }
+}
+
+/// Split an optional bank off an address: `[bank:]addr`
+fn parse_bank(input: &str) -> Option<(Option<u16>, &str)> {
+    match input.split_once(':') {
+        Some((bank, addr)) => Some((Some(parse_u16(bank)?), addr)),
+        None => Some((None, input)),
+    }
+}
+
+/// Parse a location: `[bank:]addr`, or a label from the symbols (`label[+offset]`)
+fn parse_location(cpu: &Cpu, input: &str) -> Option<(Option<u16>, u16)> {
+    let location = parse_bank(input).and_then(|(bank, addr)| Some((bank, parse_u16(addr)?)));
+
+    location.or_else(|| {
+        let (bank, addr) = cpu.symbols()?.resolve(input)?;
+        Some((Some(bank), addr))
+    })
+}
+
+/// Parse an address, or a label from the symbols
+fn parse_addr(cpu: &Cpu, input: &str) -> Option<u16> {
+    parse_u16(input).or_else(|| Some(cpu.symbols()?.resolve(input)?.1))
+}
+
+/// Parse a watchpoint range: `[bank:]addr[-end]`, or a label from the symbols
+fn parse_watch_range(cpu: &Cpu, input: &str) -> Option<(Option<u16>, u16, u16)> {
+    let (bank, range) = parse_bank(input)?;
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_u16(start)?, parse_u16(end)?),
+        None => match parse_u16(range) {
+            Some(addr) => (addr, addr),
+            None => {
+                let (bank, addr) = cpu.symbols()?.resolve(input)?;
+                return Some((Some(bank), addr, addr));
+            }
+        },
+    };
+
+    if start > end {
+        return None;
+    }
+
+    Some((bank, start, end))
+}
+
+/// Format `bank:addr`, followed by its label if there are symbols for it
+fn format_location(cpu: &Cpu, bank: u16, addr: u16) -> String {
+    match cpu.symbols().and_then(|symbols| symbols.format(bank, addr)) {
+        Some(label) => format!("{:02X}:{:04X} <{}>", bank, addr, label),
+        None => format!("{:02X}:{:04X}", bank, addr),
+    }
+}
+
+/// Debugger frontend that reads commands from stdin until the CPU is resumed
+#[derive(Default)]
+pub struct Repl {
+    /// Memory search in progress, if any
+    search: Option<MemorySearch>,
+}
+
+impl Repl {
+    /// Number of search results printed after a filter
+    const SEARCH_RESULTS: usize = 20;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run a `search` subcommand
+    fn search(&mut self, cpu: &Cpu, line: &[&str]) {
+        let filter = match (line[1], line.get(2)) {
+            ("new", value_type) => {
+                let value_type = match value_type.map_or(Ok(ValueType::U8), |t| t.parse()) {
+                    Ok(value_type) => value_type,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                };
+
+                let search = MemorySearch::new(&cpu.memory, value_type);
+                println!("{} candidates", search.len());
+                self.search = Some(search);
+                return;
+            }
+            ("list", count) => {
+                let count = count.and_then(|n| n.parse().ok()).unwrap_or(Self::SEARCH_RESULTS);
+                match self.search.as_ref() {
+                    Some(search) => search.results(count).iter().for_each(|result| println!("{}", result)),
+                    None => eprintln!("No search in progress, start one with 'search new'"),
+                }
+                return;
+            }
+            ("eq", Some(value)) => match parse_u16(value) {
+                Some(value) => SearchFilter::Equal(value as u32),
+                None => {
+                    eprintln!("Invalid value specified: {}", value);
+                    return;
+                }
+            },
+            ("changed", None) => SearchFilter::Changed,
+            ("unchanged", None) => SearchFilter::Unchanged,
+            ("inc", None) => SearchFilter::Increased,
+            ("dec", None) => SearchFilter::Decreased,
+            _ => {
+                eprintln!("Usage: search new [8|16|bcd8|bcd16] | eq <value> | changed | unchanged | inc | dec | list [count]");
+                return;
+            }
+        };
+
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => {
+                eprintln!("No search in progress, start one with 'search new'");
+                return;
+            }
+        };
+
+        let count = search.filter(&cpu.memory, filter);
+        println!("{} candidates", count);
+        if count <= Self::SEARCH_RESULTS {
+            for result in search.results(count) {
+                println!("{}", result);
+            }
+        }
+    }
+
+    /// Run a single command. Returns `true` if the CPU should resume.
+    fn command(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, line: &[&str]) -> bool {
+        match line[0] {
+            "" => (),
+            "q" | "quit" => {
+                std::process::exit(0);
+            }
+            "b" if line.len() == 2 || line.len() > 3 && line[2] == "if" => {
+                let (bank, addr) = match parse_location(cpu, line[1]) {
+                    Some(location) => location,
+                    None => {
+                        eprintln!("Invalid address specified: {}", line[1]);
+                        return false;
+                    }
+                };
+
+                let condition = match line.get(3..).map(|cond| Condition::parse(&cond.join(" "))) {
+                    Some(Ok(condition)) => Some(condition),
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return false;
+                    }
+                    None => None,
+                };
+
+                let mut breakpoint = Breakpoint::new(addr);
+                breakpoint.bank = bank;
+                breakpoint.condition = condition;
+                let index = debugger.add_breakpoint(breakpoint);
+                println!("Breakpoint {} at {}", index, debugger.breakpoints()[index]);
+            }
+            "b" => eprintln!("Usage: b [bank:]<addr> [if <condition>]"),
+            "cond" if line.len() >= 2 => {
+                // Set or clear the condition of a breakpoint
+                let condition = if line.len() > 2 {
+                    match Condition::parse(&line[2..].join(" ")) {
+                        Ok(condition) => Some(condition),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return false;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let updated = line[1].parse().ok().and_then(|index| debugger.set_breakpoint_condition(index, condition));
+                if updated.is_none() {
+                    eprintln!("Invalid breakpoint {}", line[1]);
+                }
+            }
+            "cond" => eprintln!("'cond' requires at least 1 argument"),
+            "d" if line.len() == 2 => {
+                // Delete a breakpoint
+                let removed = line[1].parse().ok().and_then(|index| debugger.remove_breakpoint(index));
+                if removed.is_none() {
+                    eprintln!("Invalid breakpoint {}", line[1]);
+                }
+            }
+            "d" => eprintln!("'d' requires at least 1 argument"),
+            cmd @ ("toggle" | "disable" | "enable") if line.len() == 2 => {
+                let index: Option<usize> = line[1].parse().ok();
+                let enabled = index.and_then(|index| debugger.breakpoints().get(index)).map(|b| {
+                    match cmd {
+                        "toggle" => !b.enabled,
+                        cmd => cmd == "enable",
+                    }
+                });
+
+                match (index, enabled) {
+                    (Some(index), Some(enabled)) => {
+                        debugger.set_breakpoint_enabled(index, enabled);
+                    }
+                    _ => eprintln!("Invalid breakpoint {}", line[1]),
+                }
+            }
+            cmd @ ("toggle" | "disable" | "enable") => eprintln!("'{}' requires at least 1 argument", cmd),
+            "watch" if line.len() == 3 => {
+                let kind = match line[1] {
+                    "r" => WatchKind::Read,
+                    "w" => WatchKind::Write,
+                    "c" => WatchKind::Change,
+                    "a" => WatchKind::Access,
+                    kind => {
+                        eprintln!("Invalid watchpoint kind: {}", kind);
+                        return false;
+                    }
+                };
+
+                match parse_watch_range(cpu, line[2]) {
+                    Some((bank, start, end)) => {
+                        let mut watchpoint = Watchpoint::new(start, end, kind);
+                        watchpoint.bank = bank;
+                        let index = cpu.memory.watchpoints_mut().add(watchpoint);
+                        println!("Watchpoint {} on {}", index, line[2]);
+                    }
+                    None => eprintln!("Invalid address range specified: {}", line[2]),
+                }
+            }
+            "watch" => eprintln!("'watch' requires 2 arguments"),
+            "dw" if line.len() == 2 => {
+                // Delete a watchpoint
+                let removed = line[1].parse().ok().and_then(|index| cpu.memory.watchpoints_mut().remove(index));
+                if removed.is_none() {
+                    eprintln!("Invalid watchpoint {}", line[1]);
+                }
+            }
+            "dw" => eprintln!("'dw' requires at least 1 argument"),
+            "dump" if line.len() == 2 => {
+                if line[1] == "0" {
+                    debugger.set_instruction_dump(None);
+                    println!("Disabled instruction dumping");
+                } else {
+                    match File::create(DEBUG_DUMP_FILE) {
+                        Ok(f) => {
+                            debugger.set_instruction_dump(Some(Box::new(f)));
+                            println!("Dumping instructions to {}", DEBUG_DUMP_FILE);
+                        }
+                        Err(e) => eprintln!("Failed to create {}: {}", DEBUG_DUMP_FILE, e),
+                    }
+                }
+            }
+            "dump" => eprintln!("'dump' requires at least 1 argument"),
+            "h" | "hist" => {
+                let count = line.get(1).and_then(|n| n.parse().ok()).unwrap_or(5);
+
+                // Print the last `count` instructions we've executed, skipping the
+                // one we stopped at
+                let history = debugger.history();
+                let executed = history.len().saturating_sub(1);
+                for (inst, pc, bank) in history.take(executed).skip(executed.saturating_sub(count)) {
+                    println!("{}", format_instruction(cpu, inst, *pc, *bank));
+                }
+            }
+            "count" => {
+                println!("{}", debugger.executed());
+            }
+            "reset" => {
+                // Reset the CPU
+                cpu.reset();
+                debugger.clear_history();
+                println!("CPU reset");
+            }
+            "l" | "list" => {
+                // Number of instructions to disassemble, starting from address below
+                let count = line.get(1).and_then(|n| n.parse().ok()).unwrap_or(5);
+
+                // Start address - defaults to PC
+                let addr = match line.get(2) {
+                    Some(addr) => match parse_addr(cpu, addr) {
+                        Some(addr) => addr,
+                        None => {
+                            eprintln!("Invalid address specified: {}", addr);
+                            return false;
+                        }
+                    },
+                    None => cpu.registers.PC,
+                };
+
+                for (inst, addr) in cpu.disassemble(count, Some(addr)) {
+                    let (_, bank) = cpu.memory.memory_info(addr);
+                    println!("{}", format_instruction(cpu, &inst, addr, bank));
+                }
+            }
+            "s" | "step" => {
+                match line.get(1).map(|n| n.parse()) {
+                    Some(Ok(n)) => debugger.set_mode(Mode::StepN(n)),
+                    Some(Err(_)) => {
+                        eprintln!("Invalid count: {}", line[1]);
+                        return false;
+                    }
+                    None => debugger.set_mode(Mode::Step),
+                }
+                return true;
+            }
+            "n" | "next" => {
+                debugger.step_over(cpu);
+                return true;
+            }
+            "finish" => {
+                if debugger.finish(cpu) {
+                    return true;
+                }
+
+                eprintln!("No frame to finish");
+            }
+            "bt" | "backtrace" => {
+                let (_, bank) = cpu.memory.memory_info(cpu.registers.PC);
+                println!("#0 {}", format_location(cpu, bank, cpu.registers.PC));
+
+                for (i, frame) in cpu.call_stack().frames().iter().rev().enumerate() {
+                    let caller = format_location(cpu, frame.call_bank, frame.call_site);
+                    match frame.kind {
+                        FrameKind::Interrupt(_) => println!("#{} {}, {}", i + 1, caller, frame.kind),
+                        kind => {
+                            let target = format_location(cpu, frame.target_bank, frame.target);
+                            println!("#{} {}, {} to {}", i + 1, caller, kind, target);
+                        }
+                    }
+                }
+            }
+            "c" | "continue" if line.len() == 2 => {
+                // Continue executing until address is hit (breakpoint shortcut)
+                match parse_addr(cpu, line[1]) {
+                    Some(addr) => {
+                        debugger.set_mode(Mode::ContinuePc(addr));
+                        return true;
+                    }
+                    None => eprintln!("Invalid address specified"),
+                }
+            }
+            "c" | "continue" => eprintln!("'c' requires at least 1 argument"),
+            "r" => {
+                match line.get(1).map(|n| n.parse()) {
+                    Some(Ok(n)) => debugger.set_mode(Mode::ContinueN(n)),
+                    Some(Err(_)) => {
+                        eprintln!("Invalid count: {}", line[1]);
+                        return false;
+                    }
+                    None => debugger.set_mode(Mode::Continue),
+                }
+                return true;
+            }
+            "p" if line.len() == 2 => {
+                match parse_addr(cpu, line[1]) {
+                    Some(addr) => {
+                        let value: u8 = cpu.memory.read(addr);
+                        println!("{:#X}", value);
+                    }
+                    None => eprintln!("Invalid address specified: {}", line[1]),
+                }
+            }
+            "p" => eprintln!("'p' requires at least 1 argument"),
+            "w" if line.len() == 3 => {
+                let addr = parse_addr(cpu, line[1]);
+                let value = parse_u16(line[2]).filter(|v| *v <= 0xFF);
+
+                match (addr, value) {
+                    (Some(addr), Some(value)) => cpu.memory.write(addr, value as u8),
+                    (None, _) => eprintln!("Invalid address specified: {}", line[1]),
+                    (_, None) => eprintln!("Invalid value specified: {}", line[2]),
+                }
+            }
+            "w" => eprintln!("'w' requires at least 2 arguments"),
+            "info" if line.len() == 2 => {
+                match line[1] {
+                    "r" | "reg" | "registers" => {
+                        println!("{}", cpu.registers);
+                    }
+                    "b" | "break" | "breakpoints" => {
+                        for (i, b) in debugger.breakpoints().iter().enumerate() {
+                            println!("{}: {}", i, b);
+                        }
+                    }
+                    "w" | "watch" | "watchpoints" => {
+                        for (i, w) in cpu.memory.watchpoints().list().iter().enumerate() {
+                            let bank = w.bank.map_or("any".to_string(), |bank| bank.to_string());
+                            println!(
+                                "{}: {:?} {:#06X}-{:#06X}, bank = {}, enabled = {}",
+                                i, w.kind, w.start, w.end, bank, w.enabled
+                            );
+                        }
+                    }
+                    unknown => eprintln!("Unknown option for 'info': {}", unknown),
+                }
+            }
+            "info" => eprintln!("'info' requires at least 1 argument"),
+            "search" if line.len() >= 2 => self.search(cpu, line),
+            "search" => eprintln!("'search' requires at least 1 argument"),
+            unknown => eprintln!("Unknown command: {}", unknown),
+        }
+
+        false
+    }
+}
+
+impl Frontend for Repl {
+    fn stopped(&mut self, debugger: &mut Debugger, cpu: &mut Cpu, reason: StopReason) {
+        if reason != StopReason::Step {
+            println!("{}", reason);
+        }
+
+        // Print the next instruction
+        let (inst, _, _) = cpu.fetch(None);
+        let pc = cpu.registers.PC;
+        let (_, bank) = cpu.memory.memory_info(pc);
+        println!("{}", format_instruction(cpu, &inst, pc, bank));
+
+        loop {
+            print!("gbcdbg> ");
+            let _ = std::io::stdout().flush();
+
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                // Treat EOF like "quit"
+                Ok(0) => std::process::exit(0),
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("Failed to read command: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            let line: Vec<&str> = line.split_whitespace().collect();
+            let line = if line.is_empty() { vec![""] } else { line };
+
+            if self.command(debugger, cpu, &line) {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Shadow call stack
+//!
+//! The CPU pushes a frame on every CALL, RST and interrupt dispatch, and pops frames on
+//! RET and RETI. Frames are popped based on SP rather than one per return, so that the
+//! stack stays in sync with code that drops return addresses (e.g., `pop hl; jp hl`).
+
+/// What created a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Call,
+    Rst,
+
+    /// Interrupt dispatch, to the given ISR address. The address is 0x0000 if the
+    /// dispatch was cancelled.
+    Interrupt(u16),
+}
+
+impl std::fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Call => write!(f, "call"),
+            Self::Rst => write!(f, "rst"),
+            Self::Interrupt(0x40) => write!(f, "VBlank interrupt"),
+            Self::Interrupt(0x48) => write!(f, "STAT interrupt"),
+            Self::Interrupt(0x50) => write!(f, "Timer interrupt"),
+            Self::Interrupt(0x58) => write!(f, "Serial interrupt"),
+            Self::Interrupt(0x60) => write!(f, "Joypad interrupt"),
+            Self::Interrupt(_) => write!(f, "cancelled interrupt"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+
+    /// Address of the CALL or RST instruction, or the address that was interrupted
+    pub call_site: u16,
+
+    /// Bank mapped at `call_site` when the frame was created
+    pub call_bank: u16,
+
+    /// Address that was called
+    pub target: u16,
+
+    /// Bank mapped at `target` when the frame was created
+    pub target_bank: u16,
+
+    /// Address the frame returns to
+    pub return_addr: u16,
+
+    /// SP right after the return address was pushed
+    pub sp: u16,
+}
+
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    /// Frames beyond this depth drop the outermost ones (e.g., for recursive code that
+    /// never returns)
+    const MAX_DEPTH: usize = 1024;
+
+    /// Frames, outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn push(&mut self, frame: Frame) {
+        // A frame pushed at or above an existing one means that one is gone (e.g., SP
+        // was reloaded)
+        self.pop_to(frame.sp);
+
+        if self.frames.len() == Self::MAX_DEPTH {
+            self.frames.remove(0);
+        }
+
+        self.frames.push(frame);
+    }
+
+    /// Pop all frames whose return address lies below `sp`, i.e., was popped off the stack
+    pub(crate) fn pop_to(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            call_site: 0,
+            call_bank: 0,
+            target: 0,
+            target_bank: 0,
+            return_addr: 0,
+            sp,
+        }
+    }
+
+    #[test]
+    fn pop_to() {
+        let mut stack = CallStack::default();
+        stack.push(frame(0xFFFC));
+        stack.push(frame(0xFFFA));
+        stack.push(frame(0xFFF8));
+
+        // RET from the innermost frame
+        stack.pop_to(0xFFFA);
+        assert_eq!(stack.depth(), 2);
+
+        // Return address dropped, then RET from the outer frame
+        stack.pop_to(0xFFFE);
+        assert_eq!(stack.depth(), 0);
+
+        // SP reloaded higher than existing frames
+        stack.push(frame(0xDFF0));
+        stack.push(frame(0xDFFE));
+        assert_eq!(stack.frames(), &[frame(0xDFFE)]);
+    }
+}
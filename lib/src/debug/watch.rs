@@ -0,0 +1,222 @@
+//! Memory watchpoints
+//!
+//! Watchpoints live on the `MemoryBus`, and are checked on every read and write made by
+//! the CPU or by DMA. The first hit is kept until the debugger picks it up before the
+//! next instruction.
+use std::cell::Cell;
+
+/// Kind of access that triggers a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+
+    /// A write that changes the value in memory
+    Change,
+
+    /// Any read or write
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    /// First address watched
+    pub start: u16,
+
+    /// Last address watched (inclusive)
+    pub end: u16,
+
+    /// Only trigger while this bank is mapped at the address, if set
+    pub bank: Option<u16>,
+
+    pub kind: WatchKind,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    /// Watch the given range of addresses (inclusive) in any bank
+    pub fn new(start: u16, end: u16, kind: WatchKind) -> Self {
+        Self {
+            start,
+            end,
+            bank: None,
+            kind,
+            enabled: true,
+        }
+    }
+
+    fn matches(&self, addr: u16, bank: u16) -> bool {
+        self.enabled
+            && (self.start..=self.end).contains(&addr)
+            && self.bank.is_none_or(|b| b == bank)
+    }
+}
+
+/// A watchpoint that was triggered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    /// Index of the watchpoint
+    pub index: usize,
+
+    /// Kind of the watchpoint
+    pub kind: WatchKind,
+
+    pub addr: u16,
+
+    /// Bank mapped at `addr` during the access
+    pub bank: u16,
+
+    /// Address of the instruction that made the access. For DMA, this is the PC at
+    /// the time of the copy.
+    pub pc: u16,
+
+    /// Value before the access. Same as `new` for reads.
+    pub old: u8,
+
+    /// Value read or written
+    pub new: u8,
+
+    pub write: bool,
+
+    /// The access was made by OAM DMA or HDMA
+    pub dma: bool,
+}
+
+impl std::fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = if self.dma { " (DMA)" } else { "" };
+
+        if self.write {
+            write!(
+                f,
+                "Watchpoint {}: write to {:02X}:{:04X}{}, {:#04X} -> {:#04X} at PC {:#06X}",
+                self.index, self.bank, self.addr, source, self.old, self.new, self.pc,
+            )
+        } else {
+            write!(
+                f,
+                "Watchpoint {}: read from {:02X}:{:04X}{}, value {:#04X} at PC {:#06X}",
+                self.index, self.bank, self.addr, source, self.new, self.pc,
+            )
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+
+    /// Only accesses made while armed trigger watchpoints. This keeps instruction
+    /// fetches and the debugger's own reads out.
+    armed: Cell<bool>,
+
+    /// Address of the instruction being executed
+    pc: Cell<u16>,
+
+    /// Set while DMA is running
+    dma: Cell<bool>,
+
+    /// First hit since the last call to `take_hit`
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    /// Add a watchpoint and return its index
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.list.push(watchpoint);
+        self.list.len() - 1
+    }
+
+    /// Delete the watchpoint with the given index
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.list.len() {
+            Some(self.list.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Enable or disable the watchpoint with the given index. Returns `None` if there
+    /// is no such watchpoint.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> Option<()> {
+        self.list.get_mut(index).map(|w| w.enabled = enabled)
+    }
+
+    /// Returns the first hit since the last call, if any
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    /// Start or stop triggering watchpoints, on behalf of the instruction at `pc`
+    #[inline]
+    pub(crate) fn arm(&self, armed: bool, pc: u16) {
+        self.armed.set(armed && !self.list.is_empty());
+        self.pc.set(pc);
+    }
+
+    #[inline]
+    pub(crate) fn set_dma(&self, dma: bool) {
+        self.dma.set(dma);
+    }
+
+    #[inline]
+    pub(crate) fn armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    /// Returns `true` if a write to `addr` needs to be reported
+    #[inline]
+    pub(crate) fn watches_write(&self, addr: u16) -> bool {
+        self.armed()
+            && self.list.iter().any(|w| w.kind != WatchKind::Read && w.enabled && (w.start..=w.end).contains(&addr))
+    }
+
+    pub(crate) fn on_read(&self, addr: u16, bank: u16, value: u8) {
+        let index = self.list.iter().position(|w| {
+            matches!(w.kind, WatchKind::Read | WatchKind::Access) && w.matches(addr, bank)
+        });
+
+        if let Some(index) = index {
+            self.record(index, addr, bank, value, value, false);
+        }
+    }
+
+    pub(crate) fn on_write(&self, addr: u16, bank: u16, old: u8, new: u8) {
+        let index = self.list.iter().position(|w| {
+            let kind = match w.kind {
+                WatchKind::Write | WatchKind::Access => true,
+                WatchKind::Change => old != new,
+                WatchKind::Read => false,
+            };
+
+            kind && w.matches(addr, bank)
+        });
+
+        if let Some(index) = index {
+            self.record(index, addr, bank, old, new, true);
+        }
+    }
+
+    fn record(&self, index: usize, addr: u16, bank: u16, old: u8, new: u8, write: bool) {
+        // Keep the first hit until the debugger picks it up
+        if self.hit.get().is_some() {
+            return;
+        }
+
+        self.hit.set(Some(WatchHit {
+            index,
+            kind: self.list[index].kind,
+            addr,
+            bank,
+            pc: self.pc.get(),
+            old,
+            new,
+            write,
+            dma: self.dma.get(),
+        }));
+    }
+}
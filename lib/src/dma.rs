@@ -70,7 +70,7 @@ impl DmaController {
     }
 
     fn oam_dma(&mut self, mut cycles: u16, memory: &mut MemoryBus) {
-        let dma_reg = memory.read(Self::DMA_ADDR);
+        let dma_reg = memory.ppu().read(Self::DMA_ADDR);
         let source_start_addr = (dma_reg as u16) << 8;
 
         // OAM DMA transfers 1 byte every 4 clock cycles
@@ -89,6 +89,10 @@ impl DmaController {
 
             // Write directly to OAM in case it is locked
             let oam_index = (dest_addr - Ppu::OAM_START_ADDR) as usize;
+
+            #[cfg(feature = "debug")]
+            memory.watch_write(dest_addr, memory.ppu().oam[oam_index], data);
+
             memory.ppu_mut().oam[oam_index] = data;
 
             self.oam_dma_counter += 1;
@@ -105,18 +109,19 @@ impl DmaController {
     fn hdma(&mut self, cycles: u16, memory: &mut MemoryBus) -> u16 {
         let speed = memory.io().speed();
 
-        // Determine source and destination addresses
-        let source_addr_upper = memory.read(0xFF51) as u16;
-        let source_addr_lower = memory.read(0xFF52) as u16;
-        let dest_addr_upper = memory.read(0xFF53) as u16;
-        let dest_addr_lower = memory.read(0xFF54) as u16;
+        // Determine source and destination addresses. The HDMA registers are write-only
+        // on the bus, so read them directly.
+        let source_addr_upper = memory.io().read(0xFF51) as u16;
+        let source_addr_lower = memory.io().read(0xFF52) as u16;
+        let dest_addr_upper = memory.io().read(0xFF53) as u16;
+        let dest_addr_lower = memory.io().read(0xFF54) as u16;
 
         let source_addr = source_addr_upper << 8 | source_addr_lower;
         let source_addr = source_addr & 0xFFF0; // lower 4 bits are ignored
         let dest_addr = dest_addr_upper << 8 | dest_addr_lower;
         let dest_addr = 0x8000 + (dest_addr & 0x1FF0); // only bits 12-4 are taken
 
-        let start_reg = memory.read(0xFF55);
+        let start_reg = memory.io().read(0xFF55);
 
         if !self.hdma_active {
             // New transfer is being started
@@ -192,6 +197,9 @@ impl DmaController {
             for offset in chunk_start..chunk_end {
                 let byte = memory.read(source_addr + offset);
 
+                #[cfg(feature = "debug")]
+                memory.watch_write(dest_addr + offset, memory.ppu().vram().read(dest_addr + offset), byte);
+
                 // Write directly to VRAM to avoid being blocked if at boundary
                 // of OAM read mode
                 let vram = memory.ppu_mut().vram_mut();
@@ -481,6 +481,9 @@ pub enum Instruction {
 
     /// Pop two bytes from stack & jump to the address, **then** enable interrupts.
     RetI,
+
+    /// One of the 11 unused opcodes. Executing it locks up the CPU until reset.
+    Illegal(u8),
 }
 
 /// Number of cycles required to execute to an instruction.
@@ -806,7 +809,7 @@ impl Instruction {
             0xFB => (Ei, 1, 4.into()),
 
             0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
-                panic!("Invalid instruction: {}", data[0]);
+                (Illegal(data[0]), 1, 4.into())
             }
         };
 
@@ -923,6 +926,18 @@ impl Instruction {
         }
     }
 
+    /// Address this instruction jumps to when taken, if it is known statically.
+    ///
+    /// `addr` is the address of the instruction, which relative jumps are based on.
+    pub fn jump_target(&self, addr: u16) -> Option<u16> {
+        match *self {
+            Self::Jp { addr, .. } | Self::Call { addr, .. } => Some(addr),
+            Self::Jr { offset, .. } => Some(addr.wrapping_add(2).wrapping_add(offset as u16)),
+            Self::Rst { offset } => Some(offset as u16),
+            _ => None,
+        }
+    }
+
     /// Helper function for the repetitive CB opcodes.
     ///
     /// Returns the dst `Arg` and value required.
@@ -1045,6 +1060,7 @@ impl std::fmt::Display for Instruction {
                 }
             }
             RetI => write!(f, "reti"),
+            Illegal(opcode) => write!(f, "db {:#04X}", opcode),
         }
     }
 }
@@ -1135,4 +1151,29 @@ mod test {
             assert_eq!(expected_cycles, &cycles);
         }
     }
+
+    #[test]
+    fn decode_illegal() {
+        const ILLEGAL: &[u8] = &[0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+        for opcode in ILLEGAL {
+            let (inst, size, _) = Instruction::decode([*opcode, 0x00, 0x00]);
+            assert_eq!(inst, Illegal(*opcode));
+            assert_eq!(size, 1);
+        }
+
+        assert_eq!(Illegal(0xDD).to_string(), "db 0xDD");
+    }
+
+    #[test]
+    fn jump_target() {
+        let target = |input: [u8; 3]| Instruction::decode(input).0.jump_target(0x150);
+
+        assert_eq!(target([0xCD, 0x2C, 0x4A]), Some(0x4A2C));
+        assert_eq!(target([0xC2, 0x00, 0x02]), Some(0x0200));
+        assert_eq!(target([0x18, 0xFE, 0x00]), Some(0x150));
+        assert_eq!(target([0x20, 0x10, 0x00]), Some(0x162));
+        assert_eq!(target([0xEF, 0x00, 0x00]), Some(0x28));
+        assert_eq!(target([0xE9, 0x00, 0x00]), None);
+    }
 }
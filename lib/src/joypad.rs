@@ -20,6 +20,29 @@ impl JoypadInput {
         }
     }
 
+    /// Bit for this input in a joypad state bitmask.
+    ///
+    /// Directions use the lower nibble and buttons use the upper nibble.
+    pub fn mask(&self) -> u8 {
+        if self.is_button() {
+            1 << (self.to_bit() + 4)
+        } else {
+            1 << self.to_bit()
+        }
+    }
+
+    /// All inputs, in bitmask order
+    pub const ALL: [JoypadInput; 8] = [
+        Self::Right,
+        Self::Left,
+        Self::Up,
+        Self::Down,
+        Self::A,
+        Self::B,
+        Self::Select,
+        Self::Start,
+    ];
+
     fn is_button(&self) -> bool {
         match self {
             Self::A | Self::B | Self::Select | Self::Start => true,
@@ -41,6 +64,20 @@ pub enum JoypadEvent {
     Down(JoypadInput),
 }
 
+/// Apply a list of events to a joypad state bitmask.
+///
+/// Each set bit in the state is a pressed input (see `JoypadInput::mask`).
+pub fn apply_events(mut state: u8, events: &[JoypadEvent]) -> u8 {
+    for event in events {
+        match event {
+            JoypadEvent::Down(input) => state |= input.mask(),
+            JoypadEvent::Up(input) => state &= !input.mask(),
+        }
+    }
+
+    state
+}
+
 impl JoypadEvent {
     fn input(&self) -> JoypadInput {
         match self {
@@ -111,6 +148,35 @@ impl Joypad {
         }
     }
 
+    /// Returns the pressed inputs as a bitmask (see `JoypadInput::mask`).
+    pub fn state(&self) -> u8 {
+        (!self.directions & 0x0F) | (!self.buttons & 0x0F) << 4
+    }
+
+    /// Replace the pressed inputs with the given bitmask.
+    ///
+    /// Returns `true` if an interrupt should be triggered.
+    pub fn set_state(&mut self, state: u8) -> bool {
+        let prev = self.state();
+        let mut interrupt = false;
+
+        for input in JoypadInput::ALL.iter() {
+            let mask = input.mask();
+
+            if state & mask != prev & mask {
+                let event = if state & mask != 0 {
+                    JoypadEvent::Down(*input)
+                } else {
+                    JoypadEvent::Up(*input)
+                };
+
+                interrupt |= self.handle_event(&event);
+            }
+        }
+
+        interrupt
+    }
+
     fn is_interrupt_required(&self, input: JoypadInput) -> bool {
         match self.selection {
             JoypadSelection::Buttons => input.is_button(),
@@ -139,3 +205,34 @@ impl Joypad {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_bitmask() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.state(), 0);
+
+        let events = [
+            JoypadEvent::Down(JoypadInput::A),
+            JoypadEvent::Down(JoypadInput::Left),
+            JoypadEvent::Down(JoypadInput::Start),
+            JoypadEvent::Up(JoypadInput::Start),
+        ];
+
+        let state = apply_events(0, &events);
+        assert_eq!(state, JoypadInput::A.mask() | JoypadInput::Left.mask());
+
+        // Select buttons, then apply the state
+        joypad.write(0x10);
+        assert!(joypad.set_state(state));
+        assert_eq!(joypad.state(), state);
+        assert_eq!(joypad.read(), 0x0E);
+
+        // Releasing everything never fires an interrupt
+        assert!(!joypad.set_state(0));
+        assert_eq!(joypad.read(), 0x0F);
+    }
+}
@@ -1,37 +1,71 @@
+#[cfg(feature = "fs")]
 use std::fs::File;
+#[cfg(feature = "fs")]
 use std::path::Path;
 
 pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
 mod cpu;
 mod dma;
 pub mod error;
 mod instructions;
 pub mod joypad;
 mod memory;
+#[cfg(feature = "save")]
+pub mod movie;
+pub mod patch;
 pub mod ppu;
 mod registers;
+#[cfg(feature = "save")]
+pub mod rewind;
 mod rtc;
+pub mod search;
+pub mod storage;
+pub mod symbols;
 mod timer;
+pub mod trace;
 
 #[cfg(feature = "debug")]
 pub mod debug;
 
-pub use cpu::Cpu;
+pub use cpu::{Cpu, Event};
 use cpu::Interrupt;
 use cartridge::Cartridge;
+use cdl::CodeDataLog;
+use cheats::{Cheat, CheatCode};
 pub use error::{Error, Result};
+pub use registers::Reg16;
+use registers::RegisterOps;
 use joypad::JoypadEvent;
-use memory::MemoryWrite;
+use memory::{MemoryRead, MemoryWrite};
 use ppu::FrameBuffer;
+use search::{MemorySearch, SearchFilter, ValueType};
+use symbols::Symbols;
+use trace::Tracer;
 
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 /// Gameboy
 pub struct Gameboy {
     cpu: Cpu,
 
+    /// Cheats, in the order they were added
+    #[cfg_attr(feature = "save", serde(skip))]
+    cheats: Vec<Cheat>,
+
     #[cfg(feature = "debug")]
     #[cfg_attr(feature = "save", serde(skip))]
     debugger: debug::Debugger,
+
+    /// Drives the debugger whenever it stops the CPU. The debugger is inactive without one.
+    #[cfg(feature = "debug")]
+    #[cfg_attr(feature = "save", serde(skip))]
+    debug_frontend: Option<Box<dyn debug::Frontend + Send>>,
+
+    /// Attributes cycles to addresses, call stacks and frames, if enabled
+    #[cfg(feature = "debug")]
+    #[cfg_attr(feature = "save", serde(skip))]
+    profiler: Option<debug::profile::Profiler>,
 }
 
 impl Gameboy {
@@ -44,19 +78,32 @@ impl Gameboy {
     ///
     /// If no ROM is provided, the emulator will boot into the CGB BIOS ROM. You can
     /// use `Self::insert` to load a cartridge later.
+    #[cfg(feature = "fs")]
     pub fn init<P: AsRef<Path>>(rom_path: P, boot_rom: bool, trace: bool) -> Result<Self> {
         let cartridge = Cartridge::from_file(rom_path, boot_rom)?;
+        Self::from_cartridge(cartridge, trace)
+    }
+
+    /// Initialize the emulator with a `Cartridge`.
+    ///
+    /// This is useful to make changes to the cartridge (e.g., `Cartridge::patch`)
+    /// before booting it.
+    pub fn from_cartridge(cartridge: Cartridge, trace: bool) -> Result<Self> {
         let cpu = Cpu::from_cartridge(cartridge, trace)?;
 
         #[cfg(feature = "debug")]
         let gameboy = Self {
             cpu,
+            cheats: Vec::new(),
             debugger: debug::Debugger::new(),
+            debug_frontend: None,
+            profiler: None,
         };
 
         #[cfg(not(feature = "debug"))]
         let gameboy = Self {
             cpu,
+            cheats: Vec::new(),
         };
 
         Ok(gameboy)
@@ -76,16 +123,34 @@ impl Gameboy {
         let speed = self.cpu.speed;
 
         #[cfg(feature = "debug")]
-        // If the debugger is triggered, step into the REPL.
-        if self.debugger.triggered(&self.cpu) {
-            self.debugger.repl(&mut self.cpu);
+        // If the debugger stops the CPU, hand control to the frontend until it resumes.
+        if let Some(frontend) = self.debug_frontend.as_mut() {
+            let mut reason = self.debugger.check(&self.cpu);
+            if reason.is_none() && frontend.interrupted() {
+                self.debugger.set_mode(debug::Mode::Step);
+                reason = Some(debug::StopReason::Interrupted);
+            }
+
+            if let Some(reason) = reason {
+                frontend.stopped(&mut self.debugger, &mut self.cpu, reason);
+            }
         }
 
         // Execute a step of the CPU
         //
         // This handles interrupt processing and DMA internally.
+        #[cfg(feature = "debug")]
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.begin(&self.cpu);
+        }
+
         let (cycles_taken, _inst) = self.cpu.step();
 
+        #[cfg(feature = "debug")]
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end(cycles_taken);
+        }
+
         let mut interrupts = Vec::new();
 
         // Update the memory bus
@@ -96,17 +161,27 @@ impl Gameboy {
         // 2. Timer
         // 3. Serial
         // 4. RTC (if present)
-        self.cpu.memory.step(cycles_taken, speed, &mut interrupts);
+        //
+        // If the system clock is stopped, only the RTC keeps running.
+        if self.cpu.clock_stopped() {
+            self.cpu.memory.step_rtc(cycles_taken, speed);
+        } else {
+            self.cpu.memory.step(cycles_taken, speed, &mut interrupts);
+        }
 
         // Trigger any pending interrupts
         for interrupt in interrupts {
-            self.cpu.trigger_interrupt(interrupt);
-        }
+            if let Interrupt::Vblank = interrupt {
+                self.apply_game_shark();
+                self.cpu.trace_frame();
+
+                #[cfg(feature = "debug")]
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.end_frame();
+                }
+            }
 
-        if self.cpu.stopped {
-            // Reset DIV on speed switch
-            self.cpu.memory.write(0xFF04u16, 0u8);
-            self.cpu.stopped = false;
+            self.cpu.trigger_interrupt(interrupt);
         }
 
         (self.cpu.memory.ppu_mut().frame_buffer(), cycles_taken as u32)
@@ -126,6 +201,28 @@ impl Gameboy {
         self.update_joypad(joypad_events);
     }
 
+    /// Run the Gameboy until the PPU completes the next frame (i.e., start of VBLANK).
+    ///
+    /// If the LCD is off, no frame is ever produced: in that case, this returns `None`
+    /// once two frames' worth of cycles have elapsed.
+    pub fn next_frame(&mut self) -> Option<&FrameBuffer> {
+        let mut cycle = 0;
+        let num_cycles = Self::cycles_per_frame(self.cpu.speed) * 2;
+        let mut ready = false;
+
+        while !ready && cycle < num_cycles {
+            let (frame_buffer, cycles_taken) = self.step();
+            ready = frame_buffer.is_some();
+            cycle += cycles_taken;
+        }
+
+        if ready {
+            Some(self.cpu.memory.ppu().current_frame())
+        } else {
+            None
+        }
+    }
+
     pub fn update_joypad(&mut self, joypad_events: Option<&[JoypadEvent]>) {
         if let Some(events) = joypad_events {
             for event in events {
@@ -136,39 +233,273 @@ impl Gameboy {
         }
     }
 
+    /// Returns the pressed joypad inputs as a bitmask (see `JoypadInput::mask`).
+    pub fn joypad_state(&mut self) -> u8 {
+        self.cpu.memory.joypad().state()
+    }
+
+    /// Replace the pressed joypad inputs with the given bitmask.
+    pub fn set_joypad_state(&mut self, state: u8) {
+        if self.cpu.memory.joypad().set_state(state) {
+            self.cpu.trigger_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    /// Add a Game Genie or GameShark cheat, enabled.
+    ///
+    /// Returns the index of the new cheat.
+    pub fn add_cheat(&mut self, code: &str) -> Result<usize> {
+        self.cheats.push(Cheat::parse(code)?);
+        self.update_game_genie();
+        Ok(self.cheats.len() - 1)
+    }
+
+    /// Remove the cheat at the given index.
+    pub fn remove_cheat(&mut self, index: usize) -> Option<Cheat> {
+        if index >= self.cheats.len() {
+            return None;
+        }
+
+        let cheat = self.cheats.remove(index);
+        self.update_game_genie();
+        Some(cheat)
+    }
+
+    /// Enable or disable the cheat at the given index.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(cheat) = self.cheats.get_mut(index) {
+            cheat.enabled = enabled;
+            self.update_game_genie();
+        }
+    }
+
+    /// Set the symbols used to show addresses as labels in traces and in the debugger
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.cpu.set_symbols(symbols);
+    }
+
+    /// Start logging how ROM bytes are used (see `cdl`), if not already logging
+    pub fn enable_cdl(&mut self) -> &mut CodeDataLog {
+        let controller = self.cpu.memory.controller();
+        let rom_size = controller.rom.len();
+        let ram_size = controller.ram.as_ref().map_or(0, |ram| ram.data().len());
+        controller.cdl.get_or_insert_with(|| CodeDataLog::new(rom_size, ram_size))
+    }
+
+    /// Code/data log, if enabled
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cpu.memory.cdl()
+    }
+
+    /// Start a memory search over WRAM, HRAM and cartridge RAM, with all addresses as
+    /// candidates (see `search`)
+    pub fn start_search(&self, value_type: ValueType) -> MemorySearch {
+        MemorySearch::new(&self.cpu.memory, value_type)
+    }
+
+    /// Compare memory to the last snapshot of a search, and drop the candidates that do
+    /// not match. Returns the number of candidates left.
+    pub fn filter_search(&self, search: &mut MemorySearch, filter: SearchFilter) -> usize {
+        search.filter(&self.cpu.memory, filter)
+    }
+
+    /// Trace the instructions executed, or stop tracing if `None`
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Pass the enabled Game Genie codes on to the cartridge controller
+    fn update_game_genie(&mut self) {
+        let codes = self.cheats.iter().filter(|c| c.enabled).filter_map(|c| match c.kind {
+            CheatCode::GameGenie(code) => Some(code),
+            _ => None,
+        });
+
+        self.cpu.memory.controller().game_genie = codes.collect();
+    }
+
+    /// Write all enabled GameShark codes to RAM
+    fn apply_game_shark(&mut self) {
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            if let CheatCode::GameShark(code) = &cheat.kind {
+                self.cpu.memory.apply_game_shark(code);
+            }
+        }
+    }
+
     /// Insert a new cartridge and reset the emulator
+    #[cfg(feature = "fs")]
     pub fn insert<P: AsRef<Path>>(&mut self, rom_path: P, boot_rom: bool) -> Result<()> {
         let cartridge = Cartridge::from_file(rom_path, boot_rom)?;
         self.cpu = Cpu::from_cartridge(cartridge, false)?;
+        self.update_game_genie();
         Ok(())
     }
 
     /// Load a Gameboy from a save state file on disk.
-    #[cfg(feature = "save")]
+    #[cfg(all(feature = "save", feature = "fs"))]
     pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(rom_path: P, save_path: Q) -> Result<Self> {
         let file = File::open(save_path)?;
         let mut gameboy: Self = bincode::deserialize_from(&file)?;
 
         // Load ROM and any other cartridge-related info
-        gameboy.cpu.memory.controller().load(rom_path)?;
+        let mut cartridge = Cartridge::from_file(rom_path, false)?;
+        gameboy.cpu.memory.controller().load(&mut cartridge)?;
 
         Ok(gameboy)
     }
 
     /// Dump the current state of this Gameboy to a file on disk.
-    #[cfg(feature = "save")]
+    #[cfg(all(feature = "save", feature = "fs"))]
     pub fn dump<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let mut file = File::create(path)?;
         bincode::serialize_into(&mut file, self)?;
         Ok(())
     }
 
+    /// Serialize the current state of this Gameboy into a buffer.
+    ///
+    /// The ROM itself is not part of the state. Use `Self::load_state` to restore
+    /// the state on a Gameboy running the same ROM.
+    #[cfg(feature = "save")]
+    pub fn save_state(&self) -> Vec<u8> {
+        serialize(self)
+    }
+
+    /// Restore a state produced by `Self::save_state`.
+    ///
+    /// The currently loaded ROM, boot ROM and any backing files (save RAM, RTC, trace)
+    /// are kept as-is.
+    ///
+    /// The restored save RAM and RTC are written to their storage.
+    #[cfg(feature = "save")]
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        self.restore_state(state, true)
+    }
+
+    /// Restore a state produced by `Self::save_state`, only writing the save RAM and RTC
+    /// to their storage if `persist` is `true`
+    #[cfg(feature = "save")]
+    pub(crate) fn restore_state(&mut self, state: &[u8], persist: bool) -> Result<()> {
+        let mut gameboy: Self = bincode::deserialize(state)?;
+
+        // Carry over everything that is not part of the state
+        gameboy.cpu.restore_from(&mut self.cpu, persist)?;
+
+        #[cfg(feature = "debug")]
+        {
+            std::mem::swap(&mut gameboy.debugger, &mut self.debugger);
+            std::mem::swap(&mut gameboy.debug_frontend, &mut self.debug_frontend);
+            std::mem::swap(&mut gameboy.profiler, &mut self.profiler);
+            std::mem::swap(
+                gameboy.cpu.memory.watchpoints_mut(),
+                self.cpu.memory.watchpoints_mut(),
+            );
+        }
+
+        std::mem::swap(&mut gameboy.cheats, &mut self.cheats);
+
+        *self = gameboy;
+        self.update_game_genie();
+
+        Ok(())
+    }
+
+    /// Returns the first I/O error since the last call, if any.
+    ///
+    /// Failing to write battery-backed RAM, the RTC or the trace does not stop the
+    /// emulator. Instead, the error is kept here so that the frontend can report it.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.cpu.take_error()
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn debugger(&mut self) -> &mut debug::Debugger {
+        &mut self.debugger
+    }
+
+    /// Attach a frontend to the debugger (e.g., `debug::repl::Repl`), or detach it.
+    ///
+    /// The debugger only stops the CPU while a frontend is attached.
+    #[cfg(feature = "debug")]
+    pub fn set_debug_frontend(&mut self, frontend: Option<Box<dyn debug::Frontend + Send>>) {
+        self.debug_frontend = frontend;
+    }
+
+    /// Start profiling CPU cycles, or stop if `None`. The profiler does not need a
+    /// debug frontend.
+    #[cfg(feature = "debug")]
+    pub fn set_profiler(&mut self, profiler: Option<debug::profile::Profiler>) {
+        self.profiler = profiler;
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn profiler(&self) -> Option<&debug::profile::Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stop profiling, and return the profiler
+    #[cfg(feature = "debug")]
+    pub fn take_profiler(&mut self) -> Option<debug::profile::Profiler> {
+        self.profiler.take()
+    }
+
+    /// Returns the last event since the previous call, if any (e.g., the CPU locked up).
+    pub fn take_event(&mut self) -> Option<Event> {
+        self.cpu.take_event()
+    }
+
     /// Reset the emulator
     pub fn reset(&mut self) {
         // Reset the CPU
         self.cpu.reset();
     }
 
+    /// Battery-backed RAM and RTC, serialized (see `movie::Movie`)
+    #[cfg(feature = "save")]
+    pub(crate) fn save_battery(&mut self) -> Vec<u8> {
+        self.cpu.memory.controller().save_battery()
+    }
+
+    /// Restore the battery-backed RAM and RTC produced by `Self::save_battery`
+    #[cfg(feature = "save")]
+    pub(crate) fn load_battery(&mut self, data: &[u8]) -> Result<()> {
+        self.cpu.memory.controller().load_battery(data)
+    }
+
+    /// CRC32 of the currently loaded ROM
+    pub fn rom_checksum(&mut self) -> u32 {
+        self.cpu.memory.controller().rom.checksum()
+    }
+
+    /// Contents of battery-backed cartridge RAM, if the cartridge has any.
+    ///
+    /// This lets a frontend manage save data itself. Note that writes through this
+    /// slice are not passed on to the cartridge's `Storage`. The slice stays at the
+    /// same address when a state is loaded.
+    pub fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        self.cpu.memory.controller().battery_ram().map(|ram| ram.data_mut())
+    }
+
+    /// Read a byte from the given address, as the CPU would see it.
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.cpu.memory.read(addr)
+    }
+
+    /// Write a byte to the given address, as the CPU would.
+    pub fn write_memory(&mut self, addr: u16, value: u8) {
+        self.cpu.memory.write(addr, value);
+    }
+
+    /// Current value of a CPU register
+    pub fn register(&self, reg: Reg16) -> u16 {
+        self.cpu.registers.read(reg)
+    }
+
     pub fn cpu(&mut self) -> &mut Cpu {
         &mut self.cpu
     }
@@ -177,6 +508,14 @@ impl Gameboy {
         self.cpu.speed
     }
 
+    /// Returns the last frame rendered by the PPU.
+    ///
+    /// Unlike `Self::step`, this does not wait for the frame to be ready. This is
+    /// mainly useful to redraw the screen right after loading a state.
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        self.cpu.memory.ppu().current_frame()
+    }
+
     /// Returns a String containing the serial output of this Gameboy _so far_.
     ///
     /// In other words, this output is cumulative and contains every character
@@ -185,3 +524,9 @@ impl Gameboy {
         self.cpu.memory.io().serial_buffer().into_iter().collect()
     }
 }
+
+/// Serialize a value into memory. Serializing can only fail on I/O, so this never fails.
+#[cfg(feature = "save")]
+pub(crate) fn serialize<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("Failed to serialize into memory")
+}
@@ -1,9 +1,13 @@
 use crate::cartridge::{BootRom, Cartridge, Controller, Ram as CartridgeRam, Rom};
+use crate::cdl;
+use crate::cheats::GameShark;
 use crate::cpu::Interrupt;
 use crate::error::Result;
 use crate::joypad::Joypad;
 use crate::ppu::{Ppu, Vram};
 use crate::timer::Timer;
+#[cfg(feature = "debug")]
+use crate::debug::watch::Watchpoints;
 
 /// Generic traits that provide access to some memory.
 ///
@@ -27,12 +31,15 @@ pub trait MemoryWrite<A, V> {
 pub struct Ram {
     data: Vec<u8>,
     active_bank: u8,
+
+    /// Raw value of the bank select register (SVBK)
+    bank_select: u8,
     num_banks: u8,
     cgb: bool,
 }
 
 impl Ram {
-    const BANK_SIZE: usize = 4 * 1024; // 4K
+    pub(crate) const BANK_SIZE: usize = 4 * 1024; // 4K
     pub const BASE_ADDR: u16 = 0xC000;
     pub const LAST_ADDR: u16 = 0xDFFF;
     pub const BANK_SELECT_ADDR: u16 = 0xFF70;
@@ -47,24 +54,40 @@ impl Ram {
         Self {
             data: vec![0u8; Self::BANK_SIZE * num_banks as usize],
             active_bank: 1,
+            bank_select: 0,
             num_banks,
             cgb,
         }
     }
 
+    /// Contents of all banks
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Write a byte to the switchable area (0xD000-0xDFFF) of the given bank.
+    pub(crate) fn write_bank(&mut self, bank: u8, addr: u16, value: u8) {
+        let bank = bank.max(1) % self.num_banks;
+        let addr = (addr - Self::BASE_ADDR) as usize - Self::BANK_SIZE;
+        self.data[bank as usize * Self::BANK_SIZE + addr] = value;
+    }
+
     /// Update the active RAM bank
+    ///
+    /// Only the lower 3 bits are used, and bank 0 selects bank 1. There is no bank
+    /// switching on DMG, so this is ignored there.
     pub fn update_bank(&mut self, bank: u8) {
-        if self.cgb {
-            self.active_bank = if bank == 0 {
-                1
-            } else {
-                bank & 0b0111
-            };
-
-            assert!(self.active_bank < self.num_banks);
-        } else {
-            panic!("Received RAM bank change request on unbanked RAM");
+        if !self.cgb {
+            log::debug!("Ignored WRAM bank change to {} on DMG", bank);
+            return;
         }
+
+        self.bank_select = bank & 0b0111;
+        self.active_bank = if bank & 0b0111 == 0 {
+            1
+        } else {
+            bank & 0b0111
+        };
     }
 }
 
@@ -145,6 +168,12 @@ pub struct Io {
 
     /// Infrared comm. register (0xFF56)
     rp: u8,
+
+    /// Object priority mode (OPRI): 0xFF6C
+    opri: u8,
+
+    /// Undocumented CGB registers: 0xFF72 - 0xFF75
+    undocumented: [u8; 4],
 }
 
 impl Io {
@@ -165,10 +194,13 @@ impl Io {
             waveform_ram: [0; 16],
             prep_speed_switch: 0,
             disable_boot_rom: 0,
-            hdma: [0; 5],
+            // HDMA5 reads 0xFF while no transfer is active
+            hdma: [0, 0, 0, 0, 0xFF],
             hdma_active: false,
             hdma_stopped: false,
             rp: 0,
+            opri: 0,
+            undocumented: [0; 4],
         }
     }
 
@@ -252,12 +284,19 @@ impl MemoryRead<u16, u8> for Io {
                 self.hdma[idx]
             }
             0xFF56 => self.rp,
-            0xFF03 | 0xFF08..=0xFF0E | 0xFF27..=0xFF2F | 0xFF4C..=0xFF4E | 0xFF57..=0xFF67 | 0xFF6C..=0xFF6F | 0xFF71..=0xFF7F => {
-                // Invalid registers -- ignore reads from these
-                log::warn!("Invalid read from 0x{:X}", addr);
+            0xFF6C => self.opri,
+            0xFF72..=0xFF75 => {
+                let idx = (addr - 0xFF72) as usize;
+                self.undocumented[idx]
+            }
+            0xFF76 | 0xFF77 => {
+                // PCM12/PCM34: channel output, which is always silent
+                0x00
+            }
+            _ => {
+                // Unmapped registers
                 0xFF
             }
-            _ => unreachable!(),
         }
     }
 }
@@ -294,7 +333,8 @@ impl MemoryWrite<u16, u8> for Io {
                 self.waveform_ram[idx] = value;
             }
             0xFF4D => {
-                self.prep_speed_switch = value;
+                // KEY1: only the "prepare speed switch" bit is writable
+                self.prep_speed_switch = (self.prep_speed_switch & 1 << 7) | (value & 0x1);
             }
             0xFF50 => {
                 self.disable_boot_rom = value;
@@ -315,15 +355,71 @@ impl MemoryWrite<u16, u8> for Io {
             0xFF56 => {
                 self.rp = value;
             }
-            0xFF03 | 0xFF08..=0xFF0E | 0xFF27..=0xFF2F | 0xFF4C..=0xFF4E | 0xFF57..=0xFF67 | 0xFF6C..=0xFF6F | 0xFF71..=0xFF7F => {
-                // Invalid registers -- ignore writes to these
-                log::warn!("Invalid write to 0x{:X}: {}", addr, value)
+            0xFF6C => {
+                self.opri = value;
+            }
+            0xFF72..=0xFF75 => {
+                let idx = (addr - 0xFF72) as usize;
+                self.undocumented[idx] = value;
+            }
+            _ => {
+                // Unmapped or read-only registers -- ignore writes to these
+                log::debug!("Ignored write to 0x{:X}: {}", addr, value)
             }
-            _ => unreachable!(),
         }
     }
 }
 
+/// Bits of an I/O register that always read as 1, for DMG or CGB.
+///
+/// Unused bits read as 1, and so do write-only and unmapped registers (0xFF). Registers
+/// that only exist on CGB are unmapped on DMG.
+///
+/// See: https://gbdev.io/pandocs/Hardware_Reg_List.html
+fn io_read_mask(addr: u16, cgb: bool) -> u8 {
+    match addr {
+        // Joypad, serial, timer and interrupt flags
+        0xFF00 => 0xC0,
+        0xFF01 => 0x00,
+        0xFF02 if cgb => 0x7C,
+        0xFF02 => 0x7E,
+        0xFF04..=0xFF06 => 0x00,
+        0xFF07 => 0xF8,
+        0xFF0F => 0xE0,
+
+        // Sound: frequency and length are write-only
+        0xFF10 => 0x80,
+        0xFF11 | 0xFF16 => 0x3F,
+        0xFF12 | 0xFF17 | 0xFF21 | 0xFF22 | 0xFF24 | 0xFF25 => 0x00,
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0xBF,
+        0xFF1A => 0x7F,
+        0xFF1C => 0x9F,
+        0xFF26 => 0x70,
+        0xFF30..=0xFF3F => 0x00,
+
+        // PPU
+        0xFF40 => 0x00,
+        0xFF41 => 0x80,
+        0xFF42..=0xFF4B => 0x00,
+
+        // CGB only
+        0xFF4D if cgb => 0x7E,
+        0xFF4F if cgb => 0xFE,
+        0xFF55 if cgb => 0x00,
+        0xFF56 if cgb => 0x3C,
+        0xFF68 | 0xFF6A if cgb => 0x40,
+        0xFF69 | 0xFF6B if cgb => 0x00,
+        0xFF6C if cgb => 0xFE,
+        0xFF70 if cgb => 0xF8,
+        0xFF72..=0xFF74 if cgb => 0x00,
+        0xFF75 if cgb => 0x8F,
+        0xFF76 | 0xFF77 if cgb => 0x00,
+
+        // Write-only (e.g., NRx3, HDMA1-4, BOOT) or unmapped
+        _ => 0xFF,
+    }
+}
+
 pub enum MemoryType {
     Rom,
     Ram,
@@ -375,6 +471,14 @@ pub struct MemoryBus {
     cgb: bool,
 
     boot_rom: bool,
+
+    /// Clock cycles the timer already ran for during the current CPU step
+    #[cfg_attr(feature = "save", serde(skip))]
+    timer_cycles: u16,
+
+    #[cfg(feature = "debug")]
+    #[cfg_attr(feature = "save", serde(skip))]
+    watchpoints: Watchpoints,
 }
 
 impl MemoryBus {
@@ -389,8 +493,11 @@ impl MemoryBus {
             io: Io::new(),
             high_ram: Box::new([0u8; 0x80]),
             int_enable: 0,
-            cgb: true,
+            cgb,
             boot_rom: false,
+            timer_cycles: 0,
+            #[cfg(feature = "debug")]
+            watchpoints: Watchpoints::default(),
         }
     }
 
@@ -408,9 +515,50 @@ impl MemoryBus {
             int_enable: 0,
             cgb,
             boot_rom,
+            timer_cycles: 0,
+            #[cfg(feature = "debug")]
+            watchpoints: Watchpoints::default(),
         })
     }
 
+    /// Read an I/O register, as seen by the CPU.
+    fn io_read(&self, addr: u16) -> u8 {
+        let mask = io_read_mask(addr, self.cgb);
+        if mask == 0xFF {
+            return 0xFF;
+        }
+
+        let value = match addr {
+            0xFF40..=0xFF4B | 0xFF68..=0xFF6B | Vram::BANK_SELECT_ADDR => self.ppu.read(addr),
+            Ram::BANK_SELECT_ADDR => self.ram.bank_select,
+            _ => self.io.read(addr),
+        };
+
+        value | mask
+    }
+
+    /// Write the value of a GameShark code to RAM.
+    pub(crate) fn apply_game_shark(&mut self, code: &GameShark) {
+        match code.bank {
+            Some(bank) => self.ram.write_bank(bank, code.addr, code.value),
+            None => self.write(code.addr, code.value),
+        }
+    }
+
+    /// Called by the CPU at the start of every step
+    pub(crate) fn begin_step(&mut self) {
+        self.timer_cycles = 0;
+    }
+
+    /// Run the timer for one M-cycle of the current CPU step, so that the CPU sees
+    /// it as of the M-cycle it accesses memory in
+    pub(crate) fn tick(&mut self) {
+        self.timer_cycles += 4;
+        if self.timer().step(4) {
+            self.io.int_flags |= 1 << Interrupt::Timer as u8;
+        }
+    }
+
     pub fn step(&mut self, cycles: u16, speed: bool, interrupts: &mut Vec<Interrupt>) {
         // Execute a step of the PPU.
         //
@@ -418,8 +566,10 @@ impl MemoryBus {
         self.ppu.step(cycles, speed, interrupts);
 
         // Update the internal timer and trigger an interrupt, if needed
-        // Note that the timer may tick multiple times for a single instruction
-        if self.timer().step(cycles) {
+        // Note that the timer may tick multiple times for a single instruction, and
+        // that it already ran for the M-cycles the CPU accessed memory in
+        let timer_cycles = cycles.saturating_sub(std::mem::take(&mut self.timer_cycles));
+        if self.timer().step(timer_cycles) {
             interrupts.push(Interrupt::Timer);
         }
 
@@ -431,7 +581,13 @@ impl MemoryBus {
             //interrupts.push(Interrupt::Serial);
         }
 
-        // Update the RTC, if present
+        self.step_rtc(cycles, speed);
+    }
+
+    /// Update the RTC, if present
+    ///
+    /// The RTC has its own oscillator, so this also runs while the system clock is stopped.
+    pub fn step_rtc(&mut self, cycles: u16, speed: bool) {
         if let Some(rtc) = self.controller.rtc.as_mut() {
             rtc.step(cycles, speed);
         }
@@ -487,6 +643,43 @@ impl MemoryBus {
         &mut self.controller
     }
 
+    /// Contents of WRAM (all banks), HRAM and cartridge RAM (all banks, if present)
+    pub(crate) fn search_ram(&self) -> (&[u8], &[u8], Option<&[u8]>) {
+        let hram_size = (Self::HRAM_LAST_ADDR - Self::HRAM_BASE_ADDR + 1) as usize;
+        let cartridge_ram = self.controller.ram.as_ref().map(|ram| ram.data());
+        (self.ram.data(), &self.high_ram[..hram_size], cartridge_ram)
+    }
+
+    pub(crate) fn cdl(&self) -> Option<&cdl::CodeDataLog> {
+        self.controller.cdl.as_ref()
+    }
+
+    /// Log the bytes of an instruction as executed, if they are in cartridge ROM
+    pub(crate) fn log_code(&self, addr: u16, size: u8) {
+        let cdl = match self.controller.cdl.as_ref() {
+            Some(cdl) => cdl,
+            None => return,
+        };
+
+        for i in 0..size as u16 {
+            let addr = addr.wrapping_add(i);
+            let boot_rom = self.controller.boot_rom.is_some() && addr <= BootRom::LAST_ADDR;
+
+            if addr <= Rom::LAST_ADDR && !boot_rom {
+                let flag = if i == 0 { cdl::EXEC_FIRST } else { cdl::EXEC_OPERAND };
+                cdl.log(self.controller.rom.offset(addr), flag);
+            }
+        }
+    }
+
+    /// Log ROM reads with the given flag (see `cdl`) until called with 0
+    #[inline]
+    pub(crate) fn log_reads(&self, flag: u8) {
+        if let Some(cdl) = self.controller.cdl.as_ref() {
+            cdl.set_access(flag);
+        }
+    }
+
     /// Return a reference to the joypad
     pub fn joypad(&mut self) -> &mut Joypad {
         self.io.joypad()
@@ -517,11 +710,9 @@ impl MemoryBus {
     }
 }
 
-impl MemoryRead<u16, u8> for MemoryBus {
-    /// Read a single byte from an arbitrary memory address.
-    ///
-    /// This will be converted into a read from the relevant memory section.
-    fn read(&self, addr: u16) -> u8 {
+impl MemoryBus {
+    /// Read a single byte, without triggering watchpoints
+    fn read_raw(&self, addr: u16) -> u8 {
         match addr {
             BootRom::BASE_ADDR..=BootRom::LAST_ADDR if self.controller.boot_rom.is_some() => {
                 // If the boot ROM is active, read from it instead of cartridge ROM
@@ -530,13 +721,13 @@ impl MemoryRead<u16, u8> for MemoryBus {
             Rom::BASE_ADDR..=Rom::LAST_ADDR | CartridgeRam::BASE_ADDR..=CartridgeRam::LAST_ADDR => {
                 self.controller.read(addr)
             }
-            Vram::BASE_ADDR..=Vram::LAST_ADDR | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B | 0xFF68..=0xFF6B | Vram::BANK_SELECT_ADDR => {
+            Vram::BASE_ADDR..=Vram::LAST_ADDR | 0xFE00..=0xFE9F => {
                 self.ppu.read(addr)
             }
             Ram::BASE_ADDR..=Ram::LAST_ADDR => self.ram.read(addr),
             0xE000..=0xFDFF => {
                 // Echo RAM
-                self.read(addr - 0x2000)
+                self.read_raw(addr - 0x2000)
             }
             0xFEA0..=0xFEFF => {
                 // Prohibited memory area
@@ -556,10 +747,7 @@ impl MemoryRead<u16, u8> for MemoryBus {
                     }
                 }
             }
-            Ram::BANK_SELECT_ADDR => self.ram.active_bank,
-            0xFF00..=0xFF7F => {
-                self.io.read(addr)
-            }
+            0xFF00..=0xFF7F => self.io_read(addr),
             0xFF80..=0xFFFE => {
                 let addr = addr as usize - 0xFF80;
                 self.high_ram[addr]
@@ -567,10 +755,9 @@ impl MemoryRead<u16, u8> for MemoryBus {
             0xFFFF => self.int_enable,
         }
     }
-}
 
-impl MemoryWrite<u16, u8> for MemoryBus {
-    fn write(&mut self, addr: u16, value: u8) {
+    /// Write a single byte, without triggering watchpoints
+    fn write_raw(&mut self, addr: u16, value: u8) {
         match addr {
             Rom::BASE_ADDR..=Rom::LAST_ADDR | CartridgeRam::BASE_ADDR..=CartridgeRam::LAST_ADDR => {
                 self.controller.write(addr, value);
@@ -605,6 +792,60 @@ impl MemoryWrite<u16, u8> for MemoryBus {
             }
         }
     }
+
+    #[cfg(feature = "debug")]
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    /// Report a write that bypassed the bus (e.g., a DMA copy) to the watchpoints
+    #[cfg(feature = "debug")]
+    pub(crate) fn watch_write(&self, addr: u16, old: u8, new: u8) {
+        if self.watchpoints.watches_write(addr) {
+            let (_, bank) = self.memory_info(addr);
+            self.watchpoints.on_write(addr, bank, old, new);
+        }
+    }
+}
+
+impl MemoryRead<u16, u8> for MemoryBus {
+    /// Read a single byte from an arbitrary memory address.
+    ///
+    /// This will be converted into a read from the relevant memory section.
+    fn read(&self, addr: u16) -> u8 {
+        let value = self.read_raw(addr);
+
+        #[cfg(feature = "debug")]
+        if self.watchpoints.armed() {
+            let (_, bank) = self.memory_info(addr);
+            self.watchpoints.on_read(addr, bank, value);
+        }
+
+        value
+    }
+}
+
+impl MemoryWrite<u16, u8> for MemoryBus {
+    fn write(&mut self, addr: u16, value: u8) {
+        #[cfg(feature = "debug")]
+        let watched = if self.watchpoints.watches_write(addr) {
+            Some((self.read_raw(addr), self.memory_info(addr).1))
+        } else {
+            None
+        };
+
+        self.write_raw(addr, value);
+
+        #[cfg(feature = "debug")]
+        if let Some((old, bank)) = watched {
+            self.watchpoints.on_write(addr, bank, old, value);
+        }
+    }
 }
 
 /// Write a 16-bit word to memory. This maps into 2 8-bit writes
@@ -634,4 +875,102 @@ mod test {
         let value: u8 = ram.read(Ram::BASE_ADDR + 0x1234u16);
         assert_eq!(value, 0x66);
     }
+
+    #[test]
+    fn ram_bank_select() {
+        // Only the lower 3 bits select the bank, and bank 0 selects bank 1
+        let mut ram = Ram::new(true);
+        ram.update_bank(0x0A);
+        assert_eq!(ram.active_bank, 2);
+        ram.update_bank(0x08);
+        assert_eq!(ram.active_bank, 1);
+
+        // No bank switching on DMG
+        let mut ram = Ram::new(false);
+        ram.update_bank(3);
+        assert_eq!(ram.active_bank, 1);
+    }
+
+    #[test]
+    fn io_register_reads() {
+        // (first, last, DMG, CGB): value read back after writing 0x00 to each register
+        #[rustfmt::skip]
+        let expected: &[(u16, u16, u8, u8)] = &[
+            (0xFF00, 0xFF00, 0xCF, 0xCF), // P1: no buttons pressed
+            (0xFF01, 0xFF01, 0x00, 0x00),
+            (0xFF02, 0xFF02, 0x7E, 0x7C),
+            (0xFF03, 0xFF03, 0xFF, 0xFF),
+            (0xFF04, 0xFF06, 0x00, 0x00),
+            (0xFF07, 0xFF07, 0xF8, 0xF8),
+            (0xFF08, 0xFF0E, 0xFF, 0xFF),
+            (0xFF0F, 0xFF0F, 0xE0, 0xE0),
+            (0xFF10, 0xFF10, 0x80, 0x80),
+            (0xFF11, 0xFF11, 0x3F, 0x3F),
+            (0xFF12, 0xFF12, 0x00, 0x00),
+            (0xFF13, 0xFF13, 0xFF, 0xFF),
+            (0xFF14, 0xFF14, 0xBF, 0xBF),
+            (0xFF15, 0xFF15, 0xFF, 0xFF),
+            (0xFF16, 0xFF16, 0x3F, 0x3F),
+            (0xFF17, 0xFF17, 0x00, 0x00),
+            (0xFF18, 0xFF18, 0xFF, 0xFF),
+            (0xFF19, 0xFF19, 0xBF, 0xBF),
+            (0xFF1A, 0xFF1A, 0x7F, 0x7F),
+            (0xFF1B, 0xFF1B, 0xFF, 0xFF),
+            (0xFF1C, 0xFF1C, 0x9F, 0x9F),
+            (0xFF1D, 0xFF1D, 0xFF, 0xFF),
+            (0xFF1E, 0xFF1E, 0xBF, 0xBF),
+            (0xFF1F, 0xFF20, 0xFF, 0xFF),
+            (0xFF21, 0xFF22, 0x00, 0x00),
+            (0xFF23, 0xFF23, 0xBF, 0xBF),
+            (0xFF24, 0xFF25, 0x00, 0x00),
+            (0xFF26, 0xFF26, 0x70, 0x70),
+            (0xFF27, 0xFF2F, 0xFF, 0xFF),
+            (0xFF30, 0xFF3F, 0x00, 0x00), // Wave RAM
+            (0xFF40, 0xFF40, 0x00, 0x00),
+            (0xFF41, 0xFF41, 0x80, 0x80), // STAT: LCD off, mode 0
+            (0xFF42, 0xFF4B, 0x00, 0x00),
+            (0xFF4C, 0xFF4C, 0xFF, 0xFF),
+            (0xFF4D, 0xFF4D, 0xFF, 0x7E),
+            (0xFF4E, 0xFF4E, 0xFF, 0xFF),
+            (0xFF4F, 0xFF4F, 0xFF, 0xFE),
+            (0xFF50, 0xFF54, 0xFF, 0xFF),
+            (0xFF55, 0xFF55, 0xFF, 0xFF), // HDMA5: no transfer active
+            (0xFF56, 0xFF56, 0xFF, 0x3C),
+            (0xFF57, 0xFF67, 0xFF, 0xFF),
+            (0xFF68, 0xFF68, 0xFF, 0x40),
+            (0xFF69, 0xFF69, 0xFF, 0x00),
+            (0xFF6A, 0xFF6A, 0xFF, 0x40),
+            (0xFF6B, 0xFF6B, 0xFF, 0x00),
+            (0xFF6C, 0xFF6C, 0xFF, 0xFE),
+            (0xFF6D, 0xFF6F, 0xFF, 0xFF),
+            (0xFF70, 0xFF70, 0xFF, 0xF8),
+            (0xFF71, 0xFF71, 0xFF, 0xFF),
+            (0xFF72, 0xFF74, 0xFF, 0x00),
+            (0xFF75, 0xFF75, 0xFF, 0x8F),
+            (0xFF76, 0xFF77, 0xFF, 0x00),
+            (0xFF78, 0xFF7F, 0xFF, 0xFF),
+        ];
+
+        for cgb in [false, true] {
+            let mut bus = MemoryBus::new(cgb);
+
+            // Writing to DMA or HDMA5 would start a transfer
+            for addr in (0xFF00u16..=0xFF7F).filter(|&addr| addr != 0xFF46 && addr != 0xFF55) {
+                bus.write(addr, 0x00u8);
+            }
+
+            let mut next = 0xFF00;
+            for &(first, last, dmg_value, cgb_value) in expected {
+                assert_eq!(first, next);
+                next = last + 1;
+
+                let expected = if cgb { cgb_value } else { dmg_value };
+                for addr in first..=last {
+                    let value: u8 = bus.read(addr);
+                    assert_eq!(value, expected, "{:#06X} (cgb: {})", addr, cgb);
+                }
+            }
+            assert_eq!(next, 0xFF80);
+        }
+    }
 }
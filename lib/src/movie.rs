@@ -0,0 +1,194 @@
+//! Deterministic input recording and playback.
+//!
+//! A movie is made up of a starting point (power-on or a save state), the checksum
+//! of the ROM it was recorded on, and the joypad state for every frame. Movies that
+//! start at power-on also hold the battery-backed RAM and RTC, which survive a reset.
+//!
+//! Frames are delimited by `Gameboy::next_frame`. At the end of each frame, the
+//! recorded joypad state is applied using `Gameboy::set_joypad_state`. As long as the
+//! recorder and the player both follow this sequence, playback is exact.
+//!
+//! Note that the RTC follows the host clock, so MBC3 games that read it may still
+//! diverge.
+#[cfg(feature = "fs")]
+use std::fs;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::{serialize, Gameboy};
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Movie {
+    /// CRC32 of the ROM this movie was recorded on
+    rom_checksum: u32,
+
+    /// State to start playback from. If `None`, the movie starts at power-on.
+    start_state: Option<Vec<u8>>,
+
+    /// Battery-backed RAM and RTC at power-on (see `Gameboy::save_battery`)
+    start_battery: Option<Vec<u8>>,
+
+    /// Joypad state for each frame (see `JoypadInput::mask`)
+    inputs: Vec<u8>,
+}
+
+impl Movie {
+    const MAGIC: &'static [u8; 4] = b"GBCM";
+    const VERSION: u8 = 1;
+
+    /// Start recording a new movie.
+    ///
+    /// If `power_on` is set, the Gameboy is reset and the movie starts from there.
+    /// Otherwise, the current state of the Gameboy is used as the starting point.
+    pub fn record(gameboy: &mut Gameboy, power_on: bool) -> Self {
+        let (start_state, start_battery) = if power_on {
+            gameboy.reset();
+            (None, Some(gameboy.save_battery()))
+        } else {
+            (Some(gameboy.save_state()), None)
+        };
+
+        Self {
+            rom_checksum: gameboy.rom_checksum(),
+            start_state,
+            start_battery,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Number of frames in this movie
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    /// Returns `true` if this movie starts at power-on
+    pub fn power_on(&self) -> bool {
+        self.start_state.is_none()
+    }
+
+    /// Joypad state for the given frame
+    pub fn input(&self, frame: usize) -> Option<u8> {
+        self.inputs.get(frame).copied()
+    }
+
+    /// Append the joypad state for the next frame.
+    pub fn push(&mut self, state: u8) {
+        self.inputs.push(state);
+    }
+
+    /// Prepare the Gameboy for playback of this movie.
+    ///
+    /// This fails if the Gameboy is running a different ROM than the movie.
+    pub fn start(&self, gameboy: &mut Gameboy) -> Result<()> {
+        let checksum = gameboy.rom_checksum();
+        if checksum != self.rom_checksum {
+            return Err(Error::InvalidValue(format!(
+                "Movie was recorded on a different ROM (CRC32: {:08X}, expected {:08X})",
+                checksum, self.rom_checksum
+            )));
+        }
+
+        match &self.start_state {
+            None => gameboy.reset(),
+            Some(state) => gameboy.load_state(state)?,
+        }
+
+        if let Some(battery) = &self.start_battery {
+            gameboy.load_battery(battery)?;
+        }
+
+        gameboy.set_joypad_state(0);
+
+        Ok(())
+    }
+
+    /// Run a single frame of playback, returning `false` once the movie is over.
+    pub fn play_frame(&self, gameboy: &mut Gameboy, frame: usize) -> bool {
+        match self.input(frame) {
+            Some(state) => {
+                gameboy.next_frame();
+                gameboy.set_joypad_state(state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Play the entire movie on the Gameboy, as fast as possible.
+    pub fn replay(&self, gameboy: &mut Gameboy) -> Result<()> {
+        self.start(gameboy)?;
+
+        let mut frame = 0;
+        while self.play_frame(gameboy, frame) {
+            frame += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Serialize this movie into a buffer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Self::MAGIC.to_vec();
+        data.push(Self::VERSION);
+        data.extend_from_slice(&serialize(self));
+
+        data
+    }
+
+    /// Deserialize a movie produced by `Self::to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 5 || &data[..4] != Self::MAGIC {
+            return Err(Error::InvalidValue("Not a movie file".to_string()));
+        }
+
+        if data[4] != Self::VERSION {
+            return Err(Error::InvalidValue(format!("Unsupported movie version: {}", data[4])));
+        }
+
+        Ok(bincode::deserialize(&data[5..])?)
+    }
+
+    /// Write this movie to a file on disk.
+    #[cfg(feature = "fs")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Read a movie from a file on disk.
+    #[cfg(feature = "fs")]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_roundtrip() {
+        let movie = Movie {
+            rom_checksum: 0xDEADBEEF,
+            start_state: Some(vec![1, 2, 3]),
+            start_battery: None,
+            inputs: vec![0x00, 0x10, 0x11, 0x80],
+        };
+
+        let data = movie.to_bytes();
+        assert_eq!(&data[..4], b"GBCM");
+        assert_eq!(Movie::from_bytes(&data).unwrap(), movie);
+
+        assert!(Movie::from_bytes(b"GBCS\x01").is_err());
+        assert!(Movie::from_bytes(b"GBCM\x02").is_err());
+    }
+}
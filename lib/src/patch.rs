@@ -0,0 +1,437 @@
+//! ROM patching: IPS, BPS and UPS
+//!
+//! Patches are applied to a ROM in memory: the original ROM file is never modified.
+//! The patch format is detected from the magic at the start of the patch file.
+//!
+//! * IPS: https://zerosoft.zophar.net/ips.php
+//! * BPS: https://www.romhacking.net/documents/746/
+//! * UPS: https://www.romhacking.net/documents/392/
+use std::convert::TryFrom;
+
+use crate::cartridge::RomSize;
+use crate::error::{Error, Result};
+
+/// Apply a patch to a ROM, returning the patched ROM.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else {
+        Err(Error::InvalidValue("Unknown patch format".to_string()))
+    }
+}
+
+/// Check the size a patch says the patched ROM has, before allocating it
+fn check_target_size(size: usize) -> Result<()> {
+    let max = usize::from(RomSize::_8M);
+    if size > max {
+        return Err(Error::InvalidValue(format!(
+            "Patched ROM would be {} bytes, larger than the {} byte maximum", size, max
+        )));
+    }
+
+    Ok(())
+}
+
+fn truncated() -> Error {
+    Error::InvalidValue("Patch is truncated".to_string())
+}
+
+/// Reads values out of a patch, failing on truncated data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len());
+        let end = end.ok_or_else(truncated)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Big-endian integer, as used by IPS
+    fn be(&mut self, len: usize) -> Result<usize> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+    }
+
+    /// Variable-length integer, as used by BPS and UPS
+    fn varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+
+        loop {
+            let byte = self.u8()?;
+            let overflow = || Error::InvalidValue("Invalid patch integer".to_string());
+
+            value = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or_else(overflow)?;
+
+            if byte & 0x80 != 0 {
+                break;
+            }
+
+            shift = shift.checked_shl(7).filter(|s| *s != 0).ok_or_else(overflow)?;
+            value = value.checked_add(shift).ok_or_else(overflow)?;
+        }
+
+        Ok(value)
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let mut output = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+
+        let offset = offset.iter().fold(0, |acc, b| acc << 8 | *b as usize);
+        let size = reader.be(2)?;
+
+        if size == 0 {
+            // RLE record
+            let count = reader.be(2)?;
+            let value = reader.u8()?;
+            resize(&mut output, offset + count);
+            output[offset..offset + count].iter_mut().for_each(|b| *b = value);
+        } else {
+            let data = reader.bytes(size)?;
+            resize(&mut output, offset + size);
+            output[offset..offset + size].copy_from_slice(data);
+        }
+    }
+
+    // Optional truncation extension
+    if let Ok(len) = reader.be(3) {
+        output.truncate(len);
+    }
+
+    Ok(output)
+}
+
+fn resize(output: &mut Vec<u8>, len: usize) {
+    if output.len() < len {
+        output.resize(len, 0);
+    }
+}
+
+/// Split the 12-byte footer off a BPS or UPS patch, verifying the patch CRC.
+///
+/// Returns the patch body along with the source and target CRCs.
+fn split_footer(patch: &[u8]) -> Result<(&[u8], u32, u32)> {
+    if patch.len() < 4 + 12 {
+        return Err(truncated());
+    }
+
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+
+    let patch_crc = crc32fast::hash(&patch[..patch.len() - 4]);
+    if patch_crc != crc(8) {
+        return Err(Error::InvalidValue(format!(
+            "Patch CRC mismatch: expected {:08X}, found {:08X}", crc(8), patch_crc
+        )));
+    }
+
+    Ok((body, crc(0), crc(4)))
+}
+
+fn check_crc(what: &str, data: &[u8], expected: u32) -> Result<()> {
+    let crc = crc32fast::hash(data);
+    if crc != expected {
+        return Err(Error::InvalidValue(format!(
+            "{} CRC mismatch: expected {:08X}, found {:08X}", what, expected, crc
+        )));
+    }
+
+    Ok(())
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (body, source_crc, target_crc) = split_footer(patch)?;
+    check_crc("Source ROM", rom, source_crc)?;
+
+    let mut reader = Reader::new(body, 4);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(target_size)?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return Err(Error::InvalidValue(format!(
+            "Patch expects a {} byte ROM, found {} bytes", source_size, rom.len()
+        )));
+    }
+
+    let invalid = || Error::InvalidValue("Invalid BPS patch".to_string());
+
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+
+    while reader.pos < body.len() {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+
+        if output.len() + length > target_size {
+            return Err(invalid());
+        }
+
+        match data & 0x3 {
+            0 => {
+                // SourceRead
+                let start = output.len();
+                let bytes = rom.get(start..start + length).ok_or_else(invalid)?;
+                output.extend_from_slice(bytes);
+            }
+            1 => {
+                // TargetRead
+                output.extend_from_slice(reader.bytes(length)?);
+            }
+            2 => {
+                // SourceCopy
+                source_offset += relative_offset(reader.varint()?);
+                let start = usize::try_from(source_offset).map_err(|_| invalid())?;
+                let bytes = rom.get(start..start + length).ok_or_else(invalid)?;
+                output.extend_from_slice(bytes);
+                source_offset += length as isize;
+            }
+            _ => {
+                // TargetCopy: may overlap with the bytes being written
+                target_offset += relative_offset(reader.varint()?);
+                for _ in 0..length {
+                    let byte = usize::try_from(target_offset)
+                        .ok()
+                        .and_then(|i| output.get(i).copied())
+                        .ok_or_else(invalid)?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(invalid());
+    }
+
+    check_crc("Patched ROM", &output, target_crc)?;
+
+    Ok(output)
+}
+
+/// Decode a signed BPS offset: the lowest bit is the sign
+fn relative_offset(data: usize) -> isize {
+    let offset = (data >> 1) as isize;
+    if data & 1 != 0 {
+        -offset
+    } else {
+        offset
+    }
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (body, source_crc, target_crc) = split_footer(patch)?;
+    check_crc("Source ROM", rom, source_crc)?;
+
+    let mut reader = Reader::new(body, 4);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(target_size)?;
+
+    if source_size != rom.len() {
+        return Err(Error::InvalidValue(format!(
+            "Patch expects a {} byte ROM, found {} bytes", source_size, rom.len()
+        )));
+    }
+
+    let mut output = rom.to_vec();
+    output.resize(target_size, 0);
+
+    let mut offset = 0;
+    while reader.pos < body.len() {
+        offset += reader.varint()?;
+
+        // XOR bytes in until (and including) a zero byte
+        loop {
+            let byte = reader.u8()?;
+            if offset < output.len() {
+                output[offset] ^= byte;
+            }
+            offset += 1;
+
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    check_crc("Patched ROM", &output, target_crc)?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                buf.push(byte | 0x80);
+                break;
+            }
+
+            buf.push(byte);
+            value -= 1;
+        }
+    }
+
+    /// Append the source, target and patch CRCs
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for &value in &[0usize, 1, 127, 128, 129, 300, 16511, 16512, 1 << 24] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+
+            let mut reader = Reader::new(&buf, 0);
+            assert_eq!(reader.varint().unwrap(), value);
+            assert_eq!(reader.pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn ips() {
+        let rom = vec![0u8; 8];
+
+        let mut patch = b"PATCH".to_vec();
+        // Record: 2 bytes at 0x000001
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record: 4 x 0xCC at 0x000006, growing the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+
+        let output = apply(&rom, &patch).unwrap();
+        assert_eq!(output, vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]);
+
+        // Truncation extension
+        patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+        assert_eq!(apply(&rom, &patch).unwrap(), vec![0, 0xAA, 0xBB]);
+
+        // Missing EOF
+        assert!(apply(&rom, &patch[..12]).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        let source: Vec<u8> = (0..16).collect();
+        let mut target = source.clone();
+        target[4..8].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        target.extend_from_slice(&[0, 1, 2, 2, 2, 2]);
+
+        let action = |cmd: usize, len: usize| ((len - 1) << 2) | cmd;
+
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+        write_varint(&mut patch, 0);
+
+        // SourceRead 4 bytes
+        write_varint(&mut patch, action(0, 4));
+        // TargetRead 4 bytes
+        write_varint(&mut patch, action(1, 4));
+        patch.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        // SourceRead the remaining 8 bytes
+        write_varint(&mut patch, action(0, 8));
+        // SourceCopy 3 bytes from offset 0
+        write_varint(&mut patch, action(2, 3));
+        write_varint(&mut patch, 0);
+        // TargetCopy 3 bytes from the last byte written, overlapping
+        write_varint(&mut patch, action(3, 3));
+        write_varint(&mut patch, 18 << 1);
+
+        let patch = finish(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        // Wrong source ROM
+        assert!(apply(&target, &patch).is_err());
+
+        // Corrupted patch
+        let mut corrupted = patch.clone();
+        corrupted[10] ^= 1;
+        assert!(apply(&source, &corrupted).is_err());
+
+        // Target larger than any ROM
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 1 << 40);
+        write_varint(&mut patch, 0);
+        let patch = finish(patch, &source, &target);
+        assert!(apply(&source, &patch).is_err());
+    }
+
+    #[test]
+    fn ups() {
+        let source: Vec<u8> = (0..16).collect();
+        let mut target = source.clone();
+        target[2] = 0xFF;
+        target[3] = 0xFE;
+        target.extend_from_slice(&[0x12, 0x34]);
+
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+
+        // Skip 2 bytes, XOR 2 bytes
+        write_varint(&mut patch, 2);
+        patch.extend_from_slice(&[source[2] ^ 0xFF, source[3] ^ 0xFE, 0x00]);
+
+        // Skip to the end of the source, then XOR the new bytes in
+        write_varint(&mut patch, 16 - 5);
+        patch.extend_from_slice(&[0x12, 0x34, 0x00]);
+
+        let patch = finish(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        // Wrong source ROM
+        assert!(apply(&target, &patch).is_err());
+
+        // Target larger than any ROM
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 1 << 40);
+        let patch = finish(patch, &source, &target);
+        assert!(apply(&source, &patch).is_err());
+    }
+}
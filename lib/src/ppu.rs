@@ -133,6 +133,17 @@ impl FrameBuffer {
     pub fn write(&mut self, x: usize, y: usize, pixel: GameboyRgba) {
         self.data[y * LCD_WIDTH + x] = pixel;
     }
+
+    /// Returns the frame as raw RGBA bytes, row by row.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.data.len() * 4);
+
+        for pixel in self.data.iter() {
+            rgba.extend_from_slice(&[pixel.red, pixel.green, pixel.blue, pixel.alpha]);
+        }
+
+        rgba
+    }
 }
 
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
@@ -168,14 +179,12 @@ impl Vram {
 
     /// Update the active VRAM bank
     pub fn update_bank(&mut self, bank: u8) {
-        let bank = bank & 0x1;
-
         if !self.cgb {
             // No VRAM bank switching on DMG
-            assert!(bank == 0);
+            return;
         }
 
-        self.active_bank = bank;
+        self.active_bank = bank & 0x1;
     }
 
     /// Read a byte from a specific bank
@@ -1136,6 +1145,18 @@ impl Ppu {
             None
         }
     }
+
+    /// Get a reference to the frame buffer, regardless of whether it is ready.
+    pub fn current_frame(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+
+    /// Turn the screen white, as the LCD does when the CPU enters STOP mode. The
+    /// blank frame is ready right away, since the PPU does not run while stopped.
+    pub(crate) fn blank(&mut self) {
+        self.frame_buffer.data.fill(GameboyRgba::white());
+        self.frame_buffer.ready = true;
+    }
 }
 
 impl MemoryRead<u16, u8> for Ppu {
@@ -0,0 +1,265 @@
+//! Rewind support based on in-memory save states.
+//!
+//! Snapshots are taken every few frames and kept in a fixed-size ring buffer.
+//! Only the most recent snapshot is kept in full: every older snapshot is stored
+//! as a delta against the snapshot that follows it. Consecutive states are
+//! mostly identical, so the deltas are usually tiny.
+use std::collections::VecDeque;
+
+use crate::error::Result;
+use crate::Gameboy;
+
+/// Encode `target` as a delta against `base`.
+///
+/// The delta is a sequence of runs, each made up of:
+///
+/// 1. Number of bytes that are unchanged (varint)
+/// 2. Number of bytes that changed (varint)
+/// 3. The changed bytes
+///
+/// Bytes past the end of `base` are treated as zero.
+fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let base_byte = |i: usize| base.get(i).copied().unwrap_or(0);
+
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && target[i] == base_byte(i) {
+            i += 1;
+        }
+        let unchanged = i - start;
+
+        let start = i;
+        while i < target.len() && target[i] != base_byte(i) {
+            i += 1;
+        }
+
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, i - start);
+        delta.extend_from_slice(&target[start..i]);
+    }
+
+    delta
+}
+
+/// Rebuild a buffer of `len` bytes from `base` and a delta produced by `encode`.
+fn decode(base: &[u8], delta: &[u8], len: usize) -> Vec<u8> {
+    let mut target = vec![0u8; len];
+    let n = base.len().min(len);
+    target[..n].copy_from_slice(&base[..n]);
+
+    let mut i = 0;
+    let mut pos = 0;
+    while pos < delta.len() {
+        let unchanged = read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+
+        i += unchanged;
+        target[i..i + changed].copy_from_slice(&delta[pos..pos + changed]);
+        i += changed;
+        pos += changed;
+    }
+
+    target
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+
+        buf.push(byte | 1 << 7);
+    }
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 1 << 7 == 0 {
+            break;
+        }
+    }
+
+    value
+}
+
+/// A snapshot stored as a delta against the next (newer) snapshot
+struct Delta {
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Ring buffer of save states used to rewind gameplay.
+pub struct RewindBuffer {
+    /// Number of frames between snapshots
+    interval: u32,
+
+    /// Maximum number of snapshots to keep
+    capacity: usize,
+
+    /// Frames seen since the last snapshot
+    frames: u32,
+
+    /// Most recent snapshot, stored in full
+    latest: Option<Vec<u8>>,
+
+    /// Older snapshots, oldest first
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    /// Create a buffer that takes a snapshot every `interval` frames and holds
+    /// at most `capacity` snapshots.
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Number of snapshots in the buffer
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Drop all snapshots
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    /// Record a frame of the given Gameboy.
+    ///
+    /// This should be called once per frame. A snapshot is taken every `interval` frames.
+    pub fn frame(&mut self, gameboy: &Gameboy) {
+        self.frames += 1;
+
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(gameboy.save_state());
+        }
+    }
+
+    /// Push a new snapshot into the buffer, evicting the oldest one if full.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            let data = encode(&state, &latest);
+            self.deltas.push_back(Delta { len: latest.len(), data });
+        }
+
+        self.latest = Some(state);
+
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Pop the most recent snapshot off the buffer.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take()?;
+
+        // Rebuild the previous snapshot from this one
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(decode(&state, &delta.data, delta.len));
+        }
+
+        self.frames = 0;
+
+        Some(state)
+    }
+
+    /// Rewind the given Gameboy to the most recent snapshot.
+    ///
+    /// Returns `false` if there was nothing left to rewind to. Unlike
+    /// `Gameboy::load_state`, the save RAM and RTC storage are not rewritten: only
+    /// writes made after rewinding reach them.
+    pub fn rewind(&mut self, gameboy: &mut Gameboy) -> Result<bool> {
+        match self.pop() {
+            Some(state) => {
+                gameboy.restore_state(&state, false)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delta_roundtrip() {
+        let base = vec![1u8, 2, 3, 4, 5, 6, 7, 8];
+
+        // Same length
+        let target = vec![1u8, 9, 3, 4, 0, 0, 7, 8];
+        let delta = encode(&base, &target);
+        assert_eq!(decode(&base, &delta, target.len()), target);
+
+        // Longer target
+        let target = vec![1u8, 2, 3, 4, 5, 6, 7, 8, 0, 0, 10];
+        let delta = encode(&base, &target);
+        assert_eq!(decode(&base, &delta, target.len()), target);
+
+        // Shorter target
+        let target = vec![1u8, 2, 0];
+        let delta = encode(&base, &target);
+        assert_eq!(decode(&base, &delta, target.len()), target);
+
+        // Identical buffers only need a single run
+        let delta = encode(&base, &base);
+        assert_eq!(delta, vec![8, 0]);
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for &value in &[0usize, 1, 127, 128, 300, 1 << 20] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos), value);
+            assert_eq!(pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut buffer = RewindBuffer::new(1, 3);
+
+        for i in 0..5u8 {
+            buffer.push(vec![i; 16 + i as usize]);
+        }
+
+        // Only the last 3 snapshots are kept, and they pop in reverse order
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(), Some(vec![4; 20]));
+        assert_eq!(buffer.pop(), Some(vec![3; 19]));
+        assert_eq!(buffer.pop(), Some(vec![2; 18]));
+        assert_eq!(buffer.pop(), None);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,13 +1,10 @@
 //! Real-time Clock implementation for MBC3.
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
-use std::path::Path;
-
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::cpu::Cpu;
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::storage::Storage;
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct RtcTime {
@@ -92,17 +89,19 @@ impl RtcState {
         // Adjust for skew by getting the actual number of seconds
         // since the last tick
         let now = Utc::now();
-        let seconds = (now - self.timestamp).num_seconds();
-        self.current.seconds += seconds as u8;
+
+        // The host clock may go backwards, so never count negative time
+        let seconds = (now - self.timestamp).num_seconds().clamp(0, 60);
+        self.current.seconds = self.current.seconds.wrapping_add(seconds as u8);
 
         if self.current.seconds > 0x3B {
-            self.current.seconds -= 60;
-            self.current.minutes += 1;
+            self.current.seconds = self.current.seconds.wrapping_sub(60);
+            self.current.minutes = self.current.minutes.wrapping_add(1);
         }
 
         if self.current.minutes > 0x3B {
             self.current.minutes = 0;
-            self.current.hours += 1;
+            self.current.hours = self.current.hours.wrapping_add(1);
         }
 
         if self.current.hours > 0x17 {
@@ -218,7 +217,12 @@ impl RtcState {
         // Figure out the number of seconds, minutes, hours, and days that have
         // occurred since the last RTC timestamp
         let seconds = delta.num_seconds();
-        assert!(seconds >= 0, "RTC timestamp is more recent than current UTC time");
+        if seconds < 0 {
+            log::warn!("RTC timestamp is more recent than current UTC time");
+            self.timestamp = now;
+            return;
+        }
+
         let minutes = seconds / 60;
         let hours = minutes / 60;
         let days = hours / 24;
@@ -235,15 +239,19 @@ impl RtcState {
 /// Real-time Clock implementation for MBC3
 ///
 /// On every tick of the clock, the last UTC timestamp is written
-/// to a backing file, if any.
+/// to a backing storage, if any.
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 pub struct Rtc {
     /// RTC state
     state: RtcState,
 
-    /// RTC state file
+    /// RTC state storage
     #[cfg_attr(feature = "save", serde(skip))]
-    file: Option<File>,
+    storage: Option<Box<dyn Storage>>,
+
+    /// First failed write to the storage, if any (see `Gameboy::take_error`)
+    #[cfg_attr(feature = "save", serde(skip))]
+    error: Option<Error>,
 }
 
 impl Rtc {
@@ -256,15 +264,16 @@ impl Rtc {
     pub fn new() -> Self {
         Self {
             state: RtcState::new(),
-            file: None,
+            storage: None,
+            error: None,
         }
     }
 
     pub fn step(&mut self, cycles: u16, speed: bool) {
         let tick = self.state.step(cycles, speed);
         if tick {
-            // Serialize RTC state to file after each tick
-            self.dump().unwrap();
+            // Serialize RTC state to storage after each tick
+            self.dump_or_record();
         }
     }
 
@@ -283,46 +292,65 @@ impl Rtc {
     pub fn write(&mut self, value: u8) {
         self.state.write(value);
 
-        // Serialize RTC state to file after each write
-        self.dump().unwrap();
+        // Serialize RTC state to storage after each write
+        self.dump_or_record();
+    }
+
+    /// Returns the first storage error since the last call, if any
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Dump the RTC state, keeping track of the first failure
+    fn dump_or_record(&mut self) {
+        if let Err(err) = self.dump() {
+            self.error.get_or_insert(err);
+        }
     }
 
-    /// Dump current RTC state to a file. The file is overwritten
+    /// Dump current RTC state to the storage. The stored state is overwritten
     /// every time.
     fn dump(&mut self) -> Result<()> {
-        if let Some(file) = &mut self.file {
-            file.seek(SeekFrom::Start(0))?;
-            bincode::serialize_into(file, &self.state)?;
+        if let Some(storage) = &mut self.storage {
+            storage.save(&bincode::serialize(&self.state)?)?;
         }
 
         Ok(())
     }
 
-    /// If an RTC file exists, load the state it. Otherwise, create a new state.
+    /// Overwrite the storage, if any, with the state of `rtc`
+    #[cfg(feature = "save")]
+    pub(crate) fn persist(&mut self, rtc: &Rtc) -> Result<()> {
+        if let Some(storage) = &mut self.storage {
+            storage.save(&bincode::serialize(&rtc.state)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take over the storage of `prev`, if any
+    #[cfg(feature = "save")]
+    pub fn restore_from(&mut self, prev: &mut Rtc) {
+        self.storage = prev.storage.take();
+    }
+
+    /// If the storage holds an RTC state, load it. Otherwise, keep the current state.
     ///
     /// Once the state is loaded, adjust current clock based on difference between it and the
     /// last timestamp.
     ///
-    /// If `overwrite` is `true`, overwrite any existing file with the current state. This
-    /// is used when loading from a save state.
-    pub fn with_file<P: AsRef<Path>>(&mut self, rom_path: P, overwrite: bool) -> Result<()> {
-        let rtc_path = rom_path.as_ref().with_extension("rtcs");
-        let rtc_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(rtc_path)?;
-
-        self.file = Some(rtc_file);
-
-        let rtc_file = self.file.as_mut().unwrap();
+    /// If `overwrite` is `true`, overwrite any existing data in the storage with the current
+    /// state. This is used when loading from a save state.
+    pub fn with_storage(&mut self, mut storage: Box<dyn Storage>, overwrite: bool) -> Result<()> {
+        let stored = if overwrite { None } else { storage.load()? };
+        self.storage = Some(storage);
 
         if overwrite {
-            // Overwrite the contents of the backing file with the current RTC state
+            // Overwrite the contents of the storage with the current RTC state
             self.dump()?;
-        } else if rtc_file.metadata()?.len() > 0 {
-            // Load last RTC state from file
-            self.state = bincode::deserialize_from(rtc_file)?;
+        } else if let Some(data) = stored {
+            // Load last RTC state from storage
+            self.state = bincode::deserialize(&data)?;
 
             // If the RTC was not halted, advance the RTC until the current time in UTC
             self.state.advance();
@@ -0,0 +1,304 @@
+//! Memory search
+//!
+//! Finds the addresses that hold a value in RAM, e.g., to build cheats: take a
+//! snapshot of WRAM (all banks), HRAM and cartridge RAM (all banks), then repeatedly
+//! filter the candidates against new snapshots ("the value decreased", "the value is
+//! now 3") until only a few remain.
+use crate::cartridge::Ram as CartridgeRam;
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBus, Ram};
+
+/// How the bytes at an address are interpreted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    U8,
+
+    /// Little-endian
+    U16,
+
+    /// Two BCD digits (0-99)
+    Bcd8,
+
+    /// Four BCD digits (0-9999), with the low digits in the first byte
+    Bcd16,
+}
+
+impl ValueType {
+    fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::Bcd8 => 1,
+            Self::U16 | Self::Bcd16 => 2,
+        }
+    }
+
+    /// Decode the value at the start of `data`. Returns `None` for invalid BCD.
+    fn decode(&self, data: &[u8]) -> Option<u32> {
+        fn bcd(value: u8) -> Option<u32> {
+            let (high, low) = (value >> 4, value & 0xF);
+            if high > 9 || low > 9 {
+                return None;
+            }
+            Some(high as u32 * 10 + low as u32)
+        }
+
+        match self {
+            Self::U8 => Some(data[0] as u32),
+            Self::U16 => Some(u16::from_le_bytes([data[0], data[1]]) as u32),
+            Self::Bcd8 => bcd(data[0]),
+            Self::Bcd16 => Some(bcd(data[1])? * 100 + bcd(data[0])?),
+        }
+    }
+}
+
+impl std::str::FromStr for ValueType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "8" | "u8" => Ok(Self::U8),
+            "16" | "u16" => Ok(Self::U16),
+            "bcd8" => Ok(Self::Bcd8),
+            "bcd16" => Ok(Self::Bcd16),
+            _ => Err(Error::InvalidValue(format!("Unknown value type: {}", s))),
+        }
+    }
+}
+
+/// Which candidates to keep, comparing the new snapshot to the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchFilter {
+    /// Value is equal to the given one
+    Equal(u32),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl SearchFilter {
+    fn matches(&self, previous: u32, current: u32) -> bool {
+        match self {
+            Self::Equal(value) => current == *value,
+            Self::Changed => current != previous,
+            Self::Unchanged => current == previous,
+            Self::Increased => current > previous,
+            Self::Decreased => current < previous,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Wram,
+    Hram,
+    CartridgeRam,
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Wram => write!(f, "WRAM"),
+            Self::Hram => write!(f, "HRAM"),
+            Self::CartridgeRam => write!(f, "SRAM"),
+        }
+    }
+}
+
+/// A candidate address, along with its value in the last two snapshots
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchResult {
+    pub region: Region,
+    pub bank: u16,
+    pub addr: u16,
+    pub value: u32,
+    pub previous: u32,
+}
+
+impl std::fmt::Display for SearchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:02X}:{:04X} = {}", self.region, self.bank, self.addr, self.value)?;
+        if self.value != self.previous {
+            write!(f, " (was {})", self.previous)?;
+        }
+        Ok(())
+    }
+}
+
+/// A bank of memory, as laid out in a snapshot
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    region: Region,
+    bank: u16,
+
+    /// Address the bank is mapped at
+    addr: u16,
+
+    /// Offset of the bank in the snapshot
+    start: usize,
+    len: usize,
+}
+
+pub struct MemorySearch {
+    value_type: ValueType,
+    blocks: Vec<Block>,
+    snapshot: Vec<u8>,
+    previous: Vec<u8>,
+
+    /// Snapshot offsets of the remaining candidates
+    candidates: Vec<usize>,
+}
+
+impl MemorySearch {
+    /// Start a new search, with all addresses holding a valid value as candidates
+    pub(crate) fn new(memory: &MemoryBus, value_type: ValueType) -> Self {
+        let (wram, hram, cartridge_ram) = memory.search_ram();
+        let mut blocks = Vec::new();
+        let mut snapshot = Vec::new();
+
+        let mut add = |region, bank, addr, data: &[u8]| {
+            blocks.push(Block { region, bank, addr, start: snapshot.len(), len: data.len() });
+            snapshot.extend_from_slice(data);
+        };
+
+        for (bank, data) in wram.chunks(Ram::BANK_SIZE).enumerate() {
+            let addr = if bank == 0 { Ram::BASE_ADDR } else { Ram::BASE_ADDR + Ram::BANK_SIZE as u16 };
+            add(Region::Wram, bank as u16, addr, data);
+        }
+        add(Region::Hram, 0, MemoryBus::HRAM_BASE_ADDR, hram);
+        for (bank, data) in cartridge_ram.unwrap_or_default().chunks(CartridgeRam::BANK_SIZE).enumerate() {
+            add(Region::CartridgeRam, bank as u16, CartridgeRam::BASE_ADDR, data);
+        }
+
+        // Values do not span banks
+        let size = value_type.size();
+        let candidates = blocks
+            .iter()
+            .flat_map(|block| block.start..(block.start + block.len + 1).saturating_sub(size))
+            .filter(|&offset| value_type.decode(&snapshot[offset..]).is_some())
+            .collect();
+
+        Self {
+            value_type,
+            blocks,
+            previous: snapshot.clone(),
+            snapshot,
+            candidates,
+        }
+    }
+
+    /// Take a new snapshot, and only keep the candidates that match the filter.
+    /// Returns the number of candidates left.
+    pub(crate) fn filter(&mut self, memory: &MemoryBus, filter: SearchFilter) -> usize {
+        let (wram, hram, cartridge_ram) = memory.search_ram();
+        let mut snapshot = Vec::with_capacity(self.snapshot.len());
+        snapshot.extend_from_slice(wram);
+        snapshot.extend_from_slice(hram);
+        snapshot.extend_from_slice(cartridge_ram.unwrap_or_default());
+
+        // The memory layout changed (e.g., a different cartridge was inserted)
+        if snapshot.len() != self.snapshot.len() {
+            self.candidates.clear();
+        }
+
+        let value_type = self.value_type;
+        let previous = &self.snapshot;
+        self.candidates.retain(|&offset| {
+            match (value_type.decode(&previous[offset..]), value_type.decode(&snapshot[offset..])) {
+                (Some(previous), Some(current)) => filter.matches(previous, current),
+                _ => false,
+            }
+        });
+
+        self.previous = std::mem::replace(&mut self.snapshot, snapshot);
+        self.candidates.len()
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    /// Number of candidates left
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// The first `count` candidates: WRAM by bank, then HRAM, then cartridge RAM by bank
+    pub fn results(&self, count: usize) -> Vec<SearchResult> {
+        self.candidates
+            .iter()
+            .take(count)
+            .map(|&offset| {
+                let block = self.blocks.iter().find(|block| (block.start..block.start + block.len).contains(&offset)).unwrap();
+
+                SearchResult {
+                    region: block.region,
+                    bank: block.bank,
+                    addr: block.addr + (offset - block.start) as u16,
+                    value: self.value_type.decode(&self.snapshot[offset..]).unwrap(),
+                    previous: self.value_type.decode(&self.previous[offset..]).unwrap(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryWrite;
+
+    #[test]
+    fn search() {
+        let mut memory = MemoryBus::new(true);
+        memory.write(0xC010u16, 5u8);
+        memory.write(0xFF90u16, 5u8);
+
+        let mut search = MemorySearch::new(&memory, ValueType::U8);
+        assert_eq!(search.len(), 8 * 0x1000 + 0x7F + 0x2000);
+
+        assert_eq!(search.filter(&memory, SearchFilter::Equal(5)), 2);
+
+        // One value decreases, the other does not change
+        memory.write(0xC010u16, 4u8);
+        assert_eq!(search.filter(&memory, SearchFilter::Unchanged), 1);
+        assert_eq!(search.results(10)[0].addr, 0xFF90);
+
+        let mut search = MemorySearch::new(&memory, ValueType::U8);
+        memory.write(0xC010u16, 0u8);
+        memory.write(0xFF90u16, 3u8);
+        assert_eq!(search.filter(&memory, SearchFilter::Decreased), 2);
+
+        let results = search.results(10);
+        assert_eq!(results[0], SearchResult { region: Region::Wram, bank: 0, addr: 0xC010, value: 0, previous: 4 });
+        assert_eq!(results[1].to_string(), "HRAM 00:FF90 = 3 (was 5)");
+    }
+
+    #[test]
+    fn banks() {
+        let mut memory = MemoryBus::new(true);
+
+        // WRAM bank 3, switched out
+        memory.write(0xFF70u16, 3u8);
+        memory.write(0xD123u16, 0x34u8);
+        memory.write(0xD124u16, 0x12u8);
+        memory.write(0xFF70u16, 1u8);
+
+        let mut search = MemorySearch::new(&memory, ValueType::Bcd16);
+        assert_eq!(search.filter(&memory, SearchFilter::Equal(1234)), 1);
+        assert_eq!(search.results(1)[0].bank, 3);
+        assert_eq!(search.results(1)[0].addr, 0xD123);
+
+        let mut search = MemorySearch::new(&memory, ValueType::U16);
+        assert_eq!(search.filter(&memory, SearchFilter::Equal(0x1234)), 1);
+
+        memory.write(0xC000u16, 0xABu8);
+
+        // 16-bit values do not span banks, and invalid BCD is never a candidate
+        assert_eq!(MemorySearch::new(&memory, ValueType::U16).len(), 8 * 0xFFF + 0x7E + 0x1FFF);
+        assert_eq!(MemorySearch::new(&memory, ValueType::Bcd8).len(), 8 * 0x1000 + 0x7F + 0x2000 - 1);
+    }
+}
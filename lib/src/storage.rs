@@ -0,0 +1,146 @@
+//! Persistent storage for battery-backed cartridge RAM and the RTC.
+//!
+//! By default, a cartridge loaded from a file stores its battery RAM in `<rom>.sav`
+//! and its RTC state in `<rom>.rtcs`. Any other backend (e.g., browser local storage)
+//! can be plugged in by implementing `Storage` and passing it to the `Cartridge`.
+#[cfg(feature = "fs")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "fs")]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+
+pub trait Storage: Send {
+    /// Returns the stored data, or `None` if nothing has been stored yet.
+    fn load(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// Replace the stored data.
+    fn save(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Overwrite part of the stored data, starting at `offset`.
+    ///
+    /// This is called on every write to battery-backed RAM, so it should be cheap.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+/// Storage backed by a file on disk
+#[cfg(feature = "fs")]
+pub struct FileStorage {
+    file: File,
+}
+
+#[cfg(feature = "fs")]
+impl FileStorage {
+    /// Open the file at the given path, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Ok(Self { file })
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+
+        if data.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(data))
+        }
+    }
+
+    fn save(&mut self, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(data)?;
+        self.file.set_len(data.len() as u64)?;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+}
+
+/// Storage kept in memory.
+///
+/// Clones share the same data, so a clone can be kept around to read the data
+/// back out (e.g., to persist it elsewhere) while the emulator is running.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Storage that starts out with the given data
+    pub fn with_data(data: Vec<u8>) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(Some(data))),
+        }
+    }
+
+    /// Returns a copy of the stored data
+    pub fn data(&self) -> Option<Vec<u8>> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.data())
+    }
+
+    fn save(&mut self, data: &[u8]) -> Result<()> {
+        *self.data.lock().unwrap() = Some(data.to_vec());
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let mut stored = self.data.lock().unwrap();
+        let stored = stored.get_or_insert_with(Vec::new);
+
+        if stored.len() < offset + data.len() {
+            stored.resize(offset + data.len(), 0);
+        }
+
+        stored[offset..offset + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_storage() {
+        let mut storage = MemoryStorage::new();
+        let handle = storage.clone();
+
+        assert_eq!(storage.load().unwrap(), None);
+
+        storage.save(&[1, 2, 3]).unwrap();
+        storage.write(1, &[9]).unwrap();
+        storage.write(4, &[7]).unwrap();
+
+        assert_eq!(handle.data(), Some(vec![1, 9, 3, 0, 7]));
+    }
+}
@@ -0,0 +1,174 @@
+//! Symbol files
+//!
+//! Symbols map `bank:address` pairs to labels, so that addresses can be shown as
+//! `label+offset` in traces and in the debugger. The format is the one written by
+//! RGBDS (`rgblink -n`) and read by no$gmb, BGB and Emulicious:
+//!
+//! ```text
+//! ; Comment
+//! 00:0150 Start
+//! 01:4A2C PlayerUpdate
+//! ```
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// Labels, by (bank, address)
+    labels: BTreeMap<(u16, u16), String>,
+
+    /// (bank, address) of each label, by name
+    names: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    /// Parse a symbol file
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut symbols = Self::default();
+
+        for (i, line) in input.lines().enumerate() {
+            // Strip comments, and skip section headers (e.g., "[labels]")
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+
+            let invalid = || Error::InvalidValue(format!("Invalid symbol on line {}: {}", i + 1, line));
+
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (bank, addr) = location.split_once(':').ok_or_else(invalid)?;
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| invalid())?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| invalid())?;
+
+            symbols.insert(bank, addr, name.trim());
+        }
+
+        Ok(symbols)
+    }
+
+    /// Load symbols from a file
+    #[cfg(feature = "fs")]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Add a label. If there already is one at this location, the first one is kept.
+    pub fn insert(&mut self, bank: u16, addr: u16, name: &str) {
+        self.labels.entry((bank, addr)).or_insert_with(|| name.to_string());
+        self.names.entry(name.to_string()).or_insert((bank, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Find the closest label at or before `addr` in the same bank and memory region.
+    /// Returns the label and the offset of `addr` from it.
+    pub fn lookup(&self, bank: u16, addr: u16) -> Option<(&str, u16)> {
+        let start = Self::region_start(addr);
+
+        self.labels
+            .range((bank, start)..=(bank, addr))
+            .next_back()
+            .map(|(&(_, label_addr), name)| (name.as_str(), addr - label_addr))
+    }
+
+    /// Format `addr` as `label` or `label+offset`, if there is a label for it
+    pub fn format(&self, bank: u16, addr: u16) -> Option<String> {
+        self.lookup(bank, addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            offset => format!("{}+{:#X}", name, offset),
+        })
+    }
+
+    /// Find the (bank, address) of a label, optionally followed by an offset: `label+0x10`
+    pub fn resolve(&self, input: &str) -> Option<(u16, u16)> {
+        let (name, offset) = match input.split_once('+') {
+            Some((name, offset)) => {
+                let offset = match offset.strip_prefix("0x").or_else(|| offset.strip_prefix("0X")) {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+                    None => offset.parse().ok()?,
+                };
+                (name, offset)
+            }
+            None => (input, 0),
+        };
+
+        let (bank, addr) = *self.names.get(name)?;
+        Some((bank, addr.wrapping_add(offset)))
+    }
+
+    /// Start address of the memory region `addr` is in. Labels do not extend past the
+    /// start of a region: e.g., the last label of ROM bank 0 does not cover VRAM.
+    fn region_start(addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x3FFF => 0x0000,
+            0x4000..=0x7FFF => 0x4000,
+            0x8000..=0x9FFF => 0x8000,
+            0xA000..=0xBFFF => 0xA000,
+            0xC000..=0xCFFF => 0xC000,
+            0xD000..=0xDFFF => 0xD000,
+            0xE000..=0xFDFF => 0xE000,
+            0xFE00..=0xFE9F => 0xFE00,
+            0xFEA0..=0xFEFF => 0xFEA0,
+            0xFF00..=0xFF7F => 0xFF00,
+            0xFF80..=0xFFFF => 0xFF80,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SYM: &str = "
+; File generated by rgblink
+[labels]
+00:0150 Start
+00:3FF0 Bank0End ; trailing comment
+01:4A2C PlayerUpdate
+01:4A40 PlayerUpdate.move
+02:4000 EnemyUpdate
+00:C000 wPlayerX
+";
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+
+        assert_eq!(symbols.format(0, 0x0150).as_deref(), Some("Start"));
+        assert_eq!(symbols.format(0, 0x0154).as_deref(), Some("Start+0x4"));
+        assert_eq!(symbols.format(1, 0x4A30).as_deref(), Some("PlayerUpdate+0x4"));
+        assert_eq!(symbols.format(1, 0x4A41).as_deref(), Some("PlayerUpdate.move+0x1"));
+        assert_eq!(symbols.format(2, 0x4A30).as_deref(), Some("EnemyUpdate+0xA30"));
+
+        // Nothing before the first label of the bank or region
+        assert_eq!(symbols.format(0, 0x0100), None);
+        assert_eq!(symbols.format(3, 0x4A30), None);
+        assert_eq!(symbols.format(0, 0x8000), None);
+        assert_eq!(symbols.format(0, 0xC001).as_deref(), Some("wPlayerX+0x1"));
+    }
+
+    #[test]
+    fn resolve() {
+        let symbols = Symbols::parse(SYM).unwrap();
+
+        assert_eq!(symbols.resolve("PlayerUpdate"), Some((1, 0x4A2C)));
+        assert_eq!(symbols.resolve("PlayerUpdate+4"), Some((1, 0x4A30)));
+        assert_eq!(symbols.resolve("PlayerUpdate+0x10"), Some((1, 0x4A3C)));
+        assert_eq!(symbols.resolve("Missing"), None);
+        assert_eq!(symbols.resolve("Start+x"), None);
+
+        assert!(Symbols::parse("00:0150").is_err());
+        assert!(Symbols::parse("0150 Start").is_err());
+        assert!(Symbols::parse("zz:0150 Start").is_err());
+    }
+}
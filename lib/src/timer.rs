@@ -1,19 +1,23 @@
 /// Internal CGB timer functionality
+///
+/// Both DIV and TIMA are driven by a single 16-bit system counter that is incremented
+/// every clock cycle. DIV is the upper byte of this counter, and TIMA is incremented
+/// on the falling edge of one of its bits (selected by TAC), ANDed with the TAC enable
+/// flag. This is what causes the DIV write and TAC change "glitches".
+///
+/// See: https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 pub struct Timer {
-    /// Divider register (0xFF04)
+    /// Internal system counter
     ///
-    /// Ticks at frequency = Cpu::CYCLE_TIME * 256
-    ///
-    /// It is affected by CGB double-speed mode.
-    div: u8,
-    div_counter: u16,
+    /// Divider register (0xFF04) is the upper 8 bits of this counter, so it ticks
+    /// once per 256 clock cycles. It is affected by CGB double-speed mode.
+    counter: u16,
 
     /// Timer counter (0xFF05)
     ///
     /// Ticks at frequency specified by TAC register.
     tima: u8,
-    tima_counter: u16,
 
     /// Timer modulo (0xFF06)
     ///
@@ -25,6 +29,14 @@ pub struct Timer {
     /// Bit 2: Timer enable
     /// Bits 0-1: Clock select
     tac: u8,
+
+    /// TIMA overflowed during the last M-cycle, and reads as 0x00 until TMA is
+    /// loaded (and the interrupt requested) at the end of the next one.
+    overflow: bool,
+
+    /// TMA was loaded into TIMA during the last M-cycle. Writes to TIMA are ignored,
+    /// and writes to TMA go through to TIMA as well.
+    reloading: bool,
 }
 
 impl Timer {
@@ -33,14 +45,17 @@ impl Timer {
     /// As a ratio, this is equal to 256 CPU clock cycles.
     pub const TIMER_RATIO: u16 = 256;
 
+    /// Clock cycles per M-cycle
+    const M_CYCLE: u16 = 4;
+
     pub fn new() -> Self {
         Self {
-            div: 0,
-            div_counter: 0,
+            counter: 0,
             tima: 0,
-            tima_counter: 0,
             tma: 0,
             tac: 0,
+            overflow: false,
+            reloading: false,
         }
     }
 
@@ -49,59 +64,79 @@ impl Timer {
         (self.tac & 1 << 2) != 0
     }
 
-    /// This function is called once per CPU step from the main Gameboy loop.
+    /// This function is called by the CPU for every M-cycle it accesses memory in, and
+    /// for the rest of the CPU step from the main Gameboy loop.
     ///
-    /// Returns `true` if an interrupt should be triggered.
+    /// The timer is advanced one M-cycle at a time. Returns `true` if an interrupt
+    /// should be triggered.
     pub fn step(&mut self, cycles: u16) -> bool {
-        // DIV: Ticks once per 256 clock cycles
-        // The TAC enable flag does not affect this
-        self.div_counter += cycles;
-        if self.div_counter >= 256 {
-            self.div = self.div.wrapping_add(1);
-            self.div_counter -= 256;
-        }
-
-        if !self.enabled() {
-            return false;
-        }
-
-        let threshold = self.tima_ratio();
         let mut interrupt = false;
 
-        self.tima_counter += cycles;
-        while self.tima_counter >= threshold {
-            let tima = self.tima.wrapping_add(1);
+        for _ in 0..cycles / Self::M_CYCLE {
+            self.reloading = false;
 
-            self.tima = if tima < self.tima {
-                // When TIMA overflows, load TMA into TIMA, and trigger an interrupt
+            if self.overflow {
+                // TIMA was 0x00 for a full M-cycle: reload it from TMA and
+                // trigger the interrupt
+                self.overflow = false;
+                self.reloading = true;
+                self.tima = self.tma;
                 interrupt = true;
-                self.tma
-            } else {
-                tima
-            };
+            }
 
-            self.tima_counter -= threshold;
+            let counter = self.counter.wrapping_add(Self::M_CYCLE);
+            self.set_counter(counter);
         }
 
         interrupt
     }
 
-    /// Returns the ratio of CPU clock cycle time to current TIMA clock
-    /// mode in TAC
+    /// Returns the bit of the system counter that drives TIMA in the current
+    /// TAC clock mode
     #[inline]
-    fn tima_ratio(&self) -> u16 {
+    fn tima_bit(&self) -> u16 {
         match self.tac & 0x3 {
-            0 => Self::TIMER_RATIO * 4,
-            1 => Self::TIMER_RATIO / 16,
-            2 => Self::TIMER_RATIO / 4,
-            3 => Self::TIMER_RATIO,
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            3 => 7, // 16384 Hz
             _ => unreachable!(),
         }
     }
 
+    /// Input to the TIMA falling edge detector
+    #[inline]
+    fn signal(&self) -> bool {
+        self.enabled() && self.counter & 1 << self.tima_bit() != 0
+    }
+
+    /// Update the system counter, and increment TIMA on a falling edge
+    fn set_counter(&mut self, counter: u16) {
+        let prev = self.signal();
+        self.counter = counter;
+        self.check_falling_edge(prev);
+    }
+
+    #[inline]
+    fn check_falling_edge(&mut self, prev: bool) {
+        if prev && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+
+        if overflow {
+            // TIMA stays at 0x00 for one M-cycle before TMA is loaded
+            self.overflow = true;
+        }
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
             0xFF07 => self.tac,
@@ -112,15 +147,112 @@ impl Timer {
     pub fn write(&mut self, addr: u16, value: u8) {
         match addr {
             0xFF04 => {
-                // Writes to DIV reset the register
-                self.div = 0;
-                self.div_counter = 0;
-                self.tima_counter = 0;
+                // Writes to DIV reset the whole system counter, which can cause
+                // a falling edge on the TIMA input
+                self.set_counter(0);
+            }
+            0xFF05 => {
+                if self.reloading {
+                    // TMA was just loaded, so this write is lost
+                    return;
+                }
+
+                // A write during the overflow cycle cancels the reload and the interrupt
+                self.overflow = false;
+                self.tima = value;
+            }
+            0xFF06 => {
+                self.tma = value;
+
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                // Disabling the timer or switching the clock select can cause a
+                // falling edge on the TIMA input
+                let prev = self.signal();
+                self.tac = value;
+                self.check_falling_edge(prev);
             }
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
-            0xFF07 => self.tac = value,
             _ => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Timer enabled, running at 262144 Hz (TIMA ticks every 16 cycles)
+    const TAC_FAST: u8 = 0b101;
+
+    #[test]
+    fn div_ticks() {
+        let mut timer = Timer::new();
+        timer.step(252);
+        assert_eq!(timer.read(0xFF04), 0);
+        timer.step(4);
+        assert_eq!(timer.read(0xFF04), 1);
+
+        // Writing any value resets DIV
+        timer.write(0xFF04, 0x12);
+        assert_eq!(timer.read(0xFF04), 0);
+    }
+
+    #[test]
+    fn tima_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, TAC_FAST);
+        timer.step(16);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // Bit 3 of the counter is set: resetting DIV causes an extra increment
+        timer.step(8);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 2);
+
+        // Same for disabling the timer
+        timer.step(8);
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.read(0xFF05), 3);
+
+        // Nothing happens when the bit is not set
+        timer.write(0xFF04, 0);
+        timer.write(0xFF07, TAC_FAST);
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.read(0xFF05), 3);
+    }
+
+    #[test]
+    fn tima_reload() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, TAC_FAST);
+
+        // TIMA reads 0x00 for one M-cycle before TMA is loaded
+        assert!(!timer.step(16));
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(timer.step(4));
+        assert_eq!(timer.read(0xFF05), 0x80);
+
+        // Writes to TIMA during the reload cycle are ignored, but TMA goes through
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x80);
+        timer.write(0xFF06, 0x90);
+        assert_eq!(timer.read(0xFF05), 0x90);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut timer = Timer::new();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, TAC_FAST);
+        timer.step(16);
+
+        timer.write(0xFF05, 0x42);
+        assert!(!timer.step(4));
+        assert_eq!(timer.read(0xFF05), 0x42);
+    }
+}
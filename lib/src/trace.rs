@@ -0,0 +1,370 @@
+//! Execution traces
+//!
+//! A trace records the CPU state before every instruction executed. The following
+//! formats are supported:
+//!
+//! * `Verbose`: all registers and flags, one per line, followed by the instruction.
+//! * `Compact`: one line per instruction, with the bank, PC, registers and instruction.
+//! * `Doctor`: one line per instruction, in the format used by
+//!   [Gameboy Doctor](https://github.com/robert/gameboy-doctor), so that traces can be
+//!   diffed against its reference logs:
+//!   `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+//! * `Binary`: a `GBCT` magic and a version byte, followed by one 17 byte record per
+//!   instruction: PC, bank, A, F, B, C, D, E, H, L, SP and the 3 bytes at PC. 16-bit
+//!   values are little-endian.
+//!
+//! Tracing can be started and stopped when PC reaches an address, or at a given frame.
+use std::io::{BufWriter, Write};
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::error::{Error, Result};
+use crate::instructions::Instruction;
+use crate::memory::MemoryRead;
+use crate::registers::{Reg16, Reg8, RegisterOps};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Verbose,
+    Compact,
+    Doctor,
+    Binary,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "verbose" => Ok(Self::Verbose),
+            "compact" => Ok(Self::Compact),
+            "doctor" => Ok(Self::Doctor),
+            "binary" => Ok(Self::Binary),
+            _ => Err(Error::InvalidValue(format!("Unknown trace format: {}", s))),
+        }
+    }
+}
+
+/// Condition that starts or stops a trace
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceTrigger {
+    /// PC reaches the address
+    Pc(u16),
+
+    /// The given number of frames were completed since the trace was created
+    Frame(u64),
+}
+
+impl std::str::FromStr for TraceTrigger {
+    type Err = Error;
+
+    /// Parse `pc:<addr>` (hex) or `frame:<n>`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidValue(format!("Invalid trace trigger: {}", s));
+
+        match s.split_once(':').ok_or_else(invalid)? {
+            ("pc", addr) => {
+                let addr = addr.strip_prefix("0x").unwrap_or(addr);
+                u16::from_str_radix(addr, 16).map(Self::Pc).map_err(|_| invalid())
+            }
+            ("frame", frame) => frame.parse().map(Self::Frame).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceConfig {
+    pub format: TraceFormat,
+
+    /// Start tracing once this triggers. Tracing starts right away if not set.
+    pub start: Option<TraceTrigger>,
+
+    /// Stop tracing once this triggers. A stopped trace does not start again.
+    pub stop: Option<TraceTrigger>,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            format: TraceFormat::Verbose,
+            start: None,
+            stop: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Waiting,
+    Active,
+    Done,
+}
+
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    config: TraceConfig,
+    state: State,
+
+    /// Number of frames completed since the trace was created
+    frame: u64,
+
+    /// Set once the binary header is written
+    header: bool,
+}
+
+impl Tracer {
+    const BINARY_MAGIC: &'static [u8] = b"GBCT";
+    const BINARY_VERSION: u8 = 1;
+
+    pub fn new(writer: Box<dyn Write + Send>, config: TraceConfig) -> Self {
+        let state = if config.start.is_none() { State::Active } else { State::Waiting };
+
+        Self {
+            writer: BufWriter::new(writer),
+            config,
+            state,
+            frame: 0,
+            header: false,
+        }
+    }
+
+    /// Trace to a file, which is created (or truncated)
+    #[cfg(feature = "fs")]
+    pub fn create<P: AsRef<Path>>(path: P, config: TraceConfig) -> Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(Self::new(Box::new(file), config))
+    }
+
+    pub fn config(&self) -> &TraceConfig {
+        &self.config
+    }
+
+    /// Returns `true` if instructions are currently written to the trace
+    pub fn active(&self) -> bool {
+        self.state == State::Active
+    }
+
+    /// Returns `true` once the stop trigger fired
+    pub fn done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Update the state based on a trigger that may have fired
+    fn update(&mut self, fired: impl Fn(TraceTrigger) -> bool) -> std::io::Result<()> {
+        if self.state == State::Waiting && self.config.start.is_some_and(&fired) {
+            self.state = State::Active;
+        }
+
+        if self.state != State::Done && self.config.stop.is_some_and(&fired) {
+            self.state = State::Done;
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Called at the start of every VBLANK. The trace is flushed once per frame.
+    pub(crate) fn frame(&mut self) -> std::io::Result<()> {
+        self.frame += 1;
+
+        let frame = self.frame;
+        self.update(|trigger| trigger == TraceTrigger::Frame(frame))?;
+
+        if self.state == State::Active {
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Trace the instruction at PC, before it is executed
+    pub(crate) fn trace(&mut self, cpu: &Cpu, inst: &Instruction) -> std::io::Result<()> {
+        let pc = cpu.registers.PC;
+        self.update(|trigger| trigger == TraceTrigger::Pc(pc))?;
+
+        if self.state != State::Active {
+            return Ok(());
+        }
+
+        let r = &cpu.registers;
+        let (memory_type, bank) = cpu.memory.memory_info(pc);
+        let mem: [u8; 4] = [0, 1, 2, 3].map(|i| cpu.memory.read(pc.wrapping_add(i)));
+        let f = &mut self.writer;
+
+        match self.config.format {
+            TraceFormat::Verbose => {
+                // First, write the register state prior to executing this instruction
+                write!(f, "{}\n\n", r)?;
+
+                // Then write the instruction, along with the label it belongs to
+                match cpu.symbols().and_then(|symbols| symbols.format(bank, pc)) {
+                    Some(label) => write!(f, "{:03}:{}:{:#06X} <{}> - {}\n\n", bank, memory_type, pc, label, inst)?,
+                    None => write!(f, "{:03}:{}:{:#06X} - {}\n\n", bank, memory_type, pc, inst)?,
+                }
+            }
+            TraceFormat::Compact => {
+                write!(
+                    f,
+                    "{:03}:{:04X} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} {}",
+                    bank,
+                    pc,
+                    r.read(Reg16::AF),
+                    r.read(Reg16::BC),
+                    r.read(Reg16::DE),
+                    r.read(Reg16::HL),
+                    r.SP,
+                    inst,
+                )?;
+
+                match cpu.symbols().and_then(|symbols| symbols.format(bank, pc)) {
+                    Some(label) => writeln!(f, " ; {}", label)?,
+                    None => writeln!(f)?,
+                }
+            }
+            TraceFormat::Doctor => {
+                writeln!(
+                    f,
+                    "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                    r.read(Reg8::A),
+                    r.read(Reg8::F),
+                    r.read(Reg8::B),
+                    r.read(Reg8::C),
+                    r.read(Reg8::D),
+                    r.read(Reg8::E),
+                    r.read(Reg8::H),
+                    r.read(Reg8::L),
+                    r.SP,
+                    pc,
+                    mem[0],
+                    mem[1],
+                    mem[2],
+                    mem[3],
+                )?;
+            }
+            TraceFormat::Binary => {
+                if !self.header {
+                    f.write_all(Self::BINARY_MAGIC)?;
+                    f.write_all(&[Self::BINARY_VERSION])?;
+                    self.header = true;
+                }
+
+                let mut record = [0u8; 17];
+                record[0..2].copy_from_slice(&pc.to_le_bytes());
+                record[2..4].copy_from_slice(&bank.to_le_bytes());
+                for (i, reg) in [Reg8::A, Reg8::F, Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L].iter().enumerate() {
+                    record[4 + i] = r.read(*reg);
+                }
+                record[12..14].copy_from_slice(&r.SP.to_le_bytes());
+                record[14..17].copy_from_slice(&mem[..3]);
+
+                f.write_all(&record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryWrite;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer that can be read back once the tracer is done with it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// CPU running NOPs from 0x100, with known registers
+    fn get_cpu() -> Cpu {
+        let mut cpu = Cpu::new(true);
+        cpu.registers.write(Reg16::AF, 0x01B0);
+        cpu.registers.write(Reg16::BC, 0x0013);
+        cpu.registers.write(Reg16::DE, 0x00D8);
+        cpu.registers.write(Reg16::HL, 0x014D);
+        cpu.registers.SP = 0xFFFE;
+        cpu.registers.PC = 0x100;
+        cpu.memory.controller().rom.write(0x101u16, 0xC3u8);
+        cpu
+    }
+
+    fn run(config: TraceConfig, steps: usize, frames: &[usize]) -> Vec<u8> {
+        let output = Shared::default();
+        let mut cpu = get_cpu();
+        cpu.set_tracer(Some(Tracer::new(Box::new(output.clone()), config)));
+
+        for step in 0..steps {
+            if frames.contains(&step) {
+                cpu.trace_frame();
+            }
+
+            // Skip over the JP at 0x101, so that only NOPs run
+            let pc = cpu.registers.PC;
+            cpu.step();
+            cpu.registers.PC = if pc == 0x100 { 0x102 } else { pc + 1 };
+        }
+
+        cpu.set_tracer(None);
+        let output = output.0.lock().unwrap().clone();
+        output
+    }
+
+    #[test]
+    fn doctor() {
+        let config = TraceConfig { format: TraceFormat::Doctor, ..Default::default() };
+        let output = String::from_utf8(run(config, 2, &[])).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,00,00");
+        assert!(lines[1].ends_with("PC:0102 PCMEM:00,00,00,00"));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn binary() {
+        let config = TraceConfig { format: TraceFormat::Binary, ..Default::default() };
+        let output = run(config, 2, &[]);
+
+        assert_eq!(&output[..5], b"GBCT\x01");
+        assert_eq!(output.len(), 5 + 2 * 17);
+        assert_eq!(&output[5..22], &[0x00, 0x01, 0, 0, 0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D, 0xFE, 0xFF, 0x00, 0xC3, 0x00]);
+    }
+
+    #[test]
+    fn triggers() {
+        let compact = TraceConfig { format: TraceFormat::Compact, ..Default::default() };
+
+        // PC 0x100, 0x102, 0x103, ...: start at 0x103, stop at 0x106 (not traced)
+        let config = TraceConfig { start: Some(TraceTrigger::Pc(0x103)), stop: Some(TraceTrigger::Pc(0x106)), ..compact };
+        let output = String::from_utf8(run(config, 10, &[])).unwrap();
+        let pcs: Vec<&str> = output.lines().map(|line| &line[4..8]).collect();
+        assert_eq!(pcs, ["0103", "0104", "0105"]);
+
+        // Frames are counted from 1
+        let config = TraceConfig { start: Some(TraceTrigger::Frame(1)), stop: Some(TraceTrigger::Frame(2)), ..compact };
+        let output = String::from_utf8(run(config, 10, &[2, 5])).unwrap();
+        assert_eq!(output.lines().count(), 3);
+
+        assert_eq!("pc:0x150".parse::<TraceTrigger>().unwrap(), TraceTrigger::Pc(0x150));
+        assert_eq!("frame:60".parse::<TraceTrigger>().unwrap(), TraceTrigger::Frame(60));
+        assert!("pc:zz".parse::<TraceTrigger>().is_err());
+        assert!("150".parse::<TraceTrigger>().is_err());
+        assert_eq!("doctor".parse::<TraceFormat>().unwrap(), TraceFormat::Doctor);
+    }
+}
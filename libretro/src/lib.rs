@@ -0,0 +1,518 @@
+//! libretro core for the `gbc` library.
+//!
+//! Build with `cargo build --release -p gbc-libretro` and load the resulting shared
+//! library (e.g., `libgbc_libretro.so`) in RetroArch.
+//!
+//! The frontend owns battery saves: cartridge RAM is exposed through
+//! `retro_get_memory_data`, so the core never touches the filesystem.
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::sync::Mutex;
+
+use gbc::cartridge::Cartridge;
+use gbc::joypad::{JoypadEvent, JoypadInput};
+use gbc::ppu::{LCD_HEIGHT, LCD_WIDTH};
+use gbc::Gameboy;
+
+mod ffi;
+use ffi::*;
+
+/// The Gameboy has no APU yet, so the frontend is fed silence at this rate
+const SAMPLE_RATE: f64 = 44100.0;
+
+/// Mapping from libretro joypad IDs to Gameboy inputs
+const INPUTS: [(c_uint, JoypadInput); 8] = [
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, JoypadInput::Right),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, JoypadInput::Left),
+    (RETRO_DEVICE_ID_JOYPAD_UP, JoypadInput::Up),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, JoypadInput::Down),
+    (RETRO_DEVICE_ID_JOYPAD_A, JoypadInput::A),
+    (RETRO_DEVICE_ID_JOYPAD_B, JoypadInput::B),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, JoypadInput::Select),
+    (RETRO_DEVICE_ID_JOYPAD_START, JoypadInput::Start),
+];
+
+/// Callbacks registered by the frontend
+struct Callbacks {
+    environment: Option<retro_environment_t>,
+    video_refresh: Option<retro_video_refresh_t>,
+    audio_sample_batch: Option<retro_audio_sample_batch_t>,
+    input_poll: Option<retro_input_poll_t>,
+    input_state: Option<retro_input_state_t>,
+    log: Option<retro_log_printf_t>,
+}
+
+struct Core {
+    gameboy: Gameboy,
+
+    /// Last frame, converted to XRGB8888
+    video: Vec<u32>,
+
+    /// Silence, one frame's worth
+    audio: Vec<i16>,
+
+    /// Enabled cheats, by frontend index
+    cheats: BTreeMap<c_uint, String>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+    log: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+/// Log a message through the frontend, if it provides a logger
+fn log(callbacks: &Callbacks, level: c_uint, msg: &str) {
+    if let Some(log) = callbacks.log {
+        let msg = CString::new(msg.replace('\0', " ")).unwrap();
+        unsafe { log(level, b"%s\n\0".as_ptr() as *const c_char, msg.as_ptr()) };
+    }
+}
+
+fn fps() -> f64 {
+    1e9 / Gameboy::FRAME_DURATION as f64
+}
+
+impl Core {
+    fn new(gameboy: Gameboy) -> Self {
+        let samples = (SAMPLE_RATE / fps()).round() as usize;
+
+        Self {
+            gameboy,
+            video: vec![0; LCD_WIDTH * LCD_HEIGHT],
+            audio: vec![0; samples * 2],
+            cheats: BTreeMap::new(),
+        }
+    }
+
+    /// Replace the cheats of the Gameboy with the enabled frontend cheats
+    fn update_cheats(&mut self) {
+        while self.gameboy.remove_cheat(0).is_some() {}
+
+        // Frontends may combine several codes in one cheat. Invalid codes are skipped.
+        for code in self.cheats.values().flat_map(|code| code.split(['+', ';'])) {
+            self.gameboy.add_cheat(code).ok();
+        }
+    }
+
+    /// Turn the pressed inputs into events, based on what the Gameboy last saw
+    fn poll_joypad(&mut self, input_state: retro_input_state_t) -> Vec<JoypadEvent> {
+        let state = self.gameboy.joypad_state();
+
+        INPUTS
+            .iter()
+            .filter_map(|(id, input)| {
+                let pressed = input_state(0, RETRO_DEVICE_JOYPAD, 0, *id) != 0;
+                let was_pressed = state & input.mask() != 0;
+
+                match (pressed, was_pressed) {
+                    (true, false) => Some(JoypadEvent::Down(*input)),
+                    (false, true) => Some(JoypadEvent::Up(*input)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn update_video(&mut self) {
+        let rgba = self.gameboy.frame_buffer().to_rgba();
+
+        for (pixel, rgba) in self.video.iter_mut().zip(rgba.chunks_exact(4)) {
+            *pixel = (rgba[0] as u32) << 16 | (rgba[1] as u32) << 8 | rgba[2] as u32;
+        }
+    }
+
+    /// Serialized state: the length of the Gameboy state (LE u32), followed by the state.
+    ///
+    /// The frontend may hand back a larger buffer than we wrote, so the length is
+    /// needed to find the end of the state.
+    fn serialize(&self) -> Vec<u8> {
+        let state = self.gameboy.save_state();
+        let mut data = (state.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&state);
+        data
+    }
+
+    fn unserialize(&mut self, data: &[u8]) -> bool {
+        if data.len() < 4 {
+            return false;
+        }
+
+        let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        match data[4..].get(..len) {
+            Some(state) => self.gameboy.load_state(state).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+/// # Safety
+///
+/// `info` must point to a valid `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+    *info = retro_system_info {
+        library_name: b"gbc\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gb|gbc|cgb\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+///
+/// `info` must point to a valid `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+    *info = retro_system_av_info {
+        geometry: retro_game_geometry {
+            base_width: LCD_WIDTH as c_uint,
+            base_height: LCD_HEIGHT as c_uint,
+            max_width: LCD_WIDTH as c_uint,
+            max_height: LCD_HEIGHT as c_uint,
+            aspect_ratio: LCD_WIDTH as f32 / LCD_HEIGHT as f32,
+        },
+        timing: retro_system_timing {
+            fps: fps(),
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: retro_environment_t) {
+    let mut callbacks = CALLBACKS.lock().unwrap();
+    callbacks.environment = Some(cb);
+
+    let mut log = retro_log_callback { log: None };
+    if cb(RETRO_ENVIRONMENT_GET_LOG_INTERFACE, &mut log as *mut retro_log_callback as *mut c_void) {
+        callbacks.log = log.log;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: retro_video_refresh_t) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: retro_audio_sample_t) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: retro_audio_sample_batch_t) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: retro_input_poll_t) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: retro_input_state_t) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// # Safety
+///
+/// `game` must be null or point to a valid `retro_game_info` holding the ROM data.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+
+    let game = &*game;
+    let data = std::slice::from_raw_parts(game.data as *const u8, game.size).to_vec();
+
+    // No storage is set on the cartridge: the frontend saves RAM itself
+    let gameboy = match Cartridge::from_bytes(data, false)
+        .and_then(|cartridge| Gameboy::from_cartridge(cartridge, false))
+    {
+        Ok(gameboy) => gameboy,
+        Err(_) => return false,
+    };
+
+    if let Some(environment) = CALLBACKS.lock().unwrap().environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            return false;
+        }
+    }
+
+    *CORE.lock().unwrap() = Some(Core::new(gameboy));
+
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const retro_game_info,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        core.gameboy.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.lock().unwrap();
+    let mut core = CORE.lock().unwrap();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return,
+    };
+
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+
+    let events = callbacks.input_state.map(|input_state| core.poll_joypad(input_state));
+
+    core.gameboy.frame(events.as_deref());
+    core.update_video();
+
+    // E.g., the CPU locked up: the game is stuck, so let the user know why
+    if let Some(event) = core.gameboy.take_event() {
+        log(&callbacks, RETRO_LOG_ERROR, &event.to_string());
+    }
+
+    if let Some(video_refresh) = callbacks.video_refresh {
+        video_refresh(
+            core.video.as_ptr() as *const c_void,
+            LCD_WIDTH as c_uint,
+            LCD_HEIGHT as c_uint,
+            LCD_WIDTH * 4,
+        );
+    }
+
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        audio_sample_batch(core.audio.as_ptr(), core.audio.len() / 2);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match CORE.lock().unwrap().as_ref() {
+        // Leave some room in case the state grows (e.g., serial output)
+        Some(core) => core.serialize().len() + 64 * 1024,
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// `data` must point to a writable buffer of `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match CORE.lock().unwrap().as_ref() {
+        Some(core) => core.serialize(),
+        None => return false,
+    };
+
+    if state.len() > size {
+        return false;
+    }
+
+    std::ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+
+    true
+}
+
+/// # Safety
+///
+/// `data` must point to a buffer of `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let data = std::slice::from_raw_parts(data as *const u8, size);
+
+    match CORE.lock().unwrap().as_mut() {
+        Some(core) => core.unserialize(data),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        core.cheats.clear();
+        core.update_cheats();
+    }
+}
+
+/// # Safety
+///
+/// `code` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        // Setting a cheat again replaces it
+        if enabled && !code.is_null() {
+            let code = CStr::from_ptr(code).to_string_lossy().into_owned();
+            core.cheats.insert(index, code);
+        } else {
+            core.cheats.remove(&index);
+        }
+
+        core.update_cheats();
+    }
+}
+
+/// Battery-backed cartridge RAM, which the frontend reads and writes directly.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return std::ptr::null_mut();
+    }
+
+    match CORE.lock().unwrap().as_mut().and_then(|core| core.gameboy.battery_ram()) {
+        Some(ram) => ram.as_mut_ptr() as *mut c_void,
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return 0;
+    }
+
+    match CORE.lock().unwrap().as_mut().and_then(|core| core.gameboy.battery_ram()) {
+        Some(ram) => ram.len(),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    extern "C" fn environment(_cmd: c_uint, _data: *mut c_void) -> bool {
+        true
+    }
+
+    extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert!(!data.is_null());
+        assert_eq!((width, height, pitch), (160, 144, 160 * 4));
+    }
+
+    extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        (id == RETRO_DEVICE_ID_JOYPAD_START) as i16
+    }
+
+    fn load(rom: &str) -> bool {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("samples")
+            .join(rom);
+        let data = std::fs::read(path).unwrap();
+
+        let game = retro_game_info {
+            path: std::ptr::null(),
+            data: data.as_ptr() as *const c_void,
+            size: data.len(),
+            meta: std::ptr::null(),
+        };
+
+        unsafe { retro_load_game(&game) }
+    }
+
+    #[test]
+    fn run_core() {
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_input_state(input_state);
+        retro_init();
+
+        assert!(load("tetris_world.gb"));
+        assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+
+        for _ in 0..10 {
+            retro_run();
+        }
+
+        // Input is forwarded to the joypad
+        let joypad = CORE.lock().unwrap().as_mut().unwrap().gameboy.joypad_state();
+        assert_eq!(joypad, JoypadInput::Start.mask());
+
+        // States survive a roundtrip through a frontend-sized buffer
+        let mut state = vec![0u8; retro_serialize_size()];
+        unsafe {
+            assert!(retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()));
+            assert!(retro_unserialize(state.as_ptr() as *const c_void, state.len()));
+            assert!(!retro_unserialize(state.as_ptr() as *const c_void, 3));
+        }
+
+        // Save RAM is exposed for battery-backed cartridges only
+        assert!(load("pokemon_gold.gbc"));
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 32 * 1024);
+        let sram = retro_get_memory_data(RETRO_MEMORY_SAVE_RAM);
+        assert!(!sram.is_null());
+
+        // Frontends keep the pointer around, so loading a state must not move the RAM
+        let mut state = vec![0u8; retro_serialize_size()];
+        unsafe {
+            assert!(retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()));
+            assert!(retro_unserialize(state.as_ptr() as *const c_void, state.len()));
+        }
+        assert_eq!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM), sram);
+
+        // Cheats are keyed by index: setting one again replaces it, disabling removes it
+        let cheats = || CORE.lock().unwrap().as_ref().unwrap().gameboy.cheats().len();
+        unsafe {
+            retro_cheat_set(0, true, b"0163A4C0+9205D0D8\0".as_ptr() as *const c_char);
+            retro_cheat_set(1, true, b"00A-17B-C49\0".as_ptr() as *const c_char);
+            assert_eq!(cheats(), 3);
+
+            retro_cheat_set(0, true, b"0163A4C0\0".as_ptr() as *const c_char);
+            assert_eq!(cheats(), 2);
+
+            retro_cheat_set(1, false, b"00A-17B-C49\0".as_ptr() as *const c_char);
+            assert_eq!(cheats(), 1);
+        }
+        retro_cheat_reset();
+        assert_eq!(cheats(), 0);
+
+        retro_deinit();
+    }
+}
@@ -0,0 +1,166 @@
+//! WebAssembly bindings for the `gbc` library.
+//!
+//! Build with `wasm-pack build wasm`. From JS:
+//!
+//! ```js
+//! const emulator = new Emulator(romBytes, savedRam);
+//!
+//! function loop() {
+//!     emulator.run_frame();
+//!     const pixels = emulator.frame(); // RGBA, 160x144
+//!     // ... draw pixels, then persist emulator.save_ram() every now and then
+//!     requestAnimationFrame(loop);
+//! }
+//! ```
+use wasm_bindgen::prelude::*;
+
+use gbc::cartridge::Cartridge;
+use gbc::joypad::JoypadInput;
+use gbc::ppu::{LCD_HEIGHT, LCD_WIDTH};
+use gbc::storage::MemoryStorage;
+use gbc::Gameboy;
+
+fn to_js(err: gbc::Error) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl From<Button> for JoypadInput {
+    fn from(button: Button) -> Self {
+        match button {
+            Button::Right => JoypadInput::Right,
+            Button::Left => JoypadInput::Left,
+            Button::Up => JoypadInput::Up,
+            Button::Down => JoypadInput::Down,
+            Button::A => JoypadInput::A,
+            Button::B => JoypadInput::B,
+            Button::Select => JoypadInput::Select,
+            Button::Start => JoypadInput::Start,
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    gameboy: Gameboy,
+
+    /// ROM contents, kept around to reboot with a different save
+    rom: Vec<u8>,
+
+    /// Battery-backed RAM, if the cartridge has any
+    save_ram: MemoryStorage,
+}
+
+impl Emulator {
+    fn boot(rom: &[u8], save_ram: &MemoryStorage) -> gbc::Result<Gameboy> {
+        let mut cartridge = Cartridge::from_bytes(rom.to_vec(), false)?;
+        cartridge.set_ram_storage(Box::new(save_ram.clone()));
+        Gameboy::from_cartridge(cartridge, false)
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// Load a ROM, along with the contents of battery-backed RAM from a previous session.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>, save_ram: Option<Vec<u8>>) -> Result<Emulator, JsValue> {
+        let save_ram = match save_ram {
+            Some(data) => MemoryStorage::with_data(data),
+            None => MemoryStorage::new(),
+        };
+
+        let gameboy = Self::boot(&rom, &save_ram).map_err(to_js)?;
+
+        Ok(Self {
+            gameboy,
+            rom,
+            save_ram,
+        })
+    }
+
+    /// Screen width, in pixels
+    pub fn width() -> usize {
+        LCD_WIDTH
+    }
+
+    /// Screen height, in pixels
+    pub fn height() -> usize {
+        LCD_HEIGHT
+    }
+
+    /// Duration of a single frame, in ms
+    pub fn frame_duration() -> f64 {
+        Gameboy::FRAME_DURATION as f64 / 1e6
+    }
+
+    /// Run the emulator until the next frame is ready.
+    ///
+    /// Returns `false` if no frame was produced (i.e., the LCD is off).
+    pub fn run_frame(&mut self) -> bool {
+        self.gameboy.next_frame().is_some()
+    }
+
+    /// Returns the last frame as RGBA pixels, row by row.
+    pub fn frame(&self) -> Vec<u8> {
+        self.gameboy.frame_buffer().to_rgba()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let state = self.gameboy.joypad_state() | JoypadInput::from(button).mask();
+        self.gameboy.set_joypad_state(state);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let state = self.gameboy.joypad_state() & !JoypadInput::from(button).mask();
+        self.gameboy.set_joypad_state(state);
+    }
+
+    /// Pressed buttons, as a bitmask. Each bit is `1 << Button`.
+    pub fn joypad(&mut self) -> u8 {
+        self.gameboy.joypad_state()
+    }
+
+    /// Replace the pressed buttons with a bitmask. Each bit is `1 << Button`.
+    pub fn set_joypad(&mut self, state: u8) {
+        self.gameboy.set_joypad_state(state);
+    }
+
+    /// Contents of battery-backed RAM, if the cartridge has any
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.save_ram.data()
+    }
+
+    /// Replace the contents of battery-backed RAM and reboot the cartridge.
+    pub fn load_save_ram(&mut self, data: Vec<u8>) -> Result<(), JsValue> {
+        let save_ram = MemoryStorage::with_data(data);
+        self.gameboy = Self::boot(&self.rom, &save_ram).map_err(to_js)?;
+        self.save_ram = save_ram;
+        Ok(())
+    }
+
+    /// Serialize the full emulator state.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.gameboy.save_state()
+    }
+
+    /// Restore a state produced by `Self::save_state`.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+        self.gameboy.load_state(state).map_err(to_js)
+    }
+
+    pub fn reset(&mut self) {
+        self.gameboy.reset();
+    }
+}
@@ -0,0 +1,54 @@
+//! Run with `wasm-pack test --node wasm`
+#![cfg(target_arch = "wasm32")]
+
+use wasm_bindgen_test::*;
+
+use gbc_wasm::{Button, Emulator};
+
+const ROM: &[u8] = include_bytes!("../../samples/tetris_world.gb");
+const POKEMON: &[u8] = include_bytes!("../../samples/pokemon_gold.gbc");
+
+#[wasm_bindgen_test]
+fn run_frames() {
+    let mut emulator = Emulator::new(ROM.to_vec(), None).unwrap();
+
+    for _ in 0..120 {
+        emulator.run_frame();
+    }
+
+    let frame = emulator.frame();
+    assert_eq!(frame.len(), Emulator::width() * Emulator::height() * 4);
+    assert!(frame.chunks(4).any(|pixel| pixel != &frame[..4]));
+}
+
+#[wasm_bindgen_test]
+fn joypad() {
+    let mut emulator = Emulator::new(ROM.to_vec(), None).unwrap();
+
+    emulator.press(Button::Start);
+    emulator.press(Button::A);
+    assert_eq!(emulator.joypad(), 0x90);
+
+    emulator.release(Button::Start);
+    assert_eq!(emulator.joypad(), 0x10);
+}
+
+#[wasm_bindgen_test]
+fn save_ram_roundtrip() {
+    let mut emulator = Emulator::new(POKEMON.to_vec(), None).unwrap();
+    for _ in 0..60 {
+        emulator.run_frame();
+    }
+
+    let save = emulator.save_ram().expect("Cartridge has battery RAM");
+    let mut emulator = Emulator::new(POKEMON.to_vec(), Some(save.clone())).unwrap();
+    assert_eq!(emulator.save_ram(), Some(save.clone()));
+
+    emulator.load_save_ram(save.clone()).unwrap();
+    assert_eq!(emulator.save_ram(), Some(save));
+}
+
+#[wasm_bindgen_test]
+fn invalid_rom() {
+    assert!(Emulator::new(vec![0; 16], None).is_err());
+}